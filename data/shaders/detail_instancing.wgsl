@@ -0,0 +1,98 @@
+#import bevy_pbr::{
+    mesh_view_bindings::view,
+    pbr_types::pbr_input_new,
+    pbr_functions as fns,
+    mesh_types::MESH_FLAGS_SHADOW_RECEIVER_BIT,
+}
+
+@group(2) @binding(0) var base_color_texture: texture_2d<f32>;
+@group(2) @binding(1) var base_color_sampler: sampler;
+
+struct Vertex {
+    @location(0) position: vec3<f32>,
+    @location(1) normal: vec3<f32>,
+#ifdef VERTEX_UVS
+    @location(2) uv: vec2<f32>,
+#endif
+#ifdef VERTEX_COLORS
+    @location(5) color: vec4<f32>,
+#endif
+
+    //locations below 8 are used by the mesh attributes
+    @location(8) i_transform_0: vec4<f32>,
+    @location(9) i_transform_1: vec4<f32>,
+    @location(10) i_transform_2: vec4<f32>,
+    @location(11) i_transform_3: vec4<f32>,
+    @location(12) i_color: vec4<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) world_position: vec4<f32>,
+    @location(1) world_normal: vec3<f32>,
+    @location(2) uv: vec2<f32>,
+    @location(3) color: vec4<f32>,
+};
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    let transform = mat4x4<f32>(
+        vertex.i_transform_0,
+        vertex.i_transform_1,
+        vertex.i_transform_2,
+        vertex.i_transform_3,
+    );
+    var out: VertexOutput;
+#ifdef DETAIL_BILLBOARD
+    //impostors keep the position and scale of the detail but turn to face the camera
+    let scale = length(vertex.i_transform_0.xyz);
+    let right = normalize(vec3<f32>(view.view[0].x, 0.0, view.view[0].z));
+    let offset = right * vertex.position.x + vec3<f32>(0.0, vertex.position.y, 0.0);
+    out.world_position = vec4<f32>(vertex.i_transform_3.xyz + offset * scale, 1.0);
+    out.world_normal = vec3<f32>(0.0, 1.0, 0.0);
+#else
+    out.world_position = transform * vec4<f32>(vertex.position, 1.0);
+    //details are only scaled uniformly, so the normal doesn't need the inverse transpose
+    out.world_normal = normalize((transform * vec4<f32>(vertex.normal, 0.0)).xyz);
+#endif
+    out.clip_position = view.view_proj * out.world_position;
+#ifdef VERTEX_UVS
+    out.uv = vertex.uv;
+#endif
+    out.color = vertex.i_color;
+#ifdef VERTEX_COLORS
+    out.color = out.color * vertex.color;
+#endif
+    return out;
+}
+
+//the default cutoff of glTF materials
+const ALPHA_CUTOFF: f32 = 0.5;
+
+@fragment
+fn fragment(in: VertexOutput, @builtin(front_facing) is_front: bool) -> @location(0) vec4<f32> {
+    var color = in.color * textureSample(base_color_texture, base_color_sampler, in.uv);
+#ifdef DETAIL_ALPHA_MASK
+    if color.a < ALPHA_CUTOFF {
+        discard;
+    }
+#endif
+    color.a = 1.0;
+
+#ifdef DETAIL_DOUBLE_SIDED
+    let double_sided = true;
+#else
+    let double_sided = false;
+#endif
+    //lit like the meshes of the StandardMaterial, with the lights and shadows of the scene
+    var pbr_input = pbr_input_new();
+    pbr_input.material.base_color = color;
+    pbr_input.frag_coord = in.clip_position;
+    pbr_input.world_position = in.world_position;
+    pbr_input.world_normal = fns::prepare_world_normal(normalize(in.world_normal), double_sided, is_front);
+    pbr_input.N = pbr_input.world_normal;
+    pbr_input.is_orthographic = view.projection[3].w == 1.0;
+    pbr_input.V = fns::calculate_view(in.world_position, pbr_input.is_orthographic);
+    pbr_input.flags = MESH_FLAGS_SHADOW_RECEIVER_BIT;
+    return fns::main_pass_post_lighting_processing(pbr_input, fns::apply_pbr_lighting(pbr_input));
+}
@@ -0,0 +1,401 @@
+use std::f32::consts::PI;
+
+use bevy::prelude::*;
+use noise::{core::open_simplex::open_simplex_2d, permutationtable::PermutationTable};
+use rand::Rng;
+
+use crate::{
+    sample_vec,
+    ui::{Brush, BrushInfo},
+};
+
+//Procedural brushes don't come from the brushes folder,
+//so they get an id that can't collide with a folder index.
+pub const PROCEDURAL_BRUSH_ID: usize = usize::MAX;
+const PROCEDURAL_BRUSH_SIZE: u32 = 128;
+
+#[derive(PartialEq, Clone)]
+pub enum BrushShape {
+    Circle,
+    Square,
+}
+impl ToString for BrushShape {
+    fn to_string(&self) -> String {
+        match self {
+            BrushShape::Circle => "Circle",
+            BrushShape::Square => "Square",
+        }
+        .to_string()
+    }
+}
+
+//Maps the distance from the hard inner edge (0) to the brush edge (1) to a strength.
+//Points are kept sorted by x and the curve is smoothstepped between them.
+#[derive(PartialEq, Clone)]
+pub struct FalloffCurve {
+    pub points: Vec<Vec2>,
+}
+impl Default for FalloffCurve {
+    fn default() -> Self {
+        Self {
+            points: vec![
+                Vec2::new(0.0, 1.0),
+                Vec2::new(0.5, 0.5),
+                Vec2::new(1.0, 0.0),
+            ],
+        }
+    }
+}
+impl FalloffCurve {
+    pub fn sample(&self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        let first = self.points[0];
+        if t <= first.x {
+            return first.y;
+        }
+        for pair in self.points.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            if t <= b.x {
+                let fraction = if b.x > a.x {
+                    (t - a.x) / (b.x - a.x)
+                } else {
+                    1.0
+                };
+                let fraction = fraction * fraction * (3.0 - 2.0 * fraction);
+                return a.y + (b.y - a.y) * fraction;
+            }
+        }
+        self.points[self.points.len() - 1].y
+    }
+    pub fn sort(&mut self) {
+        self.points.sort_by(|a, b| a.x.total_cmp(&b.x));
+    }
+}
+
+#[derive(PartialEq, Clone)]
+pub struct ProceduralBrush {
+    pub shape: BrushShape,
+    pub hardness: f32,
+    pub falloff: FalloffCurve,
+
+    pub noise_amount: f32,
+    pub noise_scale: f32,
+    pub noise_seed: u32,
+}
+impl Default for ProceduralBrush {
+    fn default() -> Self {
+        Self {
+            shape: BrushShape::Circle,
+            hardness: 0.0,
+            falloff: FalloffCurve::default(),
+
+            noise_amount: 0.0,
+            noise_scale: 4.0,
+            noise_seed: 0,
+        }
+    }
+}
+impl ProceduralBrush {
+    pub fn generate(&self) -> (Vec<f32>, u32) {
+        let size = PROCEDURAL_BRUSH_SIZE;
+        let hasher = PermutationTable::new(self.noise_seed);
+        let mut map = vec![0.0; (size * size) as usize];
+        for y in 0..size {
+            for x in 0..size {
+                let uv = Vec2::new(
+                    (x as f32 + 0.5) / size as f32 * 2.0 - 1.0,
+                    (y as f32 + 0.5) / size as f32 * 2.0 - 1.0,
+                );
+                let mut distance = match self.shape {
+                    BrushShape::Circle => uv.length(),
+                    BrushShape::Square => uv.x.abs().max(uv.y.abs()),
+                };
+                if self.noise_amount > 0.0 {
+                    let noise = open_simplex_2d(
+                        [
+                            (uv.x * self.noise_scale) as f64,
+                            (uv.y * self.noise_scale) as f64,
+                        ],
+                        &hasher,
+                    ) as f32;
+                    distance *= 1.0 + noise * self.noise_amount;
+                }
+                map[(x + y * size) as usize] = self.falloff_at(distance);
+            }
+        }
+        (map, size)
+    }
+    pub fn to_brush(&self) -> Brush {
+        let (map, map_size) = self.generate();
+        Brush {
+            id: PROCEDURAL_BRUSH_ID,
+            map,
+            map_size,
+            sample_map: Vec::new(),
+            sample_map_size: 0,
+        }
+    }
+    fn falloff_at(&self, distance: f32) -> f32 {
+        if distance >= 1.0 {
+            return 0.0;
+        }
+        if distance <= self.hardness {
+            return self.falloff.sample(0.0);
+        }
+        let t = (distance - self.hardness) / (1.0 - self.hardness);
+        self.falloff.sample(t)
+    }
+}
+
+#[derive(PartialEq, Clone)]
+pub enum RotationMode {
+    Fixed,
+    FollowStroke,
+    Random,
+}
+impl ToString for RotationMode {
+    fn to_string(&self) -> String {
+        match self {
+            RotationMode::Fixed => "Fixed",
+            RotationMode::FollowStroke => "Follow stroke",
+            RotationMode::Random => "Random",
+        }
+        .to_string()
+    }
+}
+
+pub struct StrokeSettings {
+    //distance between stamps in percent of the brush size, 0 stamps every frame
+    pub spacing: f32,
+
+    pub rotation_mode: RotationMode,
+    pub rotation: f32,
+
+    pub position_jitter: f32,
+    pub size_jitter: f32,
+    pub strength_jitter: f32,
+}
+impl Default for StrokeSettings {
+    fn default() -> Self {
+        Self {
+            spacing: 0.0,
+
+            rotation_mode: RotationMode::Fixed,
+            rotation: 0.0,
+
+            position_jitter: 0.0,
+            size_jitter: 0.0,
+            strength_jitter: 0.0,
+        }
+    }
+}
+
+//Spaced stamps use a fixed time step so the result doesn't depend on the frame rate.
+const STAMP_DELTA_SECONDS: f32 = 1.0 / 60.0;
+
+pub struct Stamp {
+    pub pos: Vec2,
+    pub rotation: f32,
+    pub size: u32,
+    pub strength: f32,
+    //how much time this stamp accounts for, tools scale their effect by it
+    pub delta_seconds: f32,
+    //mirrored stamps flip the brush before rotating it
+    pub flipped: bool,
+    //maps directions of the stroke onto this stamp, it's only more than identity for
+    //the copies made by symmetry
+    pub mirror: Mat2,
+}
+impl Stamp {
+    //Brush strength at the cell (x, y) of this stamp, where both are in 0..self.size.
+    pub fn sample(&self, brush: &Brush, x: u32, y: u32) -> f32 {
+        if self.rotation == 0.0 && !self.flipped && self.size == brush.sample_map_size {
+            return brush.sample_map[(x + y * self.size) as usize];
+        }
+        let uv = Vec2::new(x as f32, y as f32) / self.size as f32 - Vec2::splat(0.5);
+        let mut uv = Mat2::from_angle(-self.rotation) * uv + Vec2::splat(0.5);
+        if self.flipped {
+            uv.x = 1.0 - uv.x;
+        }
+        if uv.x < 0.0 || uv.y < 0.0 || uv.x >= 1.0 || uv.y >= 1.0 {
+            return 0.0;
+        }
+        sample_vec(
+            uv.x,
+            uv.y,
+            &brush.sample_map,
+            brush.sample_map_size as usize,
+        )
+    }
+}
+
+//Turns the brush position of this frame into the stamps that should be applied.
+//With spacing, stamps are interpolated along the mouse path from the previous stamp.
+pub fn stroke_stamps(
+    brush_info: &mut BrushInfo,
+    pos: Vec2,
+    new_stroke: bool,
+    delta_seconds: f32,
+) -> Vec<Stamp> {
+    let size = brush_info.size as f32;
+    let spacing = brush_info.stroke.spacing / 100.0 * size;
+    let mut positions = Vec::new();
+    match brush_info.last_stamp {
+        Some(last_stamp) if !new_stroke && spacing > 0.0 => {
+            let path = pos - last_stamp;
+            let steps = (path.length() / spacing).floor() as usize;
+            if steps > 0 {
+                let step = path.normalize() * spacing;
+                for i in 1..=steps {
+                    positions.push(last_stamp + step * i as f32);
+                }
+                brush_info.stroke_direction = path.normalize();
+                brush_info.last_stamp = positions.last().copied();
+            }
+        }
+        _ => {
+            if let Some(last_stamp) = brush_info.last_stamp {
+                if !new_stroke && pos != last_stamp {
+                    brush_info.stroke_direction = (pos - last_stamp).normalize();
+                }
+            }
+            positions.push(pos);
+            brush_info.last_stamp = Some(pos);
+        }
+    }
+    let delta_seconds = if spacing > 0.0 {
+        STAMP_DELTA_SECONDS
+    } else {
+        delta_seconds
+    };
+
+    let stroke = &brush_info.stroke;
+    let mut rng = rand::thread_rng();
+    let mut jitter = |amount: f32| -> f32 {
+        if amount > 0.0 {
+            rng.gen_range(-amount..=amount)
+        } else {
+            0.0
+        }
+    };
+    positions
+        .into_iter()
+        .map(|pos| {
+            let rotation = stroke.rotation.to_radians()
+                + match stroke.rotation_mode {
+                    RotationMode::Fixed => 0.0,
+                    RotationMode::FollowStroke => {
+                        let direction = brush_info.stroke_direction;
+                        direction.y.atan2(direction.x)
+                    }
+                    RotationMode::Random => jitter(PI),
+                };
+            let offset = Vec2::new(
+                jitter(stroke.position_jitter),
+                jitter(stroke.position_jitter),
+            ) * size;
+            Stamp {
+                pos: pos + offset,
+                rotation,
+                size: ((size * (1.0 + jitter(stroke.size_jitter))).round() as u32).max(1),
+                strength: brush_info.strength * (1.0 + jitter(stroke.strength_jitter)).max(0.0),
+                delta_seconds,
+                flipped: false,
+                mirror: Mat2::IDENTITY,
+            }
+        })
+        .collect()
+}
+
+//Mirrors brush stamps about world-space axes through center, or repeats them around it.
+pub struct Symmetry {
+    //mirror across the line x = center.x
+    pub mirror_x: bool,
+    //mirror across the line z = center.y
+    pub mirror_z: bool,
+    //number of copies around the center, 1 disables radial symmetry
+    pub radial_count: u32,
+    pub center: Vec2,
+}
+impl Default for Symmetry {
+    fn default() -> Self {
+        Self {
+            mirror_x: false,
+            mirror_z: false,
+            radial_count: 1,
+            center: Vec2::ZERO,
+        }
+    }
+}
+impl Symmetry {
+    pub fn is_active(&self) -> bool {
+        self.mirror_x || self.mirror_z || self.radial_count > 1
+    }
+    //The position and every symmetric copy of it, the original comes first.
+    pub fn positions(&self, pos: Vec2) -> Vec<Vec2> {
+        self.copies(pos).into_iter().map(|(pos, _)| pos).collect()
+    }
+    //Like positions, with the transform that maps directions onto each copy.
+    pub fn copies(&self, pos: Vec2) -> Vec<(Vec2, Mat2)> {
+        self.transforms()
+            .into_iter()
+            .map(|(angle, flip_x, flip_z)| {
+                let matrix = transform_matrix(angle, flip_x, flip_z);
+                (self.center + matrix * (pos - self.center), matrix)
+            })
+            .collect()
+    }
+    pub fn apply(&self, stamps: Vec<Stamp>) -> Vec<Stamp> {
+        if !self.is_active() {
+            return stamps;
+        }
+        let transforms = self.transforms();
+        let mut mirrored = Vec::with_capacity(stamps.len() * transforms.len());
+        for stamp in stamps {
+            for (angle, flip_x, flip_z) in &transforms {
+                let (angle, flip_x, flip_z) = (*angle, *flip_x, *flip_z);
+                //a reflection of a rotated brush is a flipped brush with the opposite rotation,
+                //reflecting across z as well adds half a turn
+                let rotation = match (flip_x, flip_z) {
+                    (false, false) => stamp.rotation,
+                    (true, false) => -stamp.rotation,
+                    (false, true) => PI - stamp.rotation,
+                    (true, true) => stamp.rotation + PI,
+                } + angle;
+                let matrix = transform_matrix(angle, flip_x, flip_z);
+                mirrored.push(Stamp {
+                    pos: self.center + matrix * (stamp.pos - self.center),
+                    rotation,
+                    size: stamp.size,
+                    strength: stamp.strength,
+                    delta_seconds: stamp.delta_seconds,
+                    flipped: stamp.flipped ^ (flip_x ^ flip_z),
+                    mirror: matrix * stamp.mirror,
+                });
+            }
+        }
+        mirrored
+    }
+    fn transforms(&self) -> Vec<(f32, bool, bool)> {
+        let mut transforms = Vec::new();
+        for i in 0..self.radial_count.max(1) {
+            let angle = i as f32 / self.radial_count.max(1) as f32 * 2.0 * PI;
+            transforms.push((angle, false, false));
+            if self.mirror_x {
+                transforms.push((angle, true, false));
+            }
+            if self.mirror_z {
+                transforms.push((angle, false, true));
+            }
+            if self.mirror_x && self.mirror_z {
+                transforms.push((angle, true, true));
+            }
+        }
+        transforms
+    }
+}
+//Reflects across the enabled axes, then rotates.
+fn transform_matrix(angle: f32, flip_x: bool, flip_z: bool) -> Mat2 {
+    let flip = |flipped: bool| if flipped { -1.0 } else { 1.0 };
+    Mat2::from_angle(angle) * Mat2::from_diagonal(Vec2::new(flip(flip_x), flip(flip_z)))
+}
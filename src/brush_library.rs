@@ -0,0 +1,350 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use bevy::{
+    asset::LoadedFolder,
+    prelude::*,
+    render::render_resource::{Extent3d, TextureDimension, TextureFormat},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{asset_path, ui::EditInfo, user_data_path};
+
+pub struct BrushLibraryPlugin;
+impl Plugin for BrushLibraryPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(BrushLibrary::load())
+            .add_systems(Update, update_brush_library);
+    }
+}
+
+//kept out of the asset folder so saving it doesn't trigger the asset watcher
+const LIBRARY_FILE: &str = "brush_library.bin";
+//where older versions kept the library
+const OLD_LIBRARY_FILE: &str = "brushes/library.bin";
+
+//Persistent information about a brush image, keyed by its file name.
+//The id stays the same when other files are added to or removed from the folder.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BrushEntry {
+    pub id: usize,
+    pub file_name: String,
+    pub name: String,
+    pub tags: Vec<String>,
+    pub favourite: bool,
+}
+
+#[derive(Resource)]
+pub struct BrushLibrary {
+    pub entries: Vec<BrushEntry>,
+    next_id: usize,
+
+    //brushes imported while the editor is running, the folder handle doesn't pick them up
+    imported: Vec<Handle<Image>>,
+    removed: HashSet<String>,
+    //images in a format that can't be read as a brush, so they're only reported once
+    unsupported: HashSet<String>,
+
+    pub images: HashMap<usize, Handle<Image>>,
+    //images that egui can display, 16-bit brushes get an 8-bit copy
+    pub previews: HashMap<usize, Handle<Image>>,
+
+    pub tag_filter: String,
+}
+#[derive(Serialize, Deserialize)]
+struct BrushLibraryData {
+    entries: Vec<BrushEntry>,
+    next_id: usize,
+}
+impl BrushLibrary {
+    fn load() -> Self {
+        let mut library = Self {
+            entries: Vec::new(),
+            next_id: 0,
+
+            imported: Vec::new(),
+            removed: HashSet::new(),
+            unsupported: HashSet::new(),
+
+            images: HashMap::new(),
+            previews: HashMap::new(),
+
+            tag_filter: String::new(),
+        };
+        let old_path = asset_path(OLD_LIBRARY_FILE);
+        let path = if user_data_path(LIBRARY_FILE).exists() || !old_path.exists() {
+            user_data_path(LIBRARY_FILE)
+        } else {
+            old_path.clone()
+        };
+        let mut buffer = Vec::new();
+        if let Ok(mut file) = File::open(&path) {
+            if file.read_to_end(&mut buffer).is_ok() {
+                if let Ok(data) = bincode::deserialize::<BrushLibraryData>(&buffer) {
+                    library.entries = data.entries;
+                    library.next_id = data.next_id;
+                }
+            }
+        }
+        //move the library out of the asset folder, keeping the ids
+        if path == old_path {
+            library.save();
+            if let Err(err) = fs::remove_file(&old_path) {
+                error!("Failed to remove the old brush library: {err}");
+            }
+        }
+        library
+    }
+    pub fn save(&self) {
+        let data = BrushLibraryData {
+            entries: self.entries.clone(),
+            next_id: self.next_id,
+        };
+        let serialized = bincode::serialize(&data).expect("Serialization failed");
+        match File::create(user_data_path(LIBRARY_FILE)) {
+            Ok(mut file) => {
+                if let Err(err) = file.write_all(&serialized) {
+                    error!("Failed to save the brush library: {err}");
+                }
+            }
+            Err(err) => error!("Failed to save the brush library: {err}"),
+        }
+    }
+    pub fn get(&self, id: usize) -> Option<&BrushEntry> {
+        self.entries.iter().find(|entry| entry.id == id)
+    }
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut BrushEntry> {
+        self.entries.iter_mut().find(|entry| entry.id == id)
+    }
+    fn entry_for_file(&mut self, file_name: &str) -> usize {
+        if let Some(entry) = self
+            .entries
+            .iter()
+            .find(|entry| entry.file_name == file_name)
+        {
+            return entry.id;
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        let name = Path::new(file_name)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or(file_name.to_string());
+        self.entries.push(BrushEntry {
+            id,
+            file_name: file_name.to_string(),
+            name,
+            tags: Vec::new(),
+            favourite: false,
+        });
+        self.save();
+        id
+    }
+    //Brushes to show, favourites first, filtered by the tag filter.
+    pub fn visible(&self) -> Vec<&BrushEntry> {
+        let filter = self.tag_filter.trim().to_lowercase();
+        let mut entries: Vec<&BrushEntry> = self
+            .entries
+            .iter()
+            .filter(|entry| self.previews.contains_key(&entry.id))
+            .filter(|entry| {
+                filter.is_empty()
+                    || entry
+                        .tags
+                        .iter()
+                        .any(|tag| tag.to_lowercase().contains(&filter))
+            })
+            .collect();
+        entries.sort_by_key(|entry| !entry.favourite);
+        entries
+    }
+    pub fn import(&mut self, source: PathBuf, asset_server: &AssetServer) {
+        let file_name = match source.file_name() {
+            Some(file_name) => file_name.to_string_lossy().to_string(),
+            None => return,
+        };
+        //don't overwrite a brush that's already in the library
+        let mut target_name = file_name.clone();
+        let mut i = 1;
+        while asset_path("brushes").join(&target_name).exists() {
+            let path = Path::new(&file_name);
+            target_name = format!(
+                "{}_{}.{}",
+                path.file_stem().unwrap_or_default().to_string_lossy(),
+                i,
+                path.extension().unwrap_or_default().to_string_lossy()
+            );
+            i += 1;
+        }
+        if let Err(err) = fs::copy(&source, asset_path("brushes").join(&target_name)) {
+            error!("Failed to import brush {}: {err}", source.display());
+            return;
+        }
+        //a deleted brush can be imported again under its old name
+        self.removed.remove(&target_name);
+        self.entry_for_file(&target_name);
+        self.imported
+            .push(asset_server.load(format!("brushes/{}", target_name)));
+    }
+    pub fn delete(&mut self, id: usize) {
+        let entry = if let Some(index) = self.entries.iter().position(|entry| entry.id == id) {
+            self.entries.remove(index)
+        } else {
+            return;
+        };
+        if let Err(err) = fs::remove_file(asset_path("brushes").join(&entry.file_name)) {
+            error!("Failed to delete brush {}: {err}", entry.file_name);
+        }
+        self.images.remove(&id);
+        self.previews.remove(&id);
+        self.removed.insert(entry.file_name);
+        self.save();
+    }
+}
+
+//Reads the strength map of a brush image with the size of its side. 8-bit images use
+//the red channel, 16-bit grayscale images keep their full precision. Maps are square,
+//other images are stretched to fit. None for formats it can't read.
+pub fn brush_map_from_image(image: &Image) -> Option<(Vec<f32>, u32)> {
+    let u16_at = |bytes: &[u8]| u16::from_le_bytes([bytes[0], bytes[1]]) as f32 / u16::MAX as f32;
+    let u8_at = |bytes: &[u8]| bytes[0] as f32 / 255.0;
+    let map: Vec<f32> = match image.texture_descriptor.format {
+        TextureFormat::R16Uint | TextureFormat::R16Unorm => {
+            image.data.chunks(2).map(u16_at).collect()
+        }
+        TextureFormat::Rg16Uint | TextureFormat::Rg16Unorm => {
+            image.data.chunks(4).map(u16_at).collect()
+        }
+        TextureFormat::Rgba16Uint | TextureFormat::Rgba16Unorm => {
+            image.data.chunks(8).map(u16_at).collect()
+        }
+        TextureFormat::R8Unorm => image.data.iter().map(|b| u8_at(&[*b])).collect(),
+        //grayscale with alpha
+        TextureFormat::Rg8Unorm => image.data.chunks(2).map(u8_at).collect(),
+        TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb => {
+            image.data.chunks(4).map(u8_at).collect()
+        }
+        _ => return None,
+    };
+    let (width, height) = (image.width(), image.height());
+    //a short map would be indexed out of bounds when it's resized
+    if map.len() != (width * height) as usize || width == 0 || height == 0 {
+        return None;
+    }
+    if width == height {
+        return Some((map, width));
+    }
+    let size = width.max(height);
+    Some((resample(&map, width, height, size), size))
+}
+//Bilinear resample of a width by height map to a size by size map.
+fn resample(map: &[f32], width: u32, height: u32, size: u32) -> Vec<f32> {
+    let at = |x: u32, y: u32| map[(x + y * width) as usize];
+    let mut square = Vec::with_capacity((size * size) as usize);
+    for y in 0..size {
+        for x in 0..size {
+            let source = |pos: u32, source_size: u32| {
+                let pos = ((pos as f32 + 0.5) * source_size as f32 / size as f32 - 0.5)
+                    .clamp(0.0, (source_size - 1) as f32);
+                (
+                    pos as u32,
+                    (pos as u32 + 1).min(source_size - 1),
+                    pos.fract(),
+                )
+            };
+            let (x0, x1, tx) = source(x, width);
+            let (y0, y1, ty) = source(y, height);
+            let top = at(x0, y0) + (at(x1, y0) - at(x0, y0)) * tx;
+            let bottom = at(x0, y1) + (at(x1, y1) - at(x0, y1)) * tx;
+            square.push(top + (bottom - top) * ty);
+        }
+    }
+    square
+}
+pub fn is_displayable(image: &Image) -> bool {
+    matches!(
+        image.texture_descriptor.format,
+        TextureFormat::Rgba8UnormSrgb | TextureFormat::Rgba8Unorm
+    )
+}
+
+fn update_brush_library(
+    mut library: ResMut<BrushLibrary>,
+    edit_info: Res<EditInfo>,
+    loaded_folders: Res<Assets<LoadedFolder>>,
+    asset_server: Res<AssetServer>,
+    mut images: ResMut<Assets<Image>>,
+) {
+    let mut handles: Vec<Handle<Image>> = Vec::new();
+    if let Some(brushes_folder) = loaded_folders.get(&edit_info.brushes) {
+        for handle in &brushes_folder.handles {
+            handles.push(handle.clone().typed());
+        }
+    }
+    handles.extend(library.imported.iter().cloned());
+
+    for handle in handles {
+        let file_name = match asset_server.get_path(handle.id()) {
+            Some(path) => match path.path().file_name() {
+                Some(file_name) => file_name.to_string_lossy().to_string(),
+                None => continue,
+            },
+            None => continue,
+        };
+        if library.removed.contains(&file_name) || library.unsupported.contains(&file_name) {
+            continue;
+        }
+        let loaded = library
+            .entries
+            .iter()
+            .any(|entry| entry.file_name == file_name && library.previews.contains_key(&entry.id));
+        if loaded {
+            continue;
+        }
+        let image = if let Some(image) = images.get(&handle) {
+            image
+        } else {
+            continue;
+        };
+        let (map, map_size) = match brush_map_from_image(image) {
+            Some(map) => map,
+            None => {
+                error!(
+                    "Brush {file_name} has an unsupported format {:?}",
+                    image.texture_descriptor.format
+                );
+                library.unsupported.insert(file_name);
+                continue;
+            }
+        };
+        let id = library.entry_for_file(&file_name);
+        let preview = if is_displayable(image) {
+            handle.clone()
+        } else {
+            let size = Extent3d {
+                width: map_size,
+                height: map_size,
+                depth_or_array_layers: 1,
+            };
+            let data = map
+                .into_iter()
+                .flat_map(|v| {
+                    let b = (v * 255.0) as u8;
+                    [b, b, b, 255]
+                })
+                .collect();
+            images.add(Image::new(
+                size,
+                TextureDimension::D2,
+                data,
+                TextureFormat::Rgba8UnormSrgb,
+            ))
+        };
+        library.images.insert(id, handle);
+        library.previews.insert(id, preview);
+    }
+}
@@ -0,0 +1,226 @@
+use std::f32::consts::TAU;
+
+use bevy::prelude::*;
+use bevy_mod_raycast::deferred::RaycastSource;
+
+use crate::{
+    sample_vec,
+    sculpt::height_stamp_result,
+    terrain::MasterTerrain,
+    ui::{BrushInfo, EditInfo, EditMode, SculptInfo, SculptType, UiHovered},
+};
+
+pub struct BrushPreviewPlugin;
+impl Plugin for BrushPreviewPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, draw_brush_preview);
+    }
+}
+
+const RING_SEGMENTS: usize = 64;
+const STAMP_GRID_LINES: usize = 16;
+const FALLOFF_RINGS: usize = 4;
+//lift the lines slightly so they don't z-fight with the terrain
+const RING_OFFSET: f32 = 0.2;
+const SYMMETRY_MARKER_HEIGHT: f32 = 20.0;
+
+fn draw_brush_preview(
+    edit_info: Res<EditInfo>,
+    raycast_source: Query<&RaycastSource<()>>,
+    master_terrain: Res<MasterTerrain>,
+    keys: Res<Input<KeyCode>>,
+    ui_hovered: Res<UiHovered>,
+    mut gizmos: Gizmos,
+) {
+    if !master_terrain.loaded || ui_hovered.0 {
+        return;
+    }
+    let lowering = keys.pressed(KeyCode::ControlLeft);
+    let (brush_info, color) = match &edit_info.edit_mode {
+        EditMode::Sculpt => (
+            &edit_info.sculpt_info.brush_info,
+            match edit_info.sculpt_info.sculpt_type {
+                SculptType::RaiseLower if lowering => Color::RED,
+                SculptType::RaiseLower => Color::LIME_GREEN,
+                SculptType::SetHeight => Color::YELLOW,
+                SculptType::Smooth => Color::CYAN,
+                SculptType::Stamp => Color::ORANGE,
+                SculptType::Terrace => Color::PURPLE,
+                SculptType::FlattenPlane => Color::GOLD,
+                SculptType::Ramp => Color::TURQUOISE,
+                SculptType::Noise => Color::FUCHSIA,
+                SculptType::Sharpen => Color::SALMON,
+                SculptType::Pinch => Color::VIOLET,
+                SculptType::Relax => Color::SEA_GREEN,
+            },
+        ),
+        EditMode::Draw => (&edit_info.draw_info.brush_info, Color::WHITE),
+        EditMode::Mask => (
+            &edit_info.mask_info.brush_info,
+            if lowering { Color::RED } else { Color::ORANGE },
+        ),
+        EditMode::GroundCover => (
+            &edit_info.ground_cover_info.brush_info,
+            if lowering { Color::RED } else { Color::GREEN },
+        ),
+        EditMode::EditDetails => (
+            &edit_info.details_info.brush_info,
+            if lowering {
+                Color::RED
+            } else {
+                Color::LIME_GREEN
+            },
+        ),
+        _ => return,
+    };
+    let raycast_source = raycast_source.single();
+    if let Some((_, intersection)) = raycast_source.intersections().first() {
+        let pos = intersection.position();
+        if let (EditMode::Sculpt, SculptType::Ramp) =
+            (&edit_info.edit_mode, &edit_info.sculpt_info.sculpt_type)
+        {
+            draw_ramp_preview(&mut gizmos, &edit_info.sculpt_info, pos, color);
+            return;
+        }
+        if let (EditMode::Sculpt, SculptType::Stamp) =
+            (&edit_info.edit_mode, &edit_info.sculpt_info.sculpt_type)
+        {
+            draw_stamp_preview(
+                &mut gizmos,
+                &master_terrain,
+                &edit_info.sculpt_info,
+                Vec2::new(pos.x, pos.z),
+                color,
+            );
+            return;
+        }
+        for center in edit_info.symmetry.positions(Vec2::new(pos.x, pos.z)) {
+            draw_brush_rings(&mut gizmos, &master_terrain, brush_info, center, color);
+        }
+        if edit_info.symmetry.is_active() {
+            let center = edit_info.symmetry.center;
+            let height = master_terrain.sample_height(center) + RING_OFFSET;
+            gizmos.line(
+                Vec3::new(center.x, height, center.y),
+                Vec3::new(center.x, height + SYMMETRY_MARKER_HEIGHT, center.y),
+                color,
+            );
+        }
+    }
+}
+
+//Draws concentric rings that follow the terrain. The outer ring shows the brush size,
+//the inner ones are faded by the brush falloff so you can see where it's strongest.
+pub fn draw_brush_rings(
+    gizmos: &mut Gizmos,
+    master_terrain: &MasterTerrain,
+    brush_info: &BrushInfo,
+    center: Vec2,
+    color: Color,
+) {
+    let radius = brush_info.size as f32 * 0.5;
+    for ring in 1..=FALLOFF_RINGS {
+        let ring_radius = radius * ring as f32 / FALLOFF_RINGS as f32;
+        let points = (0..=RING_SEGMENTS).map(|i| {
+            let angle = i as f32 / RING_SEGMENTS as f32 * TAU;
+            let offset = Vec2::new(angle.cos(), angle.sin()) * ring_radius;
+            let pos = center + offset;
+            let height = master_terrain.sample_height(pos) + RING_OFFSET;
+            let alpha = if ring == FALLOFF_RINGS {
+                1.0
+            } else {
+                brush_falloff(
+                    brush_info,
+                    offset / brush_info.size as f32 + Vec2::splat(0.5),
+                )
+            };
+            (
+                Vec3::new(pos.x, height, pos.y),
+                color.with_a(alpha.clamp(0.1, 1.0)),
+            )
+        });
+        gizmos.linestrip_gradient(points);
+    }
+}
+
+//Draws a grid at the heights the terrain would have after imprinting the stamp.
+fn draw_stamp_preview(
+    gizmos: &mut Gizmos,
+    master_terrain: &MasterTerrain,
+    sculpt_info: &SculptInfo,
+    center: Vec2,
+    color: Color,
+) {
+    if sculpt_info.stamp.is_none() {
+        return;
+    }
+    let base_height = master_terrain.sample_height(center);
+    let size = sculpt_info.brush_info.size as f32;
+    let rotation = Mat2::from_angle(sculpt_info.stamp_rotation.to_radians());
+    let point = |u: f32, v: f32| -> Option<Vec3> {
+        let pos = center + rotation * (Vec2::new(u, v) - Vec2::splat(0.5)) * size;
+        let world_pos = pos.round().as_ivec2();
+        let height = height_stamp_result(
+            master_terrain,
+            sculpt_info,
+            center,
+            Mat2::IDENTITY,
+            base_height,
+            world_pos,
+        )?;
+        Some(Vec3::new(pos.x, height + RING_OFFSET, pos.y))
+    };
+    for line in 0..=STAMP_GRID_LINES {
+        let line = line as f32 / STAMP_GRID_LINES as f32 * 0.999;
+        let mut along_u = Vec::new();
+        let mut along_v = Vec::new();
+        for segment in 0..=RING_SEGMENTS {
+            let t = segment as f32 / RING_SEGMENTS as f32 * 0.999;
+            along_u.extend(point(t, line));
+            along_v.extend(point(line, t));
+        }
+        gizmos.linestrip(along_u, color);
+        gizmos.linestrip(along_v, color);
+    }
+}
+
+//Draws the center line and edges of the ramp from its start to the cursor.
+//Before the start is placed, only the cross section at the cursor is shown.
+fn draw_ramp_preview(gizmos: &mut Gizmos, sculpt_info: &SculptInfo, end: Vec3, color: Color) {
+    let start = sculpt_info.ramp_start.unwrap_or(end);
+    let direction = Vec2::new(end.x - start.x, end.z - start.z).normalize_or_zero();
+    let direction = if direction == Vec2::ZERO {
+        Vec2::Y
+    } else {
+        direction
+    };
+    let side = Vec2::new(-direction.y, direction.x);
+    let offset = Vec3::Y * RING_OFFSET;
+    for (width, alpha) in [
+        (sculpt_info.ramp_width * 0.5, 1.0),
+        (sculpt_info.ramp_width * 0.5 + sculpt_info.ramp_falloff, 0.3),
+    ] {
+        let side = Vec3::new(side.x, 0.0, side.y) * width;
+        let color = color.with_a(alpha);
+        gizmos.linestrip(
+            [
+                start - side + offset,
+                end - side + offset,
+                end + side + offset,
+                start + side + offset,
+                start - side + offset,
+            ],
+            color,
+        );
+    }
+    gizmos.line(start + offset, end + offset, color);
+}
+
+//Brush strength at a normalized (0..1) position inside the brush footprint.
+//resize_vector transposes the map, so the axes are swapped here to match what the tools apply.
+fn brush_falloff(brush_info: &BrushInfo, uv: Vec2) -> f32 {
+    match &brush_info.selected_brush {
+        Some(brush) => sample_vec(uv.y, uv.x, &brush.map, brush.map_size as usize),
+        None => 1.0,
+    }
+}
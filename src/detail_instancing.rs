@@ -0,0 +1,1070 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+use bevy::{
+    asset::{AssetId, LoadState},
+    core_pipeline::{
+        core_3d::Opaque3d,
+        prepass::{DeferredPrepass, DepthPrepass, MotionVectorPrepass, NormalPrepass},
+        tonemapping::{DebandDither, Tonemapping},
+    },
+    ecs::system::{lifetimeless::*, SystemParamItem},
+    gltf::Gltf,
+    pbr::{
+        EnvironmentMapLight, MeshPipeline, MeshPipelineKey, RenderMeshInstances, SetMeshBindGroup,
+        SetMeshViewBindGroup, ShadowFilteringMethod,
+    },
+    prelude::*,
+    render::{
+        batching::NoAutomaticBatching,
+        camera::TemporalJitter,
+        mesh::{GpuBufferInfo, Indices, MeshVertexBufferLayout, VertexAttributeValues},
+        primitives::Aabb,
+        render_asset::RenderAssets,
+        render_phase::{
+            AddRenderCommand, DrawFunctions, PhaseItem, RenderCommand, RenderCommandResult,
+            RenderPhase, SetItemPipeline, TrackedRenderPass,
+        },
+        render_resource::*,
+        renderer::RenderDevice,
+        texture::FallbackImage,
+        view::{ExtractedView, NoFrustumCulling},
+        Extract, ExtractSchedule, Render, RenderApp, RenderSet,
+    },
+};
+use bytemuck::{Pod, Zeroable};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    asset_path,
+    details::DetailModel,
+    model_library::{ModelLibrary, ModelMetadata},
+    terrain::aabb_distance_to_point,
+};
+
+//Draws all details of a model in a chunk with one instanced draw call per mesh,
+//instead of spawning a scene hierarchy for every detail.
+pub struct DetailInstancingPlugin;
+impl Plugin for DetailInstancingPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(DetailBatches::default())
+            .insert_resource(ModelLods::default())
+            .add_systems(Update, (update_detail_batches, update_detail_lods).chain());
+        app.sub_app_mut(RenderApp)
+            .add_render_command::<Opaque3d, DrawDetailInstances>()
+            .init_resource::<SpecializedMeshPipelines<DetailPipeline>>()
+            .init_resource::<DetailInstanceBuffers>()
+            .init_resource::<DetailTextureBindGroups>()
+            .add_systems(ExtractSchedule, extract_detail_instances)
+            .add_systems(
+                Render,
+                (
+                    queue_detail_instances.in_set(RenderSet::QueueMeshes),
+                    prepare_instance_buffers.in_set(RenderSet::PrepareResources),
+                    prepare_texture_bind_groups.in_set(RenderSet::PrepareBindGroups),
+                ),
+            );
+    }
+    fn finish(&self, app: &mut App) {
+        app.sub_app_mut(RenderApp).init_resource::<DetailPipeline>();
+    }
+}
+
+//chunk position and model name
+type BatchKey = (IVec2, String);
+
+//cells of the silhouette that impostors are generated from
+const IMPOSTOR_COLUMNS: usize = 16;
+const IMPOSTOR_ROWS: usize = 8;
+
+#[derive(Resource, Default)]
+pub struct DetailBatches {
+    batches: HashMap<BatchKey, Batch>,
+    entity_keys: HashMap<Entity, BatchKey>,
+    dirty: HashSet<BatchKey>,
+    models: HashMap<String, LoadedModel>,
+}
+impl DetailBatches {
+    //Bounds of the full detail level of a model in model space, once it has loaded.
+    pub fn model_bounds(&self, name: &str) -> Option<Aabb> {
+        self.models.get(name)?.bounds
+    }
+}
+
+struct Batch {
+    //one entity per mesh of every level
+    entities: Vec<(usize, Entity)>,
+    //bounds of the detail positions, used for the camera distance
+    aabb: Aabb,
+    mesh_levels: usize,
+    impostor: bool,
+}
+
+//The model and its LODs, named model_lod1.glb, model_lod2.glb and so on next to it.
+struct LoadedModel {
+    gltfs: Vec<Handle<Gltf>>,
+    //the meshes of every LOD followed by the impostor if one could be made, once everything has loaded
+    levels: Option<Vec<Vec<ModelPart>>>,
+    mesh_levels: usize,
+    bounds: Option<Aabb>,
+    //set once a file of the model fails to load, so it isn't retried every frame
+    failed: bool,
+}
+impl LoadedModel {
+    fn load(name: &str, asset_server: &AssetServer) -> Self {
+        let mut gltfs = vec![asset_server.load(format!("models/{}", name))];
+        let path = Path::new(name);
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let extension = path.extension().unwrap_or_default().to_string_lossy();
+        for lod in 1.. {
+            let lod_path = format!("models/{}_lod{}.{}", stem, lod, extension);
+            if !asset_path(&lod_path).exists() {
+                break;
+            }
+            gltfs.push(asset_server.load(lod_path));
+        }
+        Self {
+            gltfs,
+            levels: None,
+            mesh_levels: 0,
+            bounds: None,
+            failed: false,
+        }
+    }
+
+    //Collects the meshes of every level once all files have loaded, or gives up on the model
+    //if one of them can't be loaded.
+    fn build(
+        &mut self,
+        name: &str,
+        asset_server: &AssetServer,
+        gltfs: &Assets<Gltf>,
+        scenes: &Assets<Scene>,
+        materials: &Assets<StandardMaterial>,
+        meshes: &mut Assets<Mesh>,
+    ) {
+        let states: Vec<_> = self
+            .gltfs
+            .iter()
+            .map(|handle| asset_server.get_load_state(handle.id()))
+            .collect();
+        if states.contains(&Some(LoadState::Failed)) {
+            error!("Failed to load the model {}", name);
+            self.failed = true;
+            return;
+        }
+        let levels: Option<Vec<Vec<ModelPart>>> = self
+            .gltfs
+            .iter()
+            .map(|handle| {
+                let gltf = gltfs.get(handle.id())?;
+                model_parts(gltf, scenes, materials)
+            })
+            .collect();
+        let Some(mut levels) = levels else {
+            //a file that loaded without a scene won't get one later
+            if states.iter().all(|state| *state == Some(LoadState::Loaded)) {
+                error!("Failed to load the model {}: it has no scene", name);
+                self.failed = true;
+            }
+            return;
+        };
+        self.mesh_levels = levels.len();
+        self.bounds = parts_bounds(&levels[0], meshes);
+        //without an impostor the last LOD is drawn up to the cull distance
+        if let Some(impostor) = impostor_mesh(&levels[0], meshes) {
+            levels.push(vec![ModelPart {
+                mesh: meshes.add(impostor),
+                transform: Mat4::IDENTITY,
+                color: Vec4::ONE,
+                material: DetailMaterial::default(),
+            }]);
+        }
+        self.levels = Some(levels);
+    }
+}
+
+//Whether a model file is a LOD of another model instead of a model of its own.
+pub fn is_lod_model(name: &str) -> bool {
+    let stem = Path::new(name)
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy();
+    match stem.rsplit_once("_lod") {
+        Some((_, lod)) => !lod.is_empty() && lod.chars().all(|c| c.is_ascii_digit()),
+        None => false,
+    }
+}
+
+//Distances at which a model switches to its LODs, its impostor and isn't drawn at all.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ModelLod {
+    //distance covered by each LOD
+    pub lod_step: f32,
+    pub impostor_distance: f32,
+    pub cull_distance: f32,
+}
+impl Default for ModelLod {
+    fn default() -> Self {
+        Self {
+            lod_step: 150.0,
+            impostor_distance: 400.0,
+            cull_distance: 1500.0,
+        }
+    }
+}
+impl ModelLod {
+    //The level to draw at distance, the level after the meshes is the impostor.
+    pub fn level(&self, distance: f32, mesh_levels: usize) -> Option<usize> {
+        if distance >= self.cull_distance {
+            None
+        } else if distance >= self.impostor_distance {
+            Some(mesh_levels)
+        } else {
+            Some(((distance / self.lod_step) as usize).min(mesh_levels - 1))
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct ModelLods {
+    pub models: HashMap<String, ModelLod>,
+}
+impl ModelLods {
+    pub fn get(&self, name: &str) -> ModelLod {
+        self.models.get(name).cloned().unwrap_or_default()
+    }
+}
+
+#[derive(Component)]
+pub struct DetailBatch {
+    pub chunk_pos: IVec2,
+    pub name: String,
+    pub level: usize,
+}
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct InstanceData {
+    pub transform: Mat4,
+    pub color: Vec4,
+}
+
+//Instances of the mesh on the same entity, also used for the generated ground cover.
+#[derive(Component)]
+pub struct DetailInstances {
+    pub instances: Vec<InstanceData>,
+    pub billboard: bool,
+    pub material: DetailMaterial,
+}
+
+//The parts of the StandardMaterial of a mesh that the detail shader uses besides the
+//base colour.
+#[derive(Clone, Default)]
+pub struct DetailMaterial {
+    pub texture: Option<Handle<Image>>,
+    pub alpha_mask: bool,
+    pub double_sided: bool,
+}
+
+//A mesh of a model with its transform inside the model and its material.
+#[derive(Clone)]
+pub struct ModelPart {
+    pub mesh: Handle<Mesh>,
+    pub transform: Mat4,
+    pub color: Vec4,
+    pub material: DetailMaterial,
+}
+//The meshes are read from the scene of the glTF file, so they keep the transforms of the
+//nodes they are nested in.
+pub fn model_parts(
+    gltf: &Gltf,
+    scenes: &Assets<Scene>,
+    materials: &Assets<StandardMaterial>,
+) -> Option<Vec<ModelPart>> {
+    let scene = gltf.default_scene.as_ref().or(gltf.scenes.first())?;
+    let world = &scenes.get(scene)?.world;
+    let mut parts = Vec::new();
+    for entity in world.iter_entities() {
+        let Some(mesh) = entity.get::<Handle<Mesh>>() else {
+            continue;
+        };
+        let mut transform = Mat4::IDENTITY;
+        let mut node = Some(entity.id());
+        while let Some(id) = node {
+            if let Some(local) = world.get::<Transform>(id) {
+                transform = local.compute_matrix() * transform;
+            }
+            node = world.get::<Parent>(id).map(|parent| parent.get());
+        }
+        let material = entity
+            .get::<Handle<StandardMaterial>>()
+            .and_then(|material| materials.get(material));
+        parts.push(ModelPart {
+            mesh: mesh.clone(),
+            transform,
+            color: Vec4::from(
+                material
+                    .map_or(Color::WHITE, |material| material.base_color)
+                    .as_rgba_f32(),
+            ),
+            material: material.map_or_else(DetailMaterial::default, |material| DetailMaterial {
+                texture: material.base_color_texture.clone(),
+                alpha_mask: matches!(material.alpha_mode, AlphaMode::Mask(_)),
+                double_sided: material.double_sided,
+            }),
+        });
+    }
+    Some(parts)
+}
+
+//The triangles of the model seen from the side, with the colour of their part.
+pub fn side_view_triangles(
+    parts: &[ModelPart],
+    meshes: &Assets<Mesh>,
+) -> Option<Vec<([Vec2; 3], Vec4)>> {
+    let mut triangles = Vec::new();
+    for part in parts {
+        let mesh = meshes.get(&part.mesh)?;
+        let positions = match mesh.attribute(Mesh::ATTRIBUTE_POSITION) {
+            Some(VertexAttributeValues::Float32x3(positions)) => positions,
+            _ => continue,
+        };
+        let positions: Vec<Vec2> = positions
+            .iter()
+            .map(|pos| part.transform.transform_point3(Vec3::from(*pos)).truncate())
+            .collect();
+        let indices: Vec<usize> = match mesh.indices() {
+            Some(indices) => indices.iter().collect(),
+            None => (0..positions.len()).collect(),
+        };
+        for triangle in indices.chunks_exact(3) {
+            triangles.push((
+                [
+                    positions[triangle[0]],
+                    positions[triangle[1]],
+                    positions[triangle[2]],
+                ],
+                part.color,
+            ));
+        }
+    }
+    if triangles.is_empty() {
+        return None;
+    }
+    Some(triangles)
+}
+
+//Flat stand-in for a model seen from far away. The side view of the model is cut into
+//horizontal slabs that each get the colour of what they cover, the shader turns it to
+//the camera.
+fn impostor_mesh(parts: &[ModelPart], meshes: &Assets<Mesh>) -> Option<Mesh> {
+    let triangles = side_view_triangles(parts, meshes)?;
+    let (min, max) = triangles
+        .iter()
+        .flat_map(|(triangle, _)| triangle.iter())
+        .fold((Vec2::MAX, Vec2::MIN), |(min, max), pos| {
+            (min.min(*pos), max.max(*pos))
+        });
+    let cell_size = (max - min) / Vec2::new(IMPOSTOR_COLUMNS as f32, IMPOSTOR_ROWS as f32);
+
+    let mut cells = vec![None; IMPOSTOR_COLUMNS * IMPOSTOR_ROWS];
+    for (triangle, color) in &triangles {
+        for y in 0..IMPOSTOR_ROWS {
+            for x in 0..IMPOSTOR_COLUMNS {
+                let point = min + (Vec2::new(x as f32, y as f32) + 0.5) * cell_size;
+                if point_in_triangle(point, triangle) {
+                    cells[x + y * IMPOSTOR_COLUMNS] = Some(*color);
+                }
+            }
+        }
+    }
+
+    let mut positions = Vec::new();
+    let mut colors = Vec::new();
+    let mut indices = Vec::new();
+    for y in 0..IMPOSTOR_ROWS {
+        let row = &cells[y * IMPOSTOR_COLUMNS..(y + 1) * IMPOSTOR_COLUMNS];
+        let covered: Vec<(usize, Vec4)> = row
+            .iter()
+            .enumerate()
+            .filter_map(|(x, color)| color.map(|color| (x, color)))
+            .collect();
+        let (first, last) = match (covered.first(), covered.last()) {
+            (Some(first), Some(last)) => (first.0, last.0),
+            _ => continue,
+        };
+        let color = covered.iter().map(|(_, color)| *color).sum::<Vec4>() / covered.len() as f32;
+        let start = min + Vec2::new(first as f32, y as f32) * cell_size;
+        let end = min + Vec2::new((last + 1) as f32, (y + 1) as f32) * cell_size;
+        let index = positions.len() as u32;
+        positions.extend([
+            [start.x, start.y, 0.0],
+            [end.x, start.y, 0.0],
+            [end.x, end.y, 0.0],
+            [start.x, end.y, 0.0],
+        ]);
+        colors.extend([Color::from(color).as_linear_rgba_f32(); 4]);
+        indices.extend([index, index + 1, index + 2, index, index + 2, index + 3]);
+    }
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    mesh.insert_attribute(
+        Mesh::ATTRIBUTE_NORMAL,
+        vec![[0.0, 0.0, 1.0]; positions.len()],
+    );
+    mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.set_indices(Some(Indices::U32(indices)));
+    Some(mesh)
+}
+pub fn point_in_triangle(point: Vec2, [a, b, c]: &[Vec2; 3]) -> bool {
+    let ab = (*b - *a).perp_dot(point - *a);
+    let bc = (*c - *b).perp_dot(point - *b);
+    let ca = (*a - *c).perp_dot(point - *c);
+    (ab >= 0.0 && bc >= 0.0 && ca >= 0.0) || (ab <= 0.0 && bc <= 0.0 && ca <= 0.0)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn update_detail_batches(
+    mut commands: Commands,
+    mut batches: ResMut<DetailBatches>,
+    asset_server: Res<AssetServer>,
+    gltfs: Res<Assets<Gltf>>,
+    scenes: Res<Assets<Scene>>,
+    materials: Res<Assets<StandardMaterial>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut model_library: ResMut<ModelLibrary>,
+    details: Query<(&DetailModel, &Transform)>,
+    changed: Query<(Entity, &DetailModel), Or<(Changed<DetailModel>, Changed<Transform>)>>,
+    mut removed: RemovedComponents<DetailModel>,
+) {
+    let batches = &mut *batches;
+    for (entity, detail_model) in &changed {
+        let key = (detail_model.chunk_pos, detail_model.name.clone());
+        //a detail that moved to another chunk also has to leave its old batch
+        if let Some(old_key) = batches.entity_keys.insert(entity, key.clone()) {
+            batches.dirty.insert(old_key);
+        }
+        batches.dirty.insert(key);
+    }
+    for entity in removed.read() {
+        if let Some(key) = batches.entity_keys.remove(&entity) {
+            batches.dirty.insert(key);
+        }
+    }
+    //the pivot and embed depth of a model are part of its instance transforms
+    let changed_models = model_library.take_changed();
+    if !changed_models.is_empty() {
+        for key in batches.entity_keys.values() {
+            if changed_models.contains(&key.1) {
+                batches.dirty.insert(key.clone());
+            }
+        }
+    }
+    if batches.dirty.is_empty() {
+        return;
+    }
+
+    //models that are still loading are tried again next frame, the others are rebuilt now
+    let mut ready = HashSet::new();
+    for key in std::mem::take(&mut batches.dirty) {
+        let model = batches
+            .models
+            .entry(key.1.clone())
+            .or_insert_with(|| LoadedModel::load(&key.1, &asset_server));
+        if model.levels.is_none() && !model.failed {
+            model.build(
+                &key.1,
+                &asset_server,
+                &gltfs,
+                &scenes,
+                &materials,
+                &mut meshes,
+            );
+        }
+        if model.levels.is_some() {
+            ready.insert(key);
+        } else if !model.failed {
+            batches.dirty.insert(key);
+        }
+    }
+    if ready.is_empty() {
+        return;
+    }
+
+    let mut instances: HashMap<&BatchKey, Vec<Mat4>> = HashMap::new();
+    for (detail_model, transform) in &details {
+        let key = (detail_model.chunk_pos, detail_model.name.clone());
+        if let Some(key) = ready.get(&key) {
+            instances
+                .entry(key)
+                .or_default()
+                .push(detail_matrix(transform, model_library.metadata.get(&key.1)));
+        }
+    }
+    for key in &ready {
+        let model = &batches.models[&key.1];
+        let Some(levels) = &model.levels else {
+            continue;
+        };
+        if let Some(batch) = batches.batches.remove(key) {
+            for (_, entity) in batch.entities {
+                commands.entity(entity).despawn();
+            }
+        }
+        let transforms = match instances.get(key) {
+            Some(transforms) => transforms,
+            None => continue,
+        };
+        let mesh_levels = model.mesh_levels;
+        let mut entities = Vec::new();
+        for (level, parts) in levels.iter().enumerate() {
+            for part in parts {
+                //the detail shader lights linear colours
+                let color = Vec4::from(Color::from(part.color).as_linear_rgba_f32());
+                let data = transforms
+                    .iter()
+                    .map(|transform| InstanceData {
+                        transform: *transform * part.transform,
+                        color,
+                    })
+                    .collect();
+                let entity = commands
+                    .spawn((
+                        part.mesh.clone(),
+                        //shown by update_detail_lods
+                        SpatialBundle::HIDDEN_IDENTITY,
+                        DetailInstances {
+                            instances: data,
+                            billboard: level == mesh_levels,
+                            material: part.material.clone(),
+                        },
+                        DetailBatch {
+                            chunk_pos: key.0,
+                            name: key.1.clone(),
+                            level,
+                        },
+                        //the instances aren't covered by the mesh bounds at the origin
+                        NoFrustumCulling,
+                        //every batch draws its own instances, even if it shares the mesh
+                        NoAutomaticBatching,
+                    ))
+                    .id();
+                entities.push((level, entity));
+            }
+        }
+        let (min, max) = transforms
+            .iter()
+            .map(|transform| transform.w_axis.truncate())
+            .fold((Vec3::MAX, Vec3::MIN), |(min, max), pos| {
+                (min.min(pos), max.max(pos))
+            });
+        batches.batches.insert(
+            key.clone(),
+            Batch {
+                entities,
+                aabb: Aabb::from_min_max(min, max),
+                mesh_levels,
+                impostor: levels.len() > mesh_levels,
+            },
+        );
+    }
+}
+
+//Where the model of a detail is drawn, with the pivot and embed depth of the model.
+pub fn detail_matrix(transform: &Transform, metadata: Option<&ModelMetadata>) -> Mat4 {
+    let (pivot_offset, embed_depth) = metadata.map_or((Vec3::ZERO, 0.0), |metadata| {
+        (metadata.pivot_offset, metadata.embed_depth)
+    });
+    Mat4::from_translation(Vec3::NEG_Y * embed_depth)
+        * transform.compute_matrix()
+        * Mat4::from_translation(pivot_offset)
+}
+
+fn parts_bounds(parts: &[ModelPart], meshes: &Assets<Mesh>) -> Option<Aabb> {
+    let mut corners = Vec::new();
+    for part in parts {
+        let aabb = meshes.get(&part.mesh)?.compute_aabb()?;
+        let (min, max) = (Vec3::from(aabb.min()), Vec3::from(aabb.max()));
+        for corner in 0..8 {
+            let pos = Vec3::select(
+                BVec3::new(corner & 1 != 0, corner & 2 != 0, corner & 4 != 0),
+                max,
+                min,
+            );
+            corners.push(part.transform.transform_point3(pos));
+        }
+    }
+    if corners.is_empty() {
+        return None;
+    }
+    let (min, max) = corners
+        .iter()
+        .fold((Vec3::MAX, Vec3::MIN), |(min, max), pos| {
+            (min.min(*pos), max.max(*pos))
+        });
+    Some(Aabb::from_min_max(min, max))
+}
+
+//Shows the level of every batch that fits its distance to the camera.
+fn update_detail_lods(
+    batches: Res<DetailBatches>,
+    model_lods: Res<ModelLods>,
+    camera: Query<&Transform, With<Camera>>,
+    mut visibilities: Query<&mut Visibility, With<DetailBatch>>,
+) {
+    let camera_tf = camera.single();
+    for ((_, name), batch) in &batches.batches {
+        let distance = aabb_distance_to_point(Vec3::ZERO, &batch.aabb, camera_tf.translation);
+        let mut level = model_lods.get(name).level(distance, batch.mesh_levels);
+        if !batch.impostor {
+            level = level.map(|level| level.min(batch.mesh_levels - 1));
+        }
+        for (entity_level, entity) in &batch.entities {
+            let new_visibility = if Some(*entity_level) == level {
+                Visibility::Inherited
+            } else {
+                Visibility::Hidden
+            };
+            if let Ok(mut visibility) = visibilities.get_mut(*entity) {
+                if *visibility != new_visibility {
+                    *visibility = new_visibility;
+                }
+            }
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+fn queue_detail_instances(
+    opaque_3d_draw_functions: Res<DrawFunctions<Opaque3d>>,
+    detail_pipeline: Res<DetailPipeline>,
+    msaa: Res<Msaa>,
+    mut pipelines: ResMut<SpecializedMeshPipelines<DetailPipeline>>,
+    pipeline_cache: Res<PipelineCache>,
+    meshes: Res<RenderAssets<Mesh>>,
+    images: Res<RenderAssets<Image>>,
+    render_mesh_instances: Res<RenderMeshInstances>,
+    detail_batches: Query<(Entity, &ExtractedDetailInstances)>,
+    mut views: Query<(
+        &ExtractedView,
+        Option<&Tonemapping>,
+        Option<&DebandDither>,
+        Option<&EnvironmentMapLight>,
+        Option<&ShadowFilteringMethod>,
+        (
+            Has<NormalPrepass>,
+            Has<DepthPrepass>,
+            Has<MotionVectorPrepass>,
+            Has<DeferredPrepass>,
+        ),
+        Option<&TemporalJitter>,
+        Option<&Projection>,
+        &mut RenderPhase<Opaque3d>,
+    )>,
+) {
+    let draw_detail_instances = opaque_3d_draw_functions.read().id::<DrawDetailInstances>();
+    let msaa_key = MeshPipelineKey::from_msaa_samples(msaa.samples());
+
+    for (
+        view,
+        tonemapping,
+        dither,
+        environment_map,
+        shadow_filter_method,
+        (normal_prepass, depth_prepass, motion_vector_prepass, deferred_prepass),
+        temporal_jitter,
+        projection,
+        mut opaque_phase,
+    ) in &mut views
+    {
+        //the same view key as the meshes of the StandardMaterial, so the details get the
+        //same lights, shadows and tonemapping
+        let mut view_key = msaa_key | MeshPipelineKey::from_hdr(view.hdr);
+        if normal_prepass {
+            view_key |= MeshPipelineKey::NORMAL_PREPASS;
+        }
+        if depth_prepass {
+            view_key |= MeshPipelineKey::DEPTH_PREPASS;
+        }
+        if motion_vector_prepass {
+            view_key |= MeshPipelineKey::MOTION_VECTOR_PREPASS;
+        }
+        if deferred_prepass {
+            view_key |= MeshPipelineKey::DEFERRED_PREPASS;
+        }
+        if temporal_jitter.is_some() {
+            view_key |= MeshPipelineKey::TEMPORAL_JITTER;
+        }
+        if environment_map.is_some_and(|map| map.is_loaded(&images)) {
+            view_key |= MeshPipelineKey::ENVIRONMENT_MAP;
+        }
+        if let Some(projection) = projection {
+            view_key |= match projection {
+                Projection::Perspective(_) => MeshPipelineKey::VIEW_PROJECTION_PERSPECTIVE,
+                Projection::Orthographic(_) => MeshPipelineKey::VIEW_PROJECTION_ORTHOGRAPHIC,
+            };
+        }
+        view_key |= match shadow_filter_method.unwrap_or(&ShadowFilteringMethod::default()) {
+            ShadowFilteringMethod::Hardware2x2 => {
+                MeshPipelineKey::SHADOW_FILTER_METHOD_HARDWARE_2X2
+            }
+            ShadowFilteringMethod::Castano13 => MeshPipelineKey::SHADOW_FILTER_METHOD_CASTANO_13,
+            ShadowFilteringMethod::Jimenez14 => MeshPipelineKey::SHADOW_FILTER_METHOD_JIMENEZ_14,
+        };
+        if !view.hdr {
+            if let Some(tonemapping) = tonemapping {
+                view_key |= MeshPipelineKey::TONEMAP_IN_SHADER | tonemapping_key(*tonemapping);
+            }
+            if let Some(DebandDither::Enabled) = dither {
+                view_key |= MeshPipelineKey::DEBAND_DITHER;
+            }
+        }
+
+        let rangefinder = view.rangefinder3d();
+        for (entity, instances) in &detail_batches {
+            let Some(mesh_instance) = render_mesh_instances.get(&entity) else {
+                continue;
+            };
+            let Some(mesh) = meshes.get(mesh_instance.mesh_asset_id) else {
+                continue;
+            };
+            let key = DetailPipelineKey {
+                mesh_key: view_key
+                    | MeshPipelineKey::from_primitive_topology(mesh.primitive_topology),
+                billboard: instances.billboard,
+                alpha_mask: instances.alpha_mask,
+                double_sided: instances.double_sided,
+            };
+            let pipeline =
+                match pipelines.specialize(&pipeline_cache, &detail_pipeline, key, &mesh.layout) {
+                    Ok(pipeline) => pipeline,
+                    Err(err) => {
+                        error!("Failed to specialize the detail pipeline: {err}");
+                        continue;
+                    }
+                };
+            opaque_phase.add(Opaque3d {
+                entity,
+                pipeline,
+                draw_function: draw_detail_instances,
+                distance: rangefinder
+                    .distance_translation(&mesh_instance.transforms.transform.translation),
+                batch_range: 0..1,
+                dynamic_offset: None,
+            });
+        }
+    }
+}
+
+const fn tonemapping_key(tonemapping: Tonemapping) -> MeshPipelineKey {
+    match tonemapping {
+        Tonemapping::None => MeshPipelineKey::TONEMAP_METHOD_NONE,
+        Tonemapping::Reinhard => MeshPipelineKey::TONEMAP_METHOD_REINHARD,
+        Tonemapping::ReinhardLuminance => MeshPipelineKey::TONEMAP_METHOD_REINHARD_LUMINANCE,
+        Tonemapping::AcesFitted => MeshPipelineKey::TONEMAP_METHOD_ACES_FITTED,
+        Tonemapping::AgX => MeshPipelineKey::TONEMAP_METHOD_AGX,
+        Tonemapping::SomewhatBoringDisplayTransform => {
+            MeshPipelineKey::TONEMAP_METHOD_SOMEWHAT_BORING_DISPLAY_TRANSFORM
+        }
+        Tonemapping::TonyMcMapface => MeshPipelineKey::TONEMAP_METHOD_TONY_MC_MAPFACE,
+        Tonemapping::BlenderFilmic => MeshPipelineKey::TONEMAP_METHOD_BLENDER_FILMIC,
+    }
+}
+
+struct InstanceBuffer {
+    buffer: Buffer,
+    length: usize,
+}
+
+//The instance buffers are kept between frames, the instances are only copied to the
+//render world and uploaded again when their batch changes.
+#[derive(Resource, Default)]
+struct DetailInstanceBuffers {
+    buffers: HashMap<Entity, InstanceBuffer>,
+    pending: Vec<(Entity, Vec<InstanceData>)>,
+}
+
+//Marks the visible batches in the render world, hidden levels aren't drawn.
+#[derive(Component)]
+struct ExtractedDetailInstances {
+    billboard: bool,
+    texture: Option<AssetId<Image>>,
+    alpha_mask: bool,
+    double_sided: bool,
+}
+
+fn extract_detail_instances(
+    mut commands: Commands,
+    mut instance_buffers: ResMut<DetailInstanceBuffers>,
+    query: Extract<Query<(Entity, Ref<DetailInstances>, &ViewVisibility)>>,
+) {
+    instance_buffers
+        .buffers
+        .retain(|entity, _| query.contains(*entity));
+    let mut visible = Vec::new();
+    for (entity, instances, view_visibility) in &query {
+        if instances.is_changed() {
+            instance_buffers
+                .pending
+                .push((entity, instances.instances.clone()));
+        }
+        if view_visibility.get() {
+            visible.push((
+                entity,
+                ExtractedDetailInstances {
+                    billboard: instances.billboard,
+                    texture: instances
+                        .material
+                        .texture
+                        .as_ref()
+                        .map(|texture| texture.id()),
+                    alpha_mask: instances.material.alpha_mask,
+                    double_sided: instances.material.double_sided,
+                },
+            ));
+        }
+    }
+    commands.insert_or_spawn_batch(visible);
+}
+
+fn prepare_instance_buffers(
+    mut instance_buffers: ResMut<DetailInstanceBuffers>,
+    render_device: Res<RenderDevice>,
+) {
+    let instance_buffers = &mut *instance_buffers;
+    for (entity, instances) in instance_buffers.pending.drain(..) {
+        let buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("detail instance buffer"),
+            contents: bytemuck::cast_slice(instances.as_slice()),
+            usage: BufferUsages::VERTEX | BufferUsages::COPY_DST,
+        });
+        instance_buffers.buffers.insert(
+            entity,
+            InstanceBuffer {
+                buffer,
+                length: instances.len(),
+            },
+        );
+    }
+}
+
+//The base colour texture of every material that is drawn, meshes without one get the
+//white fallback image.
+#[derive(Resource, Default)]
+struct DetailTextureBindGroups {
+    bind_groups: HashMap<Option<AssetId<Image>>, BindGroup>,
+}
+
+fn prepare_texture_bind_groups(
+    mut texture_bind_groups: ResMut<DetailTextureBindGroups>,
+    detail_pipeline: Res<DetailPipeline>,
+    detail_batches: Query<&ExtractedDetailInstances>,
+    images: Res<RenderAssets<Image>>,
+    fallback_image: Res<FallbackImage>,
+    render_device: Res<RenderDevice>,
+) {
+    //rebuilt every frame, so textures that finished loading replace the fallback
+    texture_bind_groups.bind_groups.clear();
+    for instances in &detail_batches {
+        if texture_bind_groups
+            .bind_groups
+            .contains_key(&instances.texture)
+        {
+            continue;
+        }
+        let image = instances
+            .texture
+            .and_then(|texture| images.get(texture))
+            .unwrap_or(&fallback_image.d2);
+        let bind_group = render_device.create_bind_group(
+            "detail texture bind group",
+            &detail_pipeline.texture_layout,
+            &BindGroupEntries::sequential((&image.texture_view, &image.sampler)),
+        );
+        texture_bind_groups
+            .bind_groups
+            .insert(instances.texture, bind_group);
+    }
+}
+
+#[derive(Resource)]
+struct DetailPipeline {
+    shader: Handle<Shader>,
+    mesh_pipeline: MeshPipeline,
+    texture_layout: BindGroupLayout,
+}
+impl FromWorld for DetailPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let asset_server = world.resource::<AssetServer>();
+        let shader = asset_server.load("shaders/detail_instancing.wgsl");
+        let render_device = world.resource::<RenderDevice>();
+        let texture_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("detail texture layout"),
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Texture {
+                        sample_type: TextureSampleType::Float { filterable: true },
+                        view_dimension: TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Sampler(SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+        });
+        let mesh_pipeline = world.resource::<MeshPipeline>();
+        DetailPipeline {
+            shader,
+            mesh_pipeline: mesh_pipeline.clone(),
+            texture_layout,
+        }
+    }
+}
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct DetailPipelineKey {
+    mesh_key: MeshPipelineKey,
+    billboard: bool,
+    alpha_mask: bool,
+    double_sided: bool,
+}
+impl SpecializedMeshPipeline for DetailPipeline {
+    type Key = DetailPipelineKey;
+
+    fn specialize(
+        &self,
+        key: Self::Key,
+        layout: &MeshVertexBufferLayout,
+    ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
+        let mut descriptor = self.mesh_pipeline.specialize(key.mesh_key, layout)?;
+        descriptor.layout.push(self.texture_layout.clone());
+        let mut shader_defs: Vec<ShaderDefVal> = vec!["MESH_BINDGROUP_1".into()];
+        if key.billboard {
+            shader_defs.push("DETAIL_BILLBOARD".into());
+        }
+        if key.alpha_mask {
+            shader_defs.push("DETAIL_ALPHA_MASK".into());
+        }
+        if key.double_sided {
+            shader_defs.push("DETAIL_DOUBLE_SIDED".into());
+            descriptor.primitive.cull_mode = None;
+        }
+        descriptor.vertex.shader_defs.extend(shader_defs.clone());
+        descriptor.vertex.shader = self.shader.clone();
+        //the transform takes four locations, one per column
+        let mut attributes: Vec<VertexAttribute> = (0..4)
+            .map(|column| VertexAttribute {
+                format: VertexFormat::Float32x4,
+                offset: VertexFormat::Float32x4.size() * column,
+                shader_location: 8 + column as u32,
+            })
+            .collect();
+        attributes.push(VertexAttribute {
+            format: VertexFormat::Float32x4,
+            offset: VertexFormat::Float32x4.size() * 4,
+            shader_location: 12,
+        });
+        descriptor.vertex.buffers.push(VertexBufferLayout {
+            array_stride: std::mem::size_of::<InstanceData>() as u64,
+            step_mode: VertexStepMode::Instance,
+            attributes,
+        });
+        let fragment = descriptor.fragment.as_mut().unwrap();
+        fragment.shader_defs.extend(shader_defs);
+        fragment.shader = self.shader.clone();
+        Ok(descriptor)
+    }
+}
+
+type DrawDetailInstances = (
+    SetItemPipeline,
+    SetMeshViewBindGroup<0>,
+    SetMeshBindGroup<1>,
+    SetDetailTextureBindGroup<2>,
+    DrawMeshInstanced,
+);
+
+struct SetDetailTextureBindGroup<const I: usize>;
+impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetDetailTextureBindGroup<I> {
+    type Param = SRes<DetailTextureBindGroups>;
+    type ViewWorldQuery = ();
+    type ItemWorldQuery = Read<ExtractedDetailInstances>;
+
+    #[inline]
+    fn render<'w>(
+        _item: &P,
+        _view: (),
+        instances: &'w ExtractedDetailInstances,
+        texture_bind_groups: SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let Some(bind_group) = texture_bind_groups
+            .into_inner()
+            .bind_groups
+            .get(&instances.texture)
+        else {
+            return RenderCommandResult::Failure;
+        };
+        pass.set_bind_group(I, bind_group, &[]);
+        RenderCommandResult::Success
+    }
+}
+
+struct DrawMeshInstanced;
+impl<P: PhaseItem> RenderCommand<P> for DrawMeshInstanced {
+    type Param = (
+        SRes<RenderAssets<Mesh>>,
+        SRes<RenderMeshInstances>,
+        SRes<DetailInstanceBuffers>,
+    );
+    type ViewWorldQuery = ();
+    type ItemWorldQuery = ();
+
+    #[inline]
+    fn render<'w>(
+        item: &P,
+        _view: (),
+        _item: (),
+        (meshes, render_mesh_instances, instance_buffers): SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let Some(mesh_instance) = render_mesh_instances.get(&item.entity()) else {
+            return RenderCommandResult::Failure;
+        };
+        let Some(instance_buffer) = instance_buffers.into_inner().buffers.get(&item.entity())
+        else {
+            return RenderCommandResult::Failure;
+        };
+        let gpu_mesh = match meshes.into_inner().get(mesh_instance.mesh_asset_id) {
+            Some(gpu_mesh) => gpu_mesh,
+            None => return RenderCommandResult::Failure,
+        };
+
+        pass.set_vertex_buffer(0, gpu_mesh.vertex_buffer.slice(..));
+        pass.set_vertex_buffer(1, instance_buffer.buffer.slice(..));
+
+        match &gpu_mesh.buffer_info {
+            GpuBufferInfo::Indexed {
+                buffer,
+                index_format,
+                count,
+            } => {
+                pass.set_index_buffer(buffer.slice(..), 0, *index_format);
+                pass.draw_indexed(0..*count, 0, 0..instance_buffer.length as u32);
+            }
+            GpuBufferInfo::NonIndexed => {
+                pass.draw(0..gpu_mesh.vertex_count, 0..instance_buffer.length as u32);
+            }
+        }
+        RenderCommandResult::Success
+    }
+}
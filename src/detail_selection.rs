@@ -0,0 +1,558 @@
+use bevy::{prelude::*, render::primitives::Aabb};
+use bevy_egui::{egui, EguiContexts};
+
+use crate::{
+    detail_instancing::{detail_matrix, DetailBatches},
+    details::{move_detail, spawn_detail, DetailModel},
+    model_library::ModelLibrary,
+    terrain::MasterTerrain,
+    ui::{EditInfo, EditMode, UiHovered},
+};
+
+pub struct DetailSelectionPlugin;
+impl Plugin for DetailSelectionPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(DetailSelection::default()).add_systems(
+            Update,
+            (select_details, selection_actions, draw_selection).chain(),
+        );
+    }
+}
+
+//how close in pixels the cursor has to be to grab a handle
+const HANDLE_PICK_DISTANCE: f32 = 8.0;
+//width of the rotation ring that can be grabbed, relative to its radius
+const RING_PICK_WIDTH: f32 = 0.15;
+//the gizmo keeps the same size on screen, this is its size relative to the camera distance
+const GIZMO_SCALE: f32 = 0.15;
+const GIZMO_CENTER_RADIUS: f32 = 0.08;
+//dragging this many pixels up doubles the scale
+const SCALE_DRAG_PIXELS: f32 = 200.0;
+const MIN_SCALE_FACTOR: f32 = 0.05;
+//a press that moves less than this is a click instead of a box
+const BOX_MIN_PIXELS: f32 = 4.0;
+const DUPLICATE_OFFSET: Vec2 = Vec2::new(2.0, 2.0);
+const SELECTION_RING_RADIUS: f32 = 1.0;
+const RING_OFFSET: f32 = 0.2;
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum GizmoMode {
+    Move,
+    Rotate,
+    Scale,
+}
+impl ToString for GizmoMode {
+    fn to_string(&self) -> String {
+        match self {
+            GizmoMode::Move => "Move",
+            GizmoMode::Rotate => "Rotate",
+            GizmoMode::Scale => "Scale",
+        }
+        .to_string()
+    }
+}
+impl Default for GizmoMode {
+    fn default() -> Self {
+        Self::Move
+    }
+}
+
+#[derive(PartialEq, Clone)]
+pub enum SelectionAction {
+    Delete,
+    Duplicate,
+}
+
+//Values of a single selected detail, shown in the properties panel.
+pub struct DetailProperties {
+    pub name: String,
+    pub pos: Vec2,
+    //in degrees
+    pub yaw: f32,
+    pub scale: f32,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum GizmoHandle {
+    Free,
+    AxisX,
+    AxisZ,
+    Ring,
+    Scale,
+}
+
+//A handle being dragged, the details are moved from where they were when it was grabbed.
+struct GizmoDrag {
+    handle: GizmoHandle,
+    center: Vec3,
+    start_cursor: Vec2,
+    start_hit: Vec3,
+    start: Vec<(Entity, Vec2, Quat, Vec3)>,
+}
+
+#[derive(Resource, Default)]
+pub struct DetailSelection {
+    pub selected: Vec<Entity>,
+    pub gizmo_mode: GizmoMode,
+    pub properties: Option<DetailProperties>,
+    pub properties_changed: bool,
+    pub action: Option<SelectionAction>,
+    box_start: Option<Vec2>,
+    drag: Option<GizmoDrag>,
+}
+
+fn selection_center(positions: impl Iterator<Item = Vec3>) -> Option<Vec3> {
+    let (sum, count) = positions.fold((Vec3::ZERO, 0), |(sum, count), pos| (sum + pos, count + 1));
+    if count == 0 {
+        None
+    } else {
+        Some(sum / count as f32)
+    }
+}
+fn gizmo_size(camera_tf: &GlobalTransform, center: Vec3) -> f32 {
+    camera_tf.translation().distance(center) * GIZMO_SCALE
+}
+//Where the ray hits the horizontal plane through center.
+fn plane_hit(ray: Ray, center: Vec3) -> Option<Vec3> {
+    ray.intersect_plane(center, Vec3::Y)
+        .map(|distance| ray.get_point(distance))
+}
+fn distance_to_segment(point: Vec2, a: Vec2, b: Vec2) -> f32 {
+    let ab = b - a;
+    let t = ((point - a).dot(ab) / ab.length_squared().max(f32::EPSILON)).clamp(0.0, 1.0);
+    point.distance(a + ab * t)
+}
+
+fn pick_handle(
+    camera: &Camera,
+    camera_tf: &GlobalTransform,
+    ray: Ray,
+    cursor: Vec2,
+    center: Vec3,
+    gizmo_mode: GizmoMode,
+) -> Option<GizmoHandle> {
+    let size = gizmo_size(camera_tf, center);
+    let screen_center = camera.world_to_viewport(camera_tf, center)?;
+    let near_axis = |axis: Vec3| {
+        camera
+            .world_to_viewport(camera_tf, center + axis * size)
+            .map_or(false, |end| {
+                distance_to_segment(cursor, screen_center, end) < HANDLE_PICK_DISTANCE
+            })
+    };
+    match gizmo_mode {
+        GizmoMode::Move => {
+            if cursor.distance(screen_center) < HANDLE_PICK_DISTANCE * 2.0 {
+                Some(GizmoHandle::Free)
+            } else if near_axis(Vec3::X) {
+                Some(GizmoHandle::AxisX)
+            } else if near_axis(Vec3::Z) {
+                Some(GizmoHandle::AxisZ)
+            } else {
+                None
+            }
+        }
+        GizmoMode::Rotate => {
+            let hit = plane_hit(ray, center)?;
+            if (hit.distance(center) - size).abs() < size * RING_PICK_WIDTH {
+                Some(GizmoHandle::Ring)
+            } else {
+                None
+            }
+        }
+        GizmoMode::Scale => {
+            if near_axis(Vec3::Y) {
+                Some(GizmoHandle::Scale)
+            } else {
+                None
+            }
+        }
+    }
+}
+
+//The closest detail under the cursor. Details are hit on the bounds of their model, or
+//on a sphere of their collision radius until the model has loaded.
+fn pick_detail<'a>(
+    ray: Ray,
+    details: impl Iterator<Item = (Entity, &'a DetailModel, &'a Transform)>,
+    batches: &DetailBatches,
+    model_library: &ModelLibrary,
+) -> Option<Entity> {
+    let mut closest = None;
+    let mut closest_distance = f32::MAX;
+    for (entity, detail_model, transform) in details {
+        let metadata = model_library.metadata.get(&detail_model.name);
+        let distance = match batches.model_bounds(&detail_model.name) {
+            Some(bounds) => ray_box_distance(ray, detail_matrix(transform, metadata), &bounds),
+            None => {
+                let radius = model_library.get(&detail_model.name).collision_radius
+                    * transform.scale.max_element();
+                ray_sphere_distance(ray, transform.translation + Vec3::Y * radius, radius)
+            }
+        };
+        if let Some(distance) = distance {
+            if distance < closest_distance {
+                closest = Some(entity);
+                closest_distance = distance;
+            }
+        }
+    }
+    closest
+}
+//Distance along the ray to where it enters the box, which is given in the space of
+//model_matrix.
+fn ray_box_distance(ray: Ray, model_matrix: Mat4, aabb: &Aabb) -> Option<f32> {
+    let inverse = model_matrix.inverse();
+    //the direction isn't normalized, so distances stay in world units
+    let origin = inverse.transform_point3(ray.origin);
+    let direction = inverse.transform_vector3(ray.direction);
+    let to_min = (Vec3::from(aabb.min()) - origin) / direction;
+    let to_max = (Vec3::from(aabb.max()) - origin) / direction;
+    let enter = to_min.min(to_max).max_element();
+    let exit = to_min.max(to_max).min_element();
+    if enter > exit || exit < 0.0 {
+        return None;
+    }
+    Some(enter.max(0.0))
+}
+fn ray_sphere_distance(ray: Ray, center: Vec3, radius: f32) -> Option<f32> {
+    let along = (center - ray.origin).dot(ray.direction);
+    if along < 0.0 || ray.get_point(along).distance(center) >= radius {
+        return None;
+    }
+    Some(along)
+}
+
+//Click to select a detail, drag to box select, Shift adds to the selection.
+//Dragging a handle of the gizmo moves, rotates or scales the selection.
+#[allow(clippy::too_many_arguments)]
+fn select_details(
+    mut selection: ResMut<DetailSelection>,
+    edit_info: Res<EditInfo>,
+    mut master_terrain: ResMut<MasterTerrain>,
+    mut details: Query<(Entity, &mut DetailModel, &mut Transform)>,
+    camera: Query<(&Camera, &GlobalTransform)>,
+    windows: Query<&Window>,
+    mouse: Res<Input<MouseButton>>,
+    keys: Res<Input<KeyCode>>,
+    ui_hovered: Res<UiHovered>,
+    batches: Res<DetailBatches>,
+    model_library: Res<ModelLibrary>,
+) {
+    if !master_terrain.loaded {
+        return;
+    }
+    let selection = &mut *selection;
+    selection
+        .selected
+        .retain(|entity| details.contains(*entity));
+    if edit_info.edit_mode != EditMode::SelectDetails {
+        selection.drag = None;
+        selection.box_start = None;
+        return;
+    }
+
+    if selection.properties_changed {
+        selection.properties_changed = false;
+        if let (Some(properties), [entity]) = (&selection.properties, selection.selected.as_slice())
+        {
+            if let Ok((entity, mut detail_model, mut transform)) = details.get_mut(*entity) {
+                move_detail(
+                    &mut master_terrain,
+                    entity,
+                    &mut detail_model,
+                    &mut transform,
+                    properties.pos,
+                );
+                let yaw = transform.rotation.to_euler(EulerRot::YXZ).0;
+                transform.rotation =
+                    Quat::from_rotation_y(properties.yaw.to_radians() - yaw) * transform.rotation;
+                transform.scale = Vec3::splat(properties.scale);
+                detail_model.rotation = transform.rotation;
+                detail_model.scale = transform.scale;
+            }
+        }
+    }
+
+    let cursor = match windows.single().cursor_position() {
+        Some(cursor) => cursor,
+        None => return,
+    };
+    let (camera, camera_tf) = camera.single();
+    let ray = match camera.viewport_to_world(camera_tf, cursor) {
+        Some(ray) => ray,
+        None => return,
+    };
+
+    if mouse.just_pressed(MouseButton::Left) && !ui_hovered.0 {
+        let center = selection_center(
+            selection
+                .selected
+                .iter()
+                .filter_map(|entity| details.get(*entity).ok())
+                .map(|(_, _, transform)| transform.translation),
+        );
+        let handle = center.and_then(|center| {
+            pick_handle(camera, camera_tf, ray, cursor, center, selection.gizmo_mode)
+        });
+        match (handle, center) {
+            (Some(handle), Some(center)) => {
+                let start = selection
+                    .selected
+                    .iter()
+                    .filter_map(|entity| details.get(*entity).ok())
+                    .map(|(entity, detail_model, transform)| {
+                        (
+                            entity,
+                            detail_model.pos,
+                            transform.rotation,
+                            transform.scale,
+                        )
+                    })
+                    .collect();
+                selection.drag = Some(GizmoDrag {
+                    handle,
+                    center,
+                    start_cursor: cursor,
+                    start_hit: plane_hit(ray, center).unwrap_or(center),
+                    start,
+                });
+            }
+            _ => selection.box_start = Some(cursor),
+        }
+    }
+
+    if let (Some(drag), true) = (&selection.drag, mouse.pressed(MouseButton::Left)) {
+        let hit = plane_hit(ray, drag.center).unwrap_or(drag.start_hit);
+        let center = Vec2::new(drag.center.x, drag.center.z);
+        for (entity, start_pos, start_rotation, start_scale) in &drag.start {
+            let (entity, mut detail_model, mut transform) = match details.get_mut(*entity) {
+                Ok(detail) => detail,
+                Err(_) => continue,
+            };
+            match drag.handle {
+                GizmoHandle::Free | GizmoHandle::AxisX | GizmoHandle::AxisZ => {
+                    let delta = hit - drag.start_hit;
+                    let delta = match drag.handle {
+                        GizmoHandle::AxisX => Vec2::new(delta.x, 0.0),
+                        GizmoHandle::AxisZ => Vec2::new(0.0, delta.z),
+                        _ => Vec2::new(delta.x, delta.z),
+                    };
+                    move_detail(
+                        &mut master_terrain,
+                        entity,
+                        &mut detail_model,
+                        &mut transform,
+                        *start_pos + delta,
+                    );
+                }
+                GizmoHandle::Ring => {
+                    let angle = |pos: Vec3| (pos.z - drag.center.z).atan2(pos.x - drag.center.x);
+                    let rotation = Quat::from_rotation_y(angle(drag.start_hit) - angle(hit));
+                    let offset =
+                        rotation * Vec3::new(start_pos.x - center.x, 0.0, start_pos.y - center.y);
+                    move_detail(
+                        &mut master_terrain,
+                        entity,
+                        &mut detail_model,
+                        &mut transform,
+                        center + Vec2::new(offset.x, offset.z),
+                    );
+                    transform.rotation = rotation * *start_rotation;
+                    detail_model.rotation = transform.rotation;
+                }
+                GizmoHandle::Scale => {
+                    let factor = (1.0 + (drag.start_cursor.y - cursor.y) / SCALE_DRAG_PIXELS)
+                        .max(MIN_SCALE_FACTOR);
+                    transform.scale = *start_scale * factor;
+                    detail_model.scale = transform.scale;
+                }
+            }
+        }
+    }
+
+    if mouse.just_released(MouseButton::Left) {
+        selection.drag = None;
+        if let Some(box_start) = selection.box_start.take() {
+            if !keys.pressed(KeyCode::ShiftLeft) {
+                selection.selected.clear();
+            }
+            let picked: Vec<Entity> = if box_start.distance(cursor) < BOX_MIN_PIXELS {
+                pick_detail(ray, details.iter(), &batches, &model_library)
+                    .into_iter()
+                    .collect()
+            } else {
+                let rect = Rect::from_corners(box_start, cursor);
+                details
+                    .iter()
+                    .filter(|(_, _, transform)| {
+                        camera
+                            .world_to_viewport(camera_tf, transform.translation)
+                            .map_or(false, |screen_pos| rect.contains(screen_pos))
+                    })
+                    .map(|(entity, _, _)| entity)
+                    .collect()
+            };
+            for entity in picked {
+                if !selection.selected.contains(&entity) {
+                    selection.selected.push(entity);
+                }
+            }
+        }
+    }
+
+    selection.properties = match selection.selected.as_slice() {
+        [entity] => details
+            .get(*entity)
+            .ok()
+            .map(|(_, detail_model, transform)| DetailProperties {
+                name: detail_model.name.clone(),
+                pos: detail_model.pos,
+                yaw: transform.rotation.to_euler(EulerRot::YXZ).0.to_degrees(),
+                scale: transform.scale.x,
+            }),
+        _ => None,
+    };
+}
+
+fn selection_actions(
+    mut commands: Commands,
+    mut selection: ResMut<DetailSelection>,
+    mut master_terrain: ResMut<MasterTerrain>,
+    edit_info: Res<EditInfo>,
+    details: Query<&DetailModel>,
+    keys: Res<Input<KeyCode>>,
+    mut contexts: EguiContexts,
+) {
+    if edit_info.edit_mode == EditMode::SelectDetails
+        && keys.just_pressed(KeyCode::Delete)
+        && !contexts.ctx_mut().wants_keyboard_input()
+    {
+        selection.action = Some(SelectionAction::Delete);
+    }
+    let action = if let Some(action) = selection.action.take() {
+        action
+    } else {
+        return;
+    };
+    if !master_terrain.loaded {
+        return;
+    }
+    match action {
+        SelectionAction::Delete => {
+            for entity in selection.selected.drain(..) {
+                if let Ok(detail_model) = details.get(entity) {
+                    master_terrain.details.remove(entity, detail_model.pos);
+                    commands.entity(entity).despawn_recursive();
+                }
+            }
+        }
+        SelectionAction::Duplicate => {
+            //the copies are selected so they can be moved away right away
+            let mut copies = Vec::new();
+            for entity in &selection.selected {
+                let detail_model = match details.get(*entity) {
+                    Ok(detail_model) => detail_model,
+                    Err(_) => continue,
+                };
+                let pos = detail_model.pos + DUPLICATE_OFFSET;
+                let chunk_pos = master_terrain.chunk_pos_from_vec2(&pos);
+                if !master_terrain.does_chunk_exist(&chunk_pos) {
+                    continue;
+                }
+                let id = spawn_detail(
+                    &mut commands,
+                    &master_terrain,
+                    DetailModel {
+                        name: detail_model.name.clone(),
+                        chunk_pos,
+                        pos,
+                        rotation: detail_model.rotation,
+                        scale: detail_model.scale,
+                        scattered: false,
+                    },
+                );
+                master_terrain.details.insert(id, pos);
+                copies.push(id);
+            }
+            selection.selected = copies;
+        }
+    }
+}
+
+fn draw_selection(
+    selection: Res<DetailSelection>,
+    edit_info: Res<EditInfo>,
+    details: Query<&Transform, With<DetailModel>>,
+    camera: Query<&GlobalTransform, With<Camera>>,
+    windows: Query<&Window>,
+    mut gizmos: Gizmos,
+    mut contexts: EguiContexts,
+) {
+    if edit_info.edit_mode != EditMode::SelectDetails {
+        return;
+    }
+    for entity in &selection.selected {
+        if let Ok(transform) = details.get(*entity) {
+            gizmos.circle(
+                transform.translation + Vec3::Y * RING_OFFSET,
+                Vec3::Y,
+                SELECTION_RING_RADIUS,
+                Color::YELLOW,
+            );
+        }
+    }
+    let center = selection_center(
+        selection
+            .selected
+            .iter()
+            .filter_map(|entity| details.get(*entity).ok())
+            .map(|transform| transform.translation),
+    );
+    if let Some(center) = center {
+        let size = gizmo_size(camera.single(), center);
+        match selection.gizmo_mode {
+            GizmoMode::Move => {
+                gizmos.line(center, center + Vec3::X * size, Color::RED);
+                gizmos.line(center, center + Vec3::Z * size, Color::BLUE);
+                gizmos.sphere(
+                    center,
+                    Quat::IDENTITY,
+                    size * GIZMO_CENTER_RADIUS,
+                    Color::WHITE,
+                );
+            }
+            GizmoMode::Rotate => {
+                gizmos.circle(center, Vec3::Y, size, Color::LIME_GREEN);
+            }
+            GizmoMode::Scale => {
+                let end = center + Vec3::Y * size;
+                gizmos.line(center, end, Color::YELLOW);
+                gizmos.sphere(
+                    end,
+                    Quat::IDENTITY,
+                    size * GIZMO_CENTER_RADIUS,
+                    Color::YELLOW,
+                );
+            }
+        }
+    }
+    if let (Some(box_start), Some(cursor)) =
+        (selection.box_start, windows.single().cursor_position())
+    {
+        if box_start.distance(cursor) >= BOX_MIN_PIXELS {
+            let painter = contexts.ctx_mut().layer_painter(egui::LayerId::new(
+                egui::Order::Foreground,
+                egui::Id::new("detail_selection_box"),
+            ));
+            painter.rect_stroke(
+                egui::Rect::from_two_pos(
+                    egui::pos2(box_start.x, box_start.y),
+                    egui::pos2(cursor.x, cursor.y),
+                ),
+                0.0,
+                egui::Stroke::new(1.0, egui::Color32::WHITE),
+            );
+        }
+    }
+}
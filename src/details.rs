@@ -1,11 +1,19 @@
+use std::{
+    collections::{HashMap, HashSet},
+    f32::consts::TAU,
+};
+
 use bevy::prelude::*;
 use bevy_mod_raycast::deferred::RaycastSource;
 use rand::Rng;
 
 use crate::{
+    brush::stroke_stamps,
+    mask::TerrainMask,
+    model_library::ModelLibrary,
     resize_vector,
     terrain::MasterTerrain,
-    ui::{EditInfo, EditMode, UiHovered},
+    ui::{DetailPlacement, DetailVariation, EditInfo, EditMode, UiHovered},
 };
 pub struct DetailsPlugin;
 impl Plugin for DetailsPlugin {
@@ -20,9 +28,11 @@ fn spawn(
     mut master_terrain: ResMut<MasterTerrain>,
     mouse: Res<Input<MouseButton>>,
     keys: Res<Input<KeyCode>>,
+    time: Res<Time>,
     ui_hovered: Res<UiHovered>,
     mut commands: Commands,
-    asset_server: Res<AssetServer>,
+    mask: Res<TerrainMask>,
+    model_library: Res<ModelLibrary>,
 ) {
     if !master_terrain.loaded {
         return;
@@ -30,9 +40,13 @@ fn spawn(
     if let EditMode::EditDetails = &edit_info.edit_mode {
         let raycast_source = raycast_source.single();
         for (_, intersection) in raycast_source.intersections() {
-            if mouse.just_pressed(MouseButton::Left) && !ui_hovered.0 {
+            if mouse.pressed(MouseButton::Left) && !ui_hovered.0 {
+                let new_stroke = mouse.just_pressed(MouseButton::Left);
+                //without spacing, details are only placed once per click
+                if !new_stroke && edit_info.details_info.brush_info.stroke.spacing <= 0.0 {
+                    return;
+                }
                 let pos = intersection.position();
-                let world_pos = master_terrain.vec2_to_world_pos(Vec2::new(pos.x, pos.z));
                 if edit_info.details_info.brush_info.selected_brush.is_none() {
                     return;
                 }
@@ -41,7 +55,7 @@ fn spawn(
                 } else {
                     edit_info.details_info.selected_detail_name.clone()
                 };
-                let size = edit_info.sculpt_info.brush_info.size;
+                let size = edit_info.details_info.brush_info.size;
                 if edit_info
                     .details_info
                     .brush_info
@@ -61,67 +75,95 @@ fn spawn(
                         resize_vector(&brush.map, brush.map_size as usize, size as usize);
                     brush.sample_map_size = size;
                 }
-                let sample_map = &edit_info
+                let stamps = stroke_stamps(
+                    &mut edit_info.details_info.brush_info,
+                    Vec2::new(pos.x, pos.z),
+                    new_stroke,
+                    time.delta_seconds(),
+                );
+                let stamps = edit_info.symmetry.apply(stamps);
+                let brush = edit_info
                     .details_info
                     .brush_info
                     .selected_brush
                     .as_ref()
-                    .unwrap()
-                    .sample_map;
-                let strength = edit_info.details_info.brush_info.strength;
+                    .unwrap();
 
                 let mut rng = rand::thread_rng();
-                for x in 0..size {
-                    for y in 0..size {
-                        let x_f32 = x as f32 - size as f32 * 0.5;
-                        let y_f32 = y as f32 - size as f32 * 0.5;
-                        let world_pos =
-                            world_pos + master_terrain.vec2_to_world_pos(Vec2::new(x_f32, y_f32));
-                        let chunk_pos = master_terrain.world_to_chunk_pos(world_pos);
-                        if !master_terrain.does_chunk_exist(&chunk_pos) {
-                            continue;
-                        }
-                        let local_pos = master_terrain.world_to_local_pos(world_pos);
-
-                        let translation = Vec3::new(world_pos.x as f32, 0.0, world_pos.y as f32);
-
-                        let brush_sample = sample_map[(x + y * size) as usize];
-                        let chance = brush_sample * strength * 0.1;
-                        let random_number: f32 = rng.gen();
-
-                        if master_terrain.details.contains_key(&world_pos) {
-                            if keys.pressed(KeyCode::ControlLeft) {
-                                if random_number < chance * 10.0 {
-                                    commands
-                                        .entity(master_terrain.details[&world_pos])
-                                        .despawn_recursive();
-                                    master_terrain.details.remove(&world_pos);
-                                }
+                let placement = &edit_info.details_info.placement;
+                let metadata = model_library.get(&model_name);
+                let min_spacing = placement.min_spacing.max(metadata.collision_radius);
+                for stamp in stamps {
+                    let world_pos = master_terrain.vec2_to_world_pos(stamp.pos);
+                    let size = stamp.size;
+                    let strength = stamp.strength;
+                    if keys.pressed(KeyCode::ControlLeft) {
+                        let radius = size as f32 * 0.5;
+                        for (entity, pos) in master_terrain.details.in_radius(stamp.pos, radius) {
+                            //the brush cell the detail stands in
+                            let cell = master_terrain.vec2_to_world_pos(pos) - world_pos;
+                            let x = (cell.x as f32 + radius).floor();
+                            let y = (cell.y as f32 + radius).floor();
+                            if x < 0.0 || y < 0.0 || x >= size as f32 || y >= size as f32 {
+                                continue;
+                            }
+                            let brush_sample = stamp.sample(brush, x as u32, y as u32);
+                            let chance = brush_sample
+                                * strength
+                                * mask
+                                    .weight(&master_terrain, master_terrain.vec2_to_world_pos(pos));
+                            let random_number: f32 = rng.gen();
+                            if random_number < chance {
+                                commands.entity(entity).despawn_recursive();
+                                master_terrain.details.remove(entity, pos);
                             }
-                            continue;
-                        }
-                        if keys.pressed(KeyCode::ControlLeft) {
-                            continue;
                         }
+                        continue;
+                    }
+                    for x in 0..size {
+                        for y in 0..size {
+                            let x_f32 = x as f32 - size as f32 * 0.5;
+                            let y_f32 = y as f32 - size as f32 * 0.5;
+                            let world_pos = world_pos
+                                + master_terrain.vec2_to_world_pos(Vec2::new(x_f32, y_f32));
+                            let chunk_pos = master_terrain.world_to_chunk_pos(world_pos);
+                            if !master_terrain.does_chunk_exist(&chunk_pos) {
+                                continue;
+                            }
 
-                        if random_number < chance {
-                            let id = commands
-                                .spawn((
-                                    SceneBundle {
-                                        scene: asset_server
-                                            .load(format!("models/{}#Scene0", model_name)),
-                                        transform: Transform::from_translation(translation)
-                                            .with_scale(Vec3::ONE * 0.05),
-                                        ..Default::default()
-                                    },
-                                    DetailModel {
-                                        name: model_name.clone(),
-                                        chunk_pos,
-                                        local_pos,
-                                    },
-                                ))
-                                .id();
-                            master_terrain.details.insert(world_pos, id);
+                            let brush_sample = stamp.sample(brush, x, y);
+                            let chance = brush_sample
+                                * strength
+                                * mask.weight(&master_terrain, world_pos)
+                                * 0.1;
+                            let random_number: f32 = rng.gen();
+                            if random_number >= chance {
+                                continue;
+                            }
+                            let (offset, rotation, scale) = random_placement(
+                                &master_terrain,
+                                placement,
+                                metadata.default_scale,
+                                world_pos,
+                                &mut rng,
+                            );
+                            let pos = world_pos.as_vec2() + offset;
+                            if master_terrain.details.any_in_radius(pos, min_spacing) {
+                                continue;
+                            }
+                            let id = spawn_detail(
+                                &mut commands,
+                                &master_terrain,
+                                DetailModel {
+                                    name: model_name.clone(),
+                                    chunk_pos: master_terrain.chunk_pos_from_vec2(&pos),
+                                    pos,
+                                    rotation,
+                                    scale,
+                                    scattered: false,
+                                },
+                            );
+                            master_terrain.details.insert(id, pos);
                         }
                     }
                 }
@@ -130,20 +172,228 @@ fn spawn(
     }
 }
 
-#[derive(Component)]
+#[derive(Component, Clone)]
 pub struct DetailModel {
     pub name: String,
+    //chunk the detail is batched with
     pub chunk_pos: IVec2,
-    pub local_pos: UVec2,
+    pub pos: Vec2,
+    pub rotation: Quat,
+    pub scale: Vec3,
+
+    //placed by a scatter rule, running the rule again replaces it
+    pub scattered: bool,
 }
 
+//bucket size of the detail index in world units
+const DETAIL_BUCKET_SIZE: f32 = 4.0;
+
+//Spatial hash of all details by their position. Any number of details can stand anywhere,
+//radius queries only look at the buckets they reach.
+#[derive(Default)]
+pub struct DetailIndex {
+    buckets: HashMap<IVec2, Vec<(Entity, Vec2)>>,
+}
+impl DetailIndex {
+    fn bucket(pos: Vec2) -> IVec2 {
+        (pos / DETAIL_BUCKET_SIZE).floor().as_ivec2()
+    }
+    pub fn insert(&mut self, entity: Entity, pos: Vec2) {
+        self.buckets
+            .entry(Self::bucket(pos))
+            .or_default()
+            .push((entity, pos));
+    }
+    //pos has to be the position the detail was inserted or last moved with
+    pub fn remove(&mut self, entity: Entity, pos: Vec2) {
+        let bucket = Self::bucket(pos);
+        if let Some(details) = self.buckets.get_mut(&bucket) {
+            details.retain(|(other, _)| *other != entity);
+            if details.is_empty() {
+                self.buckets.remove(&bucket);
+            }
+        }
+    }
+    pub fn move_detail(&mut self, entity: Entity, from: Vec2, to: Vec2) {
+        self.remove(entity, from);
+        self.insert(entity, to);
+    }
+    pub fn in_radius(&self, center: Vec2, radius: f32) -> Vec<(Entity, Vec2)> {
+        let min = Self::bucket(center - radius);
+        let max = Self::bucket(center + radius);
+        let mut found = Vec::new();
+        for x in min.x..=max.x {
+            for y in min.y..=max.y {
+                if let Some(details) = self.buckets.get(&IVec2::new(x, y)) {
+                    found.extend(
+                        details
+                            .iter()
+                            .filter(|(_, pos)| pos.distance(center) <= radius),
+                    );
+                }
+            }
+        }
+        found
+    }
+    pub fn in_rect(&self, min: Vec2, max: Vec2) -> Vec<(Entity, Vec2)> {
+        let min_bucket = Self::bucket(min);
+        let max_bucket = Self::bucket(max);
+        let mut found = Vec::new();
+        for x in min_bucket.x..=max_bucket.x {
+            for y in min_bucket.y..=max_bucket.y {
+                if let Some(details) = self.buckets.get(&IVec2::new(x, y)) {
+                    found.extend(details.iter().filter(|(_, pos)| {
+                        pos.x >= min.x && pos.y >= min.y && pos.x <= max.x && pos.y <= max.y
+                    }));
+                }
+            }
+        }
+        found
+    }
+    pub fn any_in_radius(&self, center: Vec2, radius: f32) -> bool {
+        if radius <= 0.0 {
+            return false;
+        }
+        !self.in_radius(center, radius).is_empty()
+    }
+    pub fn entities(&self) -> impl Iterator<Item = Entity> + '_ {
+        self.buckets
+            .values()
+            .flat_map(|details| details.iter().map(|(entity, _)| *entity))
+    }
+}
+
+//Moves a detail to pos and keeps the index and its chunk up to date. Details can't be
+//moved off the terrain, a move onto a chunk that doesn't exist is skipped.
+pub fn move_detail(
+    master_terrain: &mut MasterTerrain,
+    entity: Entity,
+    detail_model: &mut DetailModel,
+    transform: &mut Transform,
+    pos: Vec2,
+) {
+    if !master_terrain.does_chunk_exist(&master_terrain.chunk_pos_from_vec2(&pos)) {
+        return;
+    }
+    master_terrain
+        .details
+        .move_detail(entity, detail_model.pos, pos);
+    detail_model.pos = pos;
+    detail_model.chunk_pos = master_terrain.chunk_pos_from_vec2(&pos);
+    transform.translation = Vec3::new(pos.x, master_terrain.sample_height(pos), pos.y);
+}
+
+//Details don't have their own scene, they're drawn in batches by detail_instancing.
+pub fn spawn_detail(
+    commands: &mut Commands,
+    master_terrain: &MasterTerrain,
+    detail_model: DetailModel,
+) -> Entity {
+    let pos = detail_model.pos;
+    let translation = Vec3::new(pos.x, master_terrain.sample_height(pos), pos.y);
+    commands
+        .spawn((
+            Transform {
+                translation,
+                rotation: detail_model.rotation,
+                scale: detail_model.scale,
+            },
+            detail_model,
+        ))
+        .id()
+}
+
+//Random offset, rotation and scale for a new detail at world_pos.
+pub fn random_placement(
+    master_terrain: &MasterTerrain,
+    placement: &DetailPlacement,
+    model_scale: f32,
+    world_pos: IVec2,
+    rng: &mut impl Rng,
+) -> (Vec2, Quat, Vec3) {
+    let jitter = placement.position_jitter * 0.5;
+    let offset = if jitter > 0.0 {
+        Vec2::new(
+            rng.gen_range(-jitter..=jitter),
+            rng.gen_range(-jitter..=jitter),
+        )
+    } else {
+        Vec2::ZERO
+    };
+    let (rotation, scale) = random_transform(
+        master_terrain,
+        &placement.variation,
+        model_scale,
+        world_pos.as_vec2() + offset,
+        rng,
+    );
+    (offset, rotation, scale)
+}
+//Random rotation and scale for a new detail at pos, the scale range is relative to model_scale.
+pub fn random_transform(
+    master_terrain: &MasterTerrain,
+    variation: &DetailVariation,
+    model_scale: f32,
+    pos: Vec2,
+    rng: &mut impl Rng,
+) -> (Quat, Vec3) {
+    let yaw = if variation.random_yaw {
+        rng.gen_range(0.0..TAU)
+    } else {
+        0.0
+    };
+    let mut rotation = Quat::from_rotation_y(yaw);
+    if variation.align_to_normal {
+        let normal = master_terrain.sample_normal(pos);
+        let (axis, angle) = Quat::from_rotation_arc(Vec3::Y, normal).to_axis_angle();
+        rotation =
+            Quat::from_axis_angle(axis, angle.min(variation.max_tilt.to_radians())) * rotation;
+    }
+    let (min_scale, max_scale) = (
+        variation.min_scale.min(variation.max_scale),
+        variation.min_scale.max(variation.max_scale),
+    );
+    let scale = rng.gen_range(min_scale..=max_scale);
+    (rotation, Vec3::splat(scale * model_scale))
+}
+
+//Details follow the terrain, so the ones in chunks whose meshes were built again are
+//moved to the new heights.
 fn update_models(
     master_terrain: Res<MasterTerrain>,
-    mut models: Query<(&mut Transform, &DetailModel)>,
+    mut mesh_events: EventReader<AssetEvent<Mesh>>,
+    chunk_meshes: Query<&Handle<Mesh>>,
+    mut models: Query<&mut Transform, With<DetailModel>>,
 ) {
-    for (mut model_tf, detail_model) in &mut models {
-        let height =
-            master_terrain.get_local_height(detail_model.local_pos, detail_model.chunk_pos);
-        model_tf.translation.y = height;
+    let mut modified_meshes = HashSet::new();
+    for event in mesh_events.read() {
+        if let AssetEvent::Modified { id } = event {
+            modified_meshes.insert(*id);
+        }
+    }
+    if modified_meshes.is_empty() || !master_terrain.loaded {
+        return;
+    }
+
+    let chunk_size = master_terrain.chunk_size as f32;
+    for (chunk_pos, (_, mesh_entities)) in &master_terrain.chunks {
+        let mesh_changed = mesh_entities.values().any(|entity| {
+            chunk_meshes
+                .get(*entity)
+                .map_or(false, |handle| modified_meshes.contains(&handle.id()))
+        });
+        if !mesh_changed {
+            continue;
+        }
+        let min = chunk_pos.as_vec2() * chunk_size;
+        for (entity, pos) in master_terrain.details.in_rect(min, min + chunk_size) {
+            if let Ok(mut model_tf) = models.get_mut(entity) {
+                let height = master_terrain.sample_height(pos);
+                //only write changes, the instance batches are rebuilt for every changed transform
+                if model_tf.translation.y != height {
+                    model_tf.translation.y = height;
+                }
+            }
+        }
     }
 }
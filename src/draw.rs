@@ -7,9 +7,11 @@ use bevy::prelude::*;
 use bevy_mod_raycast::deferred::RaycastSource;
 
 use crate::{
+    brush::stroke_stamps,
+    mask::TerrainMask,
     resize_vector,
     terrain::MasterTerrain,
-    ui::{EditInfo, EditMode, UiHovered},
+    ui::{DrawType, EditInfo, EditMode, UiHovered},
 };
 
 pub struct DrawPlugin;
@@ -24,9 +26,12 @@ fn draw(
     raycast_source: Query<&RaycastSource<()>>,
     master_terrain: Res<MasterTerrain>,
     mouse: Res<Input<MouseButton>>,
+    keys: Res<Input<KeyCode>>,
     time: Res<Time>,
     ui_hovered: Res<UiHovered>,
     mut images: ResMut<Assets<Image>>,
+    mask: Res<TerrainMask>,
+    mut stroke_colors: Local<HashMap<IVec2, Color>>,
 ) {
     if !master_terrain.loaded {
         return;
@@ -41,14 +46,34 @@ fn draw(
                 if edit_info.draw_info.brush_info.selected_brush.is_none() {
                     return;
                 }
-                if edit_info
-                    .draw_info
-                    .draw_texture_info
-                    .selected_texture
-                    .is_none()
+                if edit_info.draw_info.eyedropper || keys.pressed(KeyCode::AltLeft) {
+                    if mouse.just_pressed(MouseButton::Left) {
+                        if let Some(color) = read_pixel(&master_terrain, &images, pixel_pos) {
+                            edit_info.draw_info.paint_color = color;
+                        }
+                        edit_info.draw_info.eyedropper = false;
+                    }
+                    return;
+                }
+                let draw_type = edit_info.draw_info.draw_type.clone();
+                if draw_type == DrawType::Paint
+                    && edit_info
+                        .draw_info
+                        .draw_texture_info
+                        .selected_texture
+                        .is_none()
                 {
                     return;
                 }
+                if mouse.just_pressed(MouseButton::Left) {
+                    edit_info.draw_info.last_pixel_pos = None;
+                    stroke_colors.clear();
+                }
+                let stroke_delta = match edit_info.draw_info.last_pixel_pos {
+                    Some(last_pixel_pos) => pixel_pos - last_pixel_pos,
+                    None => IVec2::ZERO,
+                };
+                edit_info.draw_info.last_pixel_pos = Some(pixel_pos);
                 let scale = edit_info.draw_info.draw_texture_info.scale;
                 let size = edit_info.draw_info.brush_info.size;
                 if edit_info
@@ -70,130 +95,218 @@ fn draw(
                         resize_vector(&brush.map, brush.map_size as usize, size as usize);
                     brush.sample_map_size = size;
                 }
-                if edit_info
+                if let Some(draw_texture) = edit_info
                     .draw_info
                     .draw_texture_info
                     .selected_texture
-                    .as_ref()
-                    .unwrap()
-                    .sample_map_size
-                    != scale
+                    .as_mut()
                 {
-                    let draw_texture = edit_info
-                        .draw_info
-                        .draw_texture_info
-                        .selected_texture
-                        .as_mut()
-                        .unwrap();
-                    draw_texture.sample_map = resize_vector(
-                        &draw_texture.map,
-                        draw_texture.map_size as usize,
-                        scale as usize,
-                    );
-                    draw_texture.sample_map_size = scale;
+                    if draw_texture.sample_map_size != scale {
+                        draw_texture.sample_map = resize_vector(
+                            &draw_texture.map,
+                            draw_texture.map_size as usize,
+                            scale as usize,
+                        );
+                        draw_texture.sample_map_size = scale;
+                    }
                 }
-                let strength_sample_map = &edit_info
+                let stamps = stroke_stamps(
+                    &mut edit_info.draw_info.brush_info,
+                    Vec2::new(intersection_pos.x, intersection_pos.z),
+                    mouse.just_pressed(MouseButton::Left),
+                    time.delta_seconds(),
+                );
+                let stamps = edit_info.symmetry.apply(stamps);
+                let brush = edit_info
                     .draw_info
                     .brush_info
                     .selected_brush
                     .as_ref()
-                    .unwrap()
-                    .sample_map;
-                let texture_sample_map = &edit_info
+                    .unwrap();
+                let texture_sample_map = edit_info
                     .draw_info
                     .draw_texture_info
                     .selected_texture
                     .as_ref()
-                    .unwrap()
-                    .sample_map;
-                let strength = edit_info.draw_info.brush_info.strength;
+                    .map(|texture| &texture.sample_map);
+                let paint_color = edit_info.draw_info.paint_color;
+                let tint_texture = edit_info.draw_info.tint_texture;
+                let erase_color = edit_info.draw_info.erase_color;
+                let blur_radius = edit_info.draw_info.blur_radius as i32;
+                let (hue_shift, saturation, brightness) = (
+                    edit_info.draw_info.hue_shift,
+                    edit_info.draw_info.saturation,
+                    edit_info.draw_info.brightness,
+                );
+                let triplanar = edit_info.draw_info.draw_texture_info.triplanar;
+                let triplanar_angle = edit_info.draw_info.draw_texture_info.triplanar_angle;
                 let p_per_tile = master_terrain.pixels_per_tile();
-                let p_size = size * p_per_tile as u32;
-
-                let mut image_map: HashMap<IVec2, Option<Vec<(Color, usize)>>> = HashMap::new();
-
-                for p_x in 0..p_size {
-                    for p_y in 0..p_size {
-                        let p_x_f32 = p_x as f32 - p_size as f32 * 0.5;
-                        let p_y_f32 = p_y as f32 - p_size as f32 * 0.5;
-                        let x_f32 = p_x_f32 / p_per_tile as f32;
-                        let y_f32 = p_y_f32 / p_per_tile as f32;
-                        let (x, y) = (p_x / p_per_tile as u32, p_y / p_per_tile as u32);
-                        let pixel_pos =
-                            pixel_pos + master_terrain.vec2_to_pixel_pos(Vec2::new(x_f32, y_f32));
-                        let chunk_pos = master_terrain.pixel_to_chunk_pos(pixel_pos);
-                        if let Some(img) = image_map.get(&chunk_pos) {
-                            if img.is_none() {
-                                continue;
-                            }
-                        } else {
-                            let textures = &master_terrain.texture_map.textures;
-                            let handle = if let Some(handle) = textures.get(&chunk_pos) {
-                                handle
+                for stamp in stamps {
+                    let pixel_pos = master_terrain.vec2_to_pixel_pos(stamp.pos);
+                    let size = stamp.size;
+                    let strength = stamp.strength;
+                    let p_size = size * p_per_tile as u32;
+                    //symmetric copies smudge in their mirrored direction
+                    let smudge_delta = (stamp.mirror * stroke_delta.as_vec2()).round().as_ivec2();
+
+                    let mut image_map: HashMap<IVec2, Option<Vec<(Color, usize)>>> = HashMap::new();
+                    let blur = (draw_type == DrawType::Blur).then(|| {
+                        let reach = IVec2::splat(p_size as i32 / 2 + 1 + blur_radius);
+                        BoxBlur::new(
+                            &master_terrain,
+                            &images,
+                            pixel_pos - reach,
+                            pixel_pos + reach,
+                        )
+                    });
+
+                    for p_x in 0..p_size {
+                        for p_y in 0..p_size {
+                            let p_x_f32 = p_x as f32 - p_size as f32 * 0.5;
+                            let p_y_f32 = p_y as f32 - p_size as f32 * 0.5;
+                            let x_f32 = p_x_f32 / p_per_tile as f32;
+                            let y_f32 = p_y_f32 / p_per_tile as f32;
+                            let (x, y) = (p_x / p_per_tile as u32, p_y / p_per_tile as u32);
+                            let pixel_pos = pixel_pos
+                                + master_terrain.vec2_to_pixel_pos(Vec2::new(x_f32, y_f32));
+                            let chunk_pos = master_terrain.pixel_to_chunk_pos(pixel_pos);
+                            if let Some(img) = image_map.get(&chunk_pos) {
+                                if img.is_none() {
+                                    continue;
+                                }
                             } else {
-                                image_map.insert(chunk_pos, None);
-                                continue;
-                            };
-                            if images.get_mut(handle).is_none() {
-                                image_map.insert(chunk_pos, None);
-                                continue;
+                                let textures = &master_terrain.texture_map.textures;
+                                let handle = if let Some(handle) = textures.get(&chunk_pos) {
+                                    handle
+                                } else {
+                                    image_map.insert(chunk_pos, None);
+                                    continue;
+                                };
+                                if images.get_mut(handle).is_none() {
+                                    image_map.insert(chunk_pos, None);
+                                    continue;
+                                }
+                                image_map.insert(chunk_pos, Some(Vec::new()));
                             }
-                            image_map.insert(chunk_pos, Some(Vec::new()));
-                        }
 
-                        let texture_sample = sample_repeating(
-                            pixel_pos.x as i32,
-                            pixel_pos.y as i32,
-                            texture_sample_map,
-                            scale as usize,
-                        );
-                        let strength_sample = strength_sample_map[(x + y * size) as usize];
-                        let wanted_color = texture_sample
-                            .with_a(strength_sample * strength * time.delta_seconds() * 100.0);
-
-                        let local_pixel_pos = master_terrain
-                            .pixel_to_local_pixel_pos_with_chunk(pixel_pos, chunk_pos);
-                        let pixel_index = (local_pixel_pos.x * 4
-                            + local_pixel_pos.y * master_terrain.texture_size as u32 * 4)
-                            as usize;
-
-                        image_map
-                            .get_mut(&chunk_pos)
-                            .unwrap()
-                            .as_mut()
-                            .unwrap()
-                            .push((wanted_color, pixel_index));
-                        //master_terrain.draw(&mut image_map, pixel_pos, wanted_color);
+                            let color = match draw_type {
+                                DrawType::Paint => {
+                                    let texture_sample_map = texture_sample_map.unwrap();
+                                    let texture_sample = if triplanar {
+                                        sample_triplanar(
+                                            &master_terrain,
+                                            pixel_pos,
+                                            texture_sample_map,
+                                            scale as usize,
+                                            triplanar_angle,
+                                        )
+                                    } else {
+                                        sample_repeating(
+                                            pixel_pos.x as i32,
+                                            pixel_pos.y as i32,
+                                            texture_sample_map,
+                                            scale as usize,
+                                        )
+                                    };
+                                    if tint_texture {
+                                        tint_color(texture_sample, paint_color)
+                                    } else {
+                                        texture_sample
+                                    }
+                                }
+                                DrawType::SolidColor => paint_color,
+                                DrawType::Erase => erase_color,
+                                DrawType::Blur => {
+                                    match blur.as_ref().unwrap().average(pixel_pos, blur_radius) {
+                                        Some(color) => color,
+                                        None => continue,
+                                    }
+                                }
+                                DrawType::Smudge => {
+                                    if smudge_delta == IVec2::ZERO {
+                                        continue;
+                                    }
+                                    match read_pixel(
+                                        &master_terrain,
+                                        &images,
+                                        pixel_pos - smudge_delta,
+                                    ) {
+                                        Some(color) => color,
+                                        None => continue,
+                                    }
+                                }
+                                //adjusts the colour the pixel had when the stroke started,
+                                //so holding the button doesn't keep shifting it
+                                DrawType::ColorAdjust => {
+                                    let color = match stroke_colors.get(&pixel_pos) {
+                                        Some(color) => *color,
+                                        None => {
+                                            match read_pixel(&master_terrain, &images, pixel_pos) {
+                                                Some(color) => {
+                                                    stroke_colors.insert(pixel_pos, color);
+                                                    color
+                                                }
+                                                None => continue,
+                                            }
+                                        }
+                                    };
+                                    adjust_color(color, hue_shift, saturation, brightness)
+                                }
+                            };
+                            let strength_sample = stamp.sample(brush, x, y)
+                                * mask.weight(
+                                    &master_terrain,
+                                    pixel_pos.div_euclid(IVec2::splat(p_per_tile as i32)),
+                                );
+                            let wanted_color = color
+                                .with_a(strength_sample * strength * stamp.delta_seconds * 100.0);
+
+                            let local_pixel_pos = master_terrain
+                                .pixel_to_local_pixel_pos_with_chunk(pixel_pos, chunk_pos);
+                            let pixel_index = (local_pixel_pos.x * 4
+                                + local_pixel_pos.y * master_terrain.texture_size as u32 * 4)
+                                as usize;
+
+                            image_map
+                                .get_mut(&chunk_pos)
+                                .unwrap()
+                                .as_mut()
+                                .unwrap()
+                                .push((wanted_color, pixel_index));
+                            //master_terrain.draw(&mut image_map, pixel_pos, wanted_color);
+                        }
                     }
-                }
 
-                for (chunk_pos, info) in image_map {
-                    let pixels = if let Some(pixels) = info {
-                        pixels
-                    } else {
-                        continue;
-                    };
-                    let handle =
-                        if let Some(handle) = master_terrain.texture_map.textures.get(&chunk_pos) {
+                    for (chunk_pos, info) in image_map {
+                        let pixels = if let Some(pixels) = info {
+                            pixels
+                        } else {
+                            continue;
+                        };
+                        let handle = if let Some(handle) =
+                            master_terrain.texture_map.textures.get(&chunk_pos)
+                        {
                             handle
                         } else {
                             return;
                         };
-                    let image = if let Some(image) = images.get_mut(handle) {
-                        image
-                    } else {
-                        return;
-                    };
-                    for (wanted_color, pixel_index) in pixels {
-                        let current_color = Color::rgb_u8(
-                            image.data[pixel_index + 0],
-                            image.data[pixel_index + 1],
-                            image.data[pixel_index + 2],
-                        );
-                        let calculated_color =
-                            lerp_color(&current_color, &wanted_color, wanted_color.a()).with_a(1.0);
-                        for (i, b) in calculated_color.as_rgba_u8().into_iter().enumerate() {
-                            image.data[pixel_index + i] = b;
+                        let image = if let Some(image) = images.get_mut(handle) {
+                            image
+                        } else {
+                            return;
+                        };
+                        for (wanted_color, pixel_index) in pixels {
+                            let current_color = Color::rgb_u8(
+                                image.data[pixel_index + 0],
+                                image.data[pixel_index + 1],
+                                image.data[pixel_index + 2],
+                            );
+                            let calculated_color =
+                                lerp_color(&current_color, &wanted_color, wanted_color.a())
+                                    .with_a(1.0);
+                            for (i, b) in calculated_color.as_rgba_u8().into_iter().enumerate() {
+                                image.data[pixel_index + i] = b;
+                            }
                         }
                     }
                 }
@@ -221,7 +334,7 @@ fn mod_neg(num: i32, mod_num: usize) -> usize {
         (mod_num as i32 + m) as usize
     }
 }
-fn sample_repeating(x: i32, y: i32, values: &Vec<Color>, size: usize) -> Color {
+pub fn sample_repeating(x: i32, y: i32, values: &Vec<Color>, size: usize) -> Color {
     let x = mod_neg(x, size);
     let y = mod_neg(y, size);
     assert!(x < size);
@@ -229,7 +342,142 @@ fn sample_repeating(x: i32, y: i32, values: &Vec<Color>, size: usize) -> Color {
     values[x + y * size]
 }
 
-fn lerp_color(col: &Color, other: &Color, mut t: f32) -> Color {
+pub fn read_pixel(
+    master_terrain: &MasterTerrain,
+    images: &Assets<Image>,
+    pixel_pos: IVec2,
+) -> Option<Color> {
+    let chunk_pos = master_terrain.pixel_to_chunk_pos(pixel_pos);
+    let image = images.get(master_terrain.texture_map.textures.get(&chunk_pos)?)?;
+    let local_pixel_pos = master_terrain.pixel_to_local_pixel_pos_with_chunk(pixel_pos, chunk_pos);
+    let pixel_index = (local_pixel_pos.x * 4
+        + local_pixel_pos.y * master_terrain.texture_size as u32 * 4)
+        as usize;
+    Some(Color::rgb_u8(
+        image.data[pixel_index + 0],
+        image.data[pixel_index + 1],
+        image.data[pixel_index + 2],
+    ))
+}
+//Box blur over a block of pixels read once per stamp. Keeps a summed area table so
+//every average is four lookups, whatever the radius. Reads through read_pixel, so it
+//works across chunk texture boundaries.
+struct BoxBlur {
+    min: IVec2,
+    size: IVec2,
+    //rgb and the number of pixels that exist, summed over all pixels above and left
+    sums: Vec<[f32; 4]>,
+}
+impl BoxBlur {
+    fn new(master_terrain: &MasterTerrain, images: &Assets<Image>, min: IVec2, max: IVec2) -> Self {
+        let size = max - min + 1;
+        let width = size.x as usize + 1;
+        let mut sums = vec![[0.0; 4]; width * (size.y as usize + 1)];
+        for y in 0..size.y as usize {
+            let mut row = [0.0; 4];
+            for x in 0..size.x as usize {
+                let pos = min + IVec2::new(x as i32, y as i32);
+                if let Some(color) = read_pixel(master_terrain, images, pos) {
+                    row[0] += color.r();
+                    row[1] += color.g();
+                    row[2] += color.b();
+                    row[3] += 1.0;
+                }
+                let above = sums[x + 1 + y * width];
+                sums[x + 1 + (y + 1) * width] = [
+                    above[0] + row[0],
+                    above[1] + row[1],
+                    above[2] + row[2],
+                    above[3] + row[3],
+                ];
+            }
+        }
+        Self { min, size, sums }
+    }
+    //Average of the square of pixels within radius of pixel_pos.
+    fn average(&self, pixel_pos: IVec2, radius: i32) -> Option<Color> {
+        let local = pixel_pos - self.min;
+        let start = (local - radius).max(IVec2::ZERO);
+        let end = (local + radius + 1).min(self.size);
+        if start.x >= end.x || start.y >= end.y {
+            return None;
+        }
+        let width = self.size.x + 1;
+        let sum = |pos: IVec2| self.sums[(pos.x + pos.y * width) as usize];
+        let (a, b, c, d) = (
+            sum(end),
+            sum(IVec2::new(start.x, end.y)),
+            sum(IVec2::new(end.x, start.y)),
+            sum(start),
+        );
+        let total: [f32; 4] = std::array::from_fn(|i| a[i] - b[i] - c[i] + d[i]);
+        if total[3] < 0.5 {
+            return None;
+        }
+        Some(Color::rgb(
+            total[0] / total[3],
+            total[1] / total[3],
+            total[2] / total[3],
+        ))
+    }
+}
+fn tint_color(color: Color, tint: Color) -> Color {
+    Color::rgba(
+        color.r() * tint.r(),
+        color.g() * tint.g(),
+        color.b() * tint.b(),
+        color.a(),
+    )
+}
+fn adjust_color(color: Color, hue_shift: f32, saturation: f32, brightness: f32) -> Color {
+    let [h, s, l, a] = color.as_hsla_f32();
+    Color::hsla(
+        (h + hue_shift).rem_euclid(360.0),
+        (s * saturation).clamp(0.0, 1.0),
+        (l * brightness).clamp(0.0, 1.0),
+        a,
+    )
+    .as_rgba()
+}
+
+//Blends the top-down projection with two side projections based on the
+//terrain normal, so steep slopes don't get stretched textures.
+fn sample_triplanar(
+    master_terrain: &MasterTerrain,
+    pixel_pos: IVec2,
+    values: &Vec<Color>,
+    size: usize,
+    angle_threshold: f32,
+) -> Color {
+    let top = sample_repeating(pixel_pos.x, pixel_pos.y, values, size);
+
+    let p_per_tile = master_terrain.pixels_per_tile() as f32;
+    let pos = pixel_pos.as_vec2() / p_per_tile;
+    let normal = master_terrain.sample_normal(pos);
+    let slope_angle = normal.y.clamp(-1.0, 1.0).acos().to_degrees();
+    //fade in over a few degrees instead of switching abruptly
+    let fade = ((slope_angle - angle_threshold) / TRIPLANAR_FADE_DEGREES).clamp(0.0, 1.0);
+    if fade <= 0.0 {
+        return top;
+    }
+
+    let height = -(master_terrain.sample_height(pos) * p_per_tile).round() as i32;
+    let side_x = sample_repeating(pixel_pos.y, height, values, size);
+    let side_z = sample_repeating(pixel_pos.x, height, values, size);
+
+    let weights = normal.abs().powf(4.0);
+    let weights = weights / (weights.x + weights.y + weights.z);
+    let triplanar = Color::rgba(
+        top.r() * weights.y + side_x.r() * weights.x + side_z.r() * weights.z,
+        top.g() * weights.y + side_x.g() * weights.x + side_z.g() * weights.z,
+        top.b() * weights.y + side_x.b() * weights.x + side_z.b() * weights.z,
+        top.a() * weights.y + side_x.a() * weights.x + side_z.a() * weights.z,
+    );
+    lerp_color(&top, &triplanar, fade)
+}
+const TRIPLANAR_FADE_DEGREES: f32 = 10.0;
+
+pub fn lerp_color(col: &Color, other: &Color, mut t: f32) -> Color {
     t = t.clamp(0.0, 1.0);
     Color::rgba(
         col.r() + (other.r() - col.r()) * t,
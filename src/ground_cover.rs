@@ -0,0 +1,468 @@
+use std::{
+    collections::{HashMap, HashSet},
+    f32::consts::TAU,
+};
+
+use bevy::{
+    prelude::*,
+    render::{
+        batching::NoAutomaticBatching, mesh::Indices, render_resource::PrimitiveTopology,
+        view::NoFrustumCulling,
+    },
+};
+use bevy_mod_raycast::deferred::RaycastSource;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    brush::stroke_stamps,
+    detail_instancing::{DetailInstances, DetailMaterial, InstanceData},
+    draw::read_pixel,
+    mask::TerrainMask,
+    resize_vector,
+    terrain::MasterTerrain,
+    ui::{EditInfo, EditMode, UiHovered},
+};
+
+pub struct GroundCoverPlugin;
+impl Plugin for GroundCoverPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(GroundCover::default()).add_systems(
+            Update,
+            (paint_ground_cover, mark_changed_tiles, update_grass_tiles).chain(),
+        );
+    }
+}
+
+//grass is generated in square tiles of this size around the camera
+const TILE_SIZE: f32 = 16.0;
+//so moving the camera quickly doesn't stall a frame
+const MAX_TILES_PER_FRAME: usize = 8;
+const BLADE_SEGMENTS: usize = 3;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct GroundCoverSettings {
+    //blades per square unit where the density is 1
+    pub blades_per_unit: f32,
+    pub blade_height: f32,
+    pub blade_width: f32,
+    //how much blades differ from each other, 0 makes them all alike
+    pub height_variation: f32,
+    pub color_variation: f32,
+    pub view_distance: f32,
+}
+impl Default for GroundCoverSettings {
+    fn default() -> Self {
+        Self {
+            blades_per_unit: 8.0,
+            blade_height: 0.5,
+            blade_width: 0.06,
+            height_variation: 0.4,
+            color_variation: 0.15,
+            view_distance: 60.0,
+        }
+    }
+}
+
+//Density of grass, painted like the mask with one value per height sample. Only the
+//blades of the tiles near the camera exist, they're generated again whenever they return.
+#[derive(Resource, Default)]
+pub struct GroundCover {
+    pub chunks: HashMap<IVec2, Vec<f32>>,
+    pub settings: GroundCoverSettings,
+
+    blade_mesh: Option<Handle<Mesh>>,
+    //None for tiles without any blades
+    tiles: HashMap<IVec2, Option<Entity>>,
+    dirty_tiles: HashSet<IVec2>,
+    //chunks that existed when the tiles were generated
+    known_chunks: HashSet<IVec2>,
+    refresh: bool,
+}
+impl GroundCover {
+    pub fn get(&self, master_terrain: &MasterTerrain, world_pos: IVec2) -> f32 {
+        let chunk_pos = master_terrain.world_to_chunk_pos(world_pos);
+        let local_pos = master_terrain.world_to_local_pos(world_pos);
+        match self.chunks.get(&chunk_pos) {
+            Some(values) => {
+                values[local_pos.x as usize + local_pos.y as usize * master_terrain.chunk_size]
+            }
+            None => 0.0,
+        }
+    }
+    pub fn set(&mut self, master_terrain: &MasterTerrain, world_pos: IVec2, value: f32) {
+        let chunk_pos = master_terrain.world_to_chunk_pos(world_pos);
+        let local_pos = master_terrain.world_to_local_pos(world_pos);
+        let chunk_size = master_terrain.chunk_size;
+        let values = self
+            .chunks
+            .entry(chunk_pos)
+            .or_insert_with(|| vec![0.0; chunk_size * chunk_size]);
+        values[local_pos.x as usize + local_pos.y as usize * chunk_size] = value.clamp(0.0, 1.0);
+    }
+    pub fn reset(&mut self, chunks: HashMap<IVec2, Vec<f32>>, settings: GroundCoverSettings) {
+        self.chunks = chunks;
+        self.settings = settings;
+        self.refresh();
+    }
+    pub fn clear(&mut self) {
+        self.chunks.clear();
+        self.refresh();
+    }
+    //Generates every tile again, needed after the settings changed.
+    pub fn refresh(&mut self) {
+        self.refresh = true;
+    }
+    fn mark_rect(&mut self, min: Vec2, max: Vec2) {
+        let (min, max) = (tile_pos(min), tile_pos(max));
+        for x in min.x..=max.x {
+            for y in min.y..=max.y {
+                self.dirty_tiles.insert(IVec2::new(x, y));
+            }
+        }
+    }
+}
+
+fn tile_pos(pos: Vec2) -> IVec2 {
+    (pos / TILE_SIZE).floor().as_ivec2()
+}
+fn tile_distance(tile_pos: IVec2, pos: Vec2) -> f32 {
+    let min = tile_pos.as_vec2() * TILE_SIZE;
+    pos.clamp(min, min + TILE_SIZE).distance(pos)
+}
+
+fn paint_ground_cover(
+    mut edit_info: ResMut<EditInfo>,
+    mut ground_cover: ResMut<GroundCover>,
+    raycast_source: Query<&RaycastSource<()>>,
+    master_terrain: Res<MasterTerrain>,
+    mask: Res<TerrainMask>,
+    mouse: Res<Input<MouseButton>>,
+    keys: Res<Input<KeyCode>>,
+    time: Res<Time>,
+    ui_hovered: Res<UiHovered>,
+) {
+    if !master_terrain.loaded {
+        return;
+    }
+    if let EditMode::GroundCover = &edit_info.edit_mode {
+        let raycast_source = raycast_source.single();
+        for (_, intersection) in raycast_source.intersections() {
+            if mouse.pressed(MouseButton::Left) && !ui_hovered.0 {
+                let pos = intersection.position();
+                if edit_info
+                    .ground_cover_info
+                    .brush_info
+                    .selected_brush
+                    .is_none()
+                {
+                    return;
+                }
+                let size = edit_info.ground_cover_info.brush_info.size;
+                let brush = edit_info
+                    .ground_cover_info
+                    .brush_info
+                    .selected_brush
+                    .as_mut()
+                    .unwrap();
+                if brush.sample_map_size != size {
+                    brush.sample_map =
+                        resize_vector(&brush.map, brush.map_size as usize, size as usize);
+                    brush.sample_map_size = size;
+                }
+                let stamps = stroke_stamps(
+                    &mut edit_info.ground_cover_info.brush_info,
+                    Vec2::new(pos.x, pos.z),
+                    mouse.just_pressed(MouseButton::Left),
+                    time.delta_seconds(),
+                );
+                let stamps = edit_info.symmetry.apply(stamps);
+                let brush = edit_info
+                    .ground_cover_info
+                    .brush_info
+                    .selected_brush
+                    .as_ref()
+                    .unwrap();
+                for stamp in stamps {
+                    let pos = master_terrain.vec2_to_world_pos(stamp.pos);
+                    let size = stamp.size;
+                    let delta = if keys.pressed(KeyCode::ControlLeft) {
+                        -stamp.delta_seconds
+                    } else {
+                        stamp.delta_seconds
+                    };
+                    for x in 0..size {
+                        for y in 0..size {
+                            let x_f32 = x as f32 - size as f32 * 0.5;
+                            let y_f32 = y as f32 - size as f32 * 0.5;
+                            let world_pos =
+                                pos + master_terrain.vec2_to_world_pos(Vec2::new(x_f32, y_f32));
+                            if !master_terrain
+                                .does_chunk_exist(&master_terrain.world_to_chunk_pos(world_pos))
+                            {
+                                continue;
+                            }
+                            let brush_sample =
+                                stamp.sample(brush, x, y) * mask.weight(&master_terrain, world_pos);
+                            let value = ground_cover.get(&master_terrain, world_pos)
+                                + delta * 10.0 * brush_sample * stamp.strength;
+                            ground_cover.set(&master_terrain, world_pos, value);
+                        }
+                    }
+                    let half_size = Vec2::splat(size as f32 * 0.5 + 1.0);
+                    ground_cover.mark_rect(stamp.pos - half_size, stamp.pos + half_size);
+                }
+            }
+        }
+    }
+}
+
+//Grass follows the terrain, so tiles are generated again when the heights or the
+//texture of their chunk change, or when chunks are added or removed.
+fn mark_changed_tiles(
+    mut ground_cover: ResMut<GroundCover>,
+    master_terrain: Res<MasterTerrain>,
+    mut image_events: EventReader<AssetEvent<Image>>,
+    mut mesh_events: EventReader<AssetEvent<Mesh>>,
+    chunk_meshes: Query<&Handle<Mesh>>,
+) {
+    let mut modified_images = HashSet::new();
+    for event in image_events.read() {
+        if let AssetEvent::Modified { id } = event {
+            modified_images.insert(*id);
+        }
+    }
+    let mut modified_meshes = HashSet::new();
+    for event in mesh_events.read() {
+        if let AssetEvent::Modified { id } = event {
+            modified_meshes.insert(*id);
+        }
+    }
+    if !master_terrain.loaded {
+        return;
+    }
+
+    let mut changed_chunks = Vec::new();
+    for (chunk_pos, (_, mesh_entities)) in &master_terrain.chunks {
+        let texture_changed = master_terrain
+            .texture_map
+            .textures
+            .get(chunk_pos)
+            .map_or(false, |handle| modified_images.contains(&handle.id()));
+        let mesh_changed = mesh_entities.values().any(|entity| {
+            chunk_meshes
+                .get(*entity)
+                .map_or(false, |handle| modified_meshes.contains(&handle.id()))
+        });
+        if texture_changed || mesh_changed || !ground_cover.known_chunks.contains(chunk_pos) {
+            changed_chunks.push(*chunk_pos);
+        }
+    }
+    changed_chunks.extend(
+        ground_cover
+            .known_chunks
+            .iter()
+            .filter(|chunk_pos| !master_terrain.chunks.contains_key(chunk_pos)),
+    );
+    if changed_chunks.is_empty() {
+        return;
+    }
+    ground_cover.known_chunks = master_terrain.chunks.keys().copied().collect();
+    let chunk_size = master_terrain.chunk_size as f32;
+    for chunk_pos in changed_chunks {
+        let min = chunk_pos.as_vec2() * chunk_size;
+        ground_cover.mark_rect(min, min + chunk_size);
+    }
+}
+
+//Keeps the tiles within the view distance generated, the closest ones first.
+fn update_grass_tiles(
+    mut commands: Commands,
+    mut ground_cover: ResMut<GroundCover>,
+    master_terrain: Res<MasterTerrain>,
+    images: Res<Assets<Image>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    camera: Query<&Transform, With<Camera>>,
+) {
+    let ground_cover = &mut *ground_cover;
+    if ground_cover.refresh || !master_terrain.loaded {
+        for (_, entity) in ground_cover.tiles.drain() {
+            if let Some(entity) = entity {
+                commands.entity(entity).despawn();
+            }
+        }
+        ground_cover.dirty_tiles.clear();
+        ground_cover.known_chunks.clear();
+        ground_cover.blade_mesh = None;
+        ground_cover.refresh = false;
+        return;
+    }
+    let camera_tf = camera.single();
+    let camera_pos = Vec2::new(camera_tf.translation.x, camera_tf.translation.z);
+    //the higher the camera is above the ground, the less of it is within the view distance
+    let camera_height =
+        if master_terrain.does_chunk_exist(&master_terrain.chunk_pos_from_vec2(&camera_pos)) {
+            (camera_tf.translation.y - master_terrain.sample_height(camera_pos)).max(0.0)
+        } else {
+            0.0
+        };
+    let view_distance = ground_cover.settings.view_distance;
+    let radius = (view_distance * view_distance - camera_height * camera_height)
+        .max(0.0)
+        .sqrt();
+
+    ground_cover.tiles.retain(|tile_pos, entity| {
+        let in_view = tile_distance(*tile_pos, camera_pos) <= radius;
+        if let (false, Some(entity)) = (in_view, entity) {
+            commands.entity(*entity).despawn();
+        }
+        in_view
+    });
+    let tiles = &ground_cover.tiles;
+    //dirty tiles out of view are generated anew once they come back into it
+    ground_cover
+        .dirty_tiles
+        .retain(|tile_pos| tiles.contains_key(tile_pos));
+    if radius <= 0.0 {
+        return;
+    }
+
+    let mut pending = Vec::new();
+    let (min, max) = (tile_pos(camera_pos - radius), tile_pos(camera_pos + radius));
+    for x in min.x..=max.x {
+        for y in min.y..=max.y {
+            let tile_pos = IVec2::new(x, y);
+            if tile_distance(tile_pos, camera_pos) > radius {
+                continue;
+            }
+            if !ground_cover.tiles.contains_key(&tile_pos)
+                || ground_cover.dirty_tiles.contains(&tile_pos)
+            {
+                pending.push(tile_pos);
+            }
+        }
+    }
+    if pending.is_empty() {
+        return;
+    }
+    pending.sort_by(|a, b| tile_distance(*a, camera_pos).total_cmp(&tile_distance(*b, camera_pos)));
+    let settings = &ground_cover.settings;
+    let blade_mesh = ground_cover
+        .blade_mesh
+        .get_or_insert_with(|| meshes.add(blade_mesh(settings)))
+        .clone();
+    for tile_pos in pending.into_iter().take(MAX_TILES_PER_FRAME) {
+        let instances = generate_tile(ground_cover, &master_terrain, &images, tile_pos);
+        let entity = if instances.is_empty() {
+            None
+        } else {
+            Some(
+                commands
+                    .spawn((
+                        blade_mesh.clone(),
+                        SpatialBundle::INHERITED_IDENTITY,
+                        DetailInstances {
+                            instances,
+                            billboard: false,
+                            material: DetailMaterial::default(),
+                        },
+                        //the instances aren't covered by the mesh bounds at the origin
+                        NoFrustumCulling,
+                        NoAutomaticBatching,
+                    ))
+                    .id(),
+            )
+        };
+        //the old blades are replaced in the same frame, so the tile doesn't flicker
+        if let Some(Some(old_entity)) = ground_cover.tiles.insert(tile_pos, entity) {
+            commands.entity(old_entity).despawn();
+        }
+        ground_cover.dirty_tiles.remove(&tile_pos);
+    }
+}
+
+//The tile position seeds the blades, so a tile looks the same every time it's generated
+//and only changes where the terrain or the painted density did.
+fn generate_tile(
+    ground_cover: &GroundCover,
+    master_terrain: &MasterTerrain,
+    images: &Assets<Image>,
+    tile_pos: IVec2,
+) -> Vec<InstanceData> {
+    let settings = &ground_cover.settings;
+    let mut rng =
+        StdRng::seed_from_u64(((tile_pos.x as u32 as u64) << 32) | tile_pos.y as u32 as u64);
+    let count = (settings.blades_per_unit * TILE_SIZE * TILE_SIZE) as usize;
+    let mut instances = Vec::new();
+    for _ in 0..count {
+        //every candidate uses the same amount of randomness whether it's placed or not
+        let pos = (tile_pos.as_vec2() + Vec2::new(rng.gen(), rng.gen())) * TILE_SIZE;
+        let chance: f32 = rng.gen();
+        let yaw = rng.gen_range(0.0..TAU);
+        let size_jitter: f32 = rng.gen_range(-1.0..=1.0);
+        let shade_jitter: f32 = rng.gen_range(-1.0..=1.0);
+        let size = 1.0 + size_jitter * settings.height_variation;
+        let shade = 1.0 + shade_jitter * settings.color_variation;
+
+        if !master_terrain.does_chunk_exist(&master_terrain.chunk_pos_from_vec2(&pos)) {
+            continue;
+        }
+        let density = ground_cover.get(master_terrain, master_terrain.vec2_to_world_pos(pos));
+        if chance >= density {
+            continue;
+        }
+        let color = match read_pixel(
+            master_terrain,
+            images,
+            master_terrain.vec2_to_pixel_pos(pos),
+        ) {
+            Some(color) => color,
+            None => continue,
+        };
+        instances.push(InstanceData {
+            transform: Mat4::from_scale_rotation_translation(
+                Vec3::splat(size.max(0.1)),
+                Quat::from_rotation_y(yaw),
+                Vec3::new(pos.x, master_terrain.sample_height(pos), pos.y),
+            ),
+            //the detail shader lights linear colours
+            color: Vec4::from(
+                Color::rgb(color.r() * shade, color.g() * shade, color.b() * shade)
+                    .as_linear_rgba_f32(),
+            ),
+        });
+    }
+    instances
+}
+
+//A tapered, slightly bent blade. It has faces on both sides since back faces are culled,
+//and it's darker at the root so the ground between the blades reads as shadow.
+fn blade_mesh(settings: &GroundCoverSettings) -> Mesh {
+    let mut positions = Vec::new();
+    let mut colors = Vec::new();
+    for segment in 0..=BLADE_SEGMENTS {
+        let t = segment as f32 / BLADE_SEGMENTS as f32;
+        let half_width = settings.blade_width * 0.5 * (1.0 - t);
+        let bend = t * t * settings.blade_height * 0.2;
+        let y = t * settings.blade_height;
+        positions.extend([[-half_width, y, bend], [half_width, y, bend]]);
+        let shade = 0.5 + 0.5 * t;
+        colors.extend([[shade, shade, shade, 1.0]; 2]);
+    }
+    let mut indices = Vec::new();
+    for segment in 0..BLADE_SEGMENTS as u32 {
+        let index = segment * 2;
+        indices.extend([index, index + 1, index + 3, index, index + 3, index + 2]);
+        indices.extend([index, index + 3, index + 1, index, index + 2, index + 3]);
+    }
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    //lit like the ground below instead of like a thin card
+    mesh.insert_attribute(
+        Mesh::ATTRIBUTE_NORMAL,
+        vec![[0.0, 1.0, 0.0]; positions.len()],
+    );
+    mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.set_indices(Some(Indices::U32(indices)));
+    mesh
+}
@@ -1,15 +1,30 @@
+mod brush;
+mod brush_library;
+mod brush_preview;
 mod camera;
+mod detail_instancing;
+mod detail_selection;
 mod details;
 mod draw;
 mod edit_chunks;
+mod ground_cover;
+mod mask;
+mod model_library;
+mod scatter;
 mod sculpt;
 mod serialize;
+mod splines;
 mod terrain;
 mod ui;
+mod water;
 
-use std::ops::{Add, Mul};
+use std::{
+    ops::{Add, Mul},
+    path::PathBuf,
+};
 
 use bevy::{
+    asset::io::file::FileAssetReader,
     pbr::wireframe::{WireframeConfig, WireframePlugin},
     prelude::*,
     window::PresentMode,
@@ -19,15 +34,26 @@ use bevy::{
 use bevy_atmosphere::prelude::*;
 
 use bevy_mod_raycast::prelude::{DeferredRaycastingPlugin, RaycastPluginState};
+use brush_library::BrushLibraryPlugin;
+use brush_preview::BrushPreviewPlugin;
+use detail_instancing::DetailInstancingPlugin;
+use detail_selection::DetailSelectionPlugin;
 use details::DetailsPlugin;
 use draw::DrawPlugin;
 use edit_chunks::EditChunksPlugin;
+use ground_cover::GroundCoverPlugin;
+use mask::MaskPlugin;
+use model_library::ModelLibraryPlugin;
+use scatter::ScatterPlugin;
 use sculpt::SculptPlugin;
 use serialize::SerializePlugin;
+use splines::SplinesPlugin;
 use terrain::TerrainPlugin;
 use ui::TerrainUiPlugin;
+use water::WaterPlugin;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
+const ASSET_FOLDER: &str = "data";
 #[derive(Reflect, Clone)]
 struct MyRaycastSet;
 fn main() {
@@ -44,7 +70,7 @@ fn main() {
                     ..Default::default()
                 })
                 .set(AssetPlugin {
-                    file_path: "data".to_string(),
+                    file_path: ASSET_FOLDER.to_string(),
                     ..Default::default()
                 }),
         )
@@ -54,14 +80,26 @@ fn main() {
             AtmospherePlugin,
             camera::CameraPlugin,
             TerrainUiPlugin,
+            BrushLibraryPlugin,
             TerrainPlugin,
             EditChunksPlugin,
             SculptPlugin,
             DrawPlugin,
             DetailsPlugin,
+            BrushPreviewPlugin,
             SerializePlugin,
             WireframePlugin,
         ))
+        .add_plugins((
+            DetailInstancingPlugin,
+            SplinesPlugin,
+            MaskPlugin,
+            ScatterPlugin,
+            DetailSelectionPlugin,
+            GroundCoverPlugin,
+            ModelLibraryPlugin,
+            WaterPlugin,
+        ))
         .insert_resource(AtmosphereModel::default())
         .insert_resource(RaycastPluginState::<()>::default())
         .insert_resource(WireframeConfig {
@@ -84,6 +122,18 @@ impl Default for DebugInformation {
     }
 }
 
+//Path on disk of a file in the asset folder, resolved the same way bevy does.
+pub fn asset_path(path: &str) -> PathBuf {
+    FileAssetReader::get_base_path()
+        .join(ASSET_FOLDER)
+        .join(path)
+}
+//Path on disk of editor data that isn't an asset, next to the asset folder so the
+//asset watcher doesn't pick up changes to it.
+pub fn user_data_path(path: &str) -> PathBuf {
+    FileAssetReader::get_base_path().join(path)
+}
+
 fn sample_vec<T: Mul<f32, Output = T> + Add<Output = T> + Copy>(
     x: f32,
     y: f32,
@@ -0,0 +1,358 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use bevy_mod_raycast::deferred::RaycastSource;
+
+use crate::{
+    brush::stroke_stamps,
+    resize_vector,
+    terrain::MasterTerrain,
+    ui::{EditInfo, EditMode, UiHovered},
+    water::Water,
+};
+
+pub struct MaskPlugin;
+impl Plugin for MaskPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(TerrainMask::default())
+            .add_systems(Update, (paint_mask, mask_actions, draw_mask));
+    }
+}
+
+//cells around the cursor that show their mask value while editing it
+const MASK_PREVIEW_RADIUS: i32 = 40;
+const MASK_PREVIEW_STEP: usize = 2;
+const MASK_PREVIEW_HEIGHT: f32 = 1.5;
+
+//what a cell of the shoreline grid has within reach
+const SHORE_WET: u8 = 1;
+const SHORE_DRY: u8 = 2;
+
+#[derive(PartialEq, Clone)]
+pub enum MaskAction {
+    FillHeight,
+    FillSlope,
+    Invert,
+    Feather,
+    FillShoreline,
+    SelectAll,
+    Clear,
+}
+
+//Selection of the terrain that edits are limited to. Every chunk has one value per
+//height sample, 1 is fully selected. Chunks without values aren't selected.
+#[derive(Resource, Default)]
+pub struct TerrainMask {
+    pub enabled: bool,
+    pub chunks: HashMap<IVec2, Vec<f32>>,
+}
+impl TerrainMask {
+    //How much an edit at world_pos should apply, always 1 when the mask is disabled.
+    pub fn weight(&self, master_terrain: &MasterTerrain, world_pos: IVec2) -> f32 {
+        if !self.enabled {
+            return 1.0;
+        }
+        self.get(master_terrain, world_pos)
+    }
+    pub fn get(&self, master_terrain: &MasterTerrain, world_pos: IVec2) -> f32 {
+        let chunk_pos = master_terrain.world_to_chunk_pos(world_pos);
+        let local_pos = master_terrain.world_to_local_pos(world_pos);
+        match self.chunks.get(&chunk_pos) {
+            Some(values) => {
+                values[local_pos.x as usize + local_pos.y as usize * master_terrain.chunk_size]
+            }
+            None => 0.0,
+        }
+    }
+    pub fn set(&mut self, master_terrain: &MasterTerrain, world_pos: IVec2, value: f32) {
+        let chunk_pos = master_terrain.world_to_chunk_pos(world_pos);
+        let local_pos = master_terrain.world_to_local_pos(world_pos);
+        let chunk_size = master_terrain.chunk_size;
+        let values = self
+            .chunks
+            .entry(chunk_pos)
+            .or_insert_with(|| vec![0.0; chunk_size * chunk_size]);
+        values[local_pos.x as usize + local_pos.y as usize * chunk_size] = value.clamp(0.0, 1.0);
+    }
+    pub fn clear(&mut self) {
+        self.chunks.clear();
+    }
+    //Sets every cell of the existing chunks from a function of its world position.
+    fn fill(&mut self, master_terrain: &MasterTerrain, value: impl Fn(IVec2) -> f32) {
+        let chunk_size = master_terrain.chunk_size as i32;
+        for chunk_pos in master_terrain.chunks.keys() {
+            let mut values = Vec::with_capacity((chunk_size * chunk_size) as usize);
+            for y in 0..chunk_size {
+                for x in 0..chunk_size {
+                    values.push(value(*chunk_pos * chunk_size + IVec2::new(x, y)).clamp(0.0, 1.0));
+                }
+            }
+            self.chunks.insert(*chunk_pos, values);
+        }
+    }
+}
+
+fn paint_mask(
+    mut edit_info: ResMut<EditInfo>,
+    mut mask: ResMut<TerrainMask>,
+    raycast_source: Query<&RaycastSource<()>>,
+    master_terrain: Res<MasterTerrain>,
+    mouse: Res<Input<MouseButton>>,
+    keys: Res<Input<KeyCode>>,
+    time: Res<Time>,
+    ui_hovered: Res<UiHovered>,
+) {
+    if !master_terrain.loaded {
+        return;
+    }
+    if let EditMode::Mask = &edit_info.edit_mode {
+        let raycast_source = raycast_source.single();
+        for (_, intersection) in raycast_source.intersections() {
+            if mouse.pressed(MouseButton::Left) && !ui_hovered.0 {
+                let pos = intersection.position();
+                if edit_info.mask_info.brush_info.selected_brush.is_none() {
+                    return;
+                }
+                let size = edit_info.mask_info.brush_info.size;
+                let brush = edit_info
+                    .mask_info
+                    .brush_info
+                    .selected_brush
+                    .as_mut()
+                    .unwrap();
+                if brush.sample_map_size != size {
+                    brush.sample_map =
+                        resize_vector(&brush.map, brush.map_size as usize, size as usize);
+                    brush.sample_map_size = size;
+                }
+                let stamps = stroke_stamps(
+                    &mut edit_info.mask_info.brush_info,
+                    Vec2::new(pos.x, pos.z),
+                    mouse.just_pressed(MouseButton::Left),
+                    time.delta_seconds(),
+                );
+                let stamps = edit_info.symmetry.apply(stamps);
+                let brush = edit_info
+                    .mask_info
+                    .brush_info
+                    .selected_brush
+                    .as_ref()
+                    .unwrap();
+                //painting the mask always works on the whole terrain, even while it's enabled
+                for stamp in stamps {
+                    let pos = master_terrain.vec2_to_world_pos(stamp.pos);
+                    let size = stamp.size;
+                    let delta = if keys.pressed(KeyCode::ControlLeft) {
+                        -stamp.delta_seconds
+                    } else {
+                        stamp.delta_seconds
+                    };
+                    for x in 0..size {
+                        for y in 0..size {
+                            let x_f32 = x as f32 - size as f32 * 0.5;
+                            let y_f32 = y as f32 - size as f32 * 0.5;
+                            let world_pos =
+                                pos + master_terrain.vec2_to_world_pos(Vec2::new(x_f32, y_f32));
+                            if !master_terrain
+                                .does_chunk_exist(&master_terrain.world_to_chunk_pos(world_pos))
+                            {
+                                continue;
+                            }
+                            let brush_sample = stamp.sample(brush, x, y);
+                            let value = mask.get(&master_terrain, world_pos)
+                                + delta * 10.0 * brush_sample * stamp.strength;
+                            mask.set(&master_terrain, world_pos, value);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn mask_actions(
+    mut edit_info: ResMut<EditInfo>,
+    mut mask: ResMut<TerrainMask>,
+    master_terrain: Res<MasterTerrain>,
+    water: Res<Water>,
+) {
+    let action = if let Some(action) = edit_info.mask_info.action.take() {
+        action
+    } else {
+        return;
+    };
+    if !master_terrain.loaded {
+        return;
+    }
+    let mask_info = &edit_info.mask_info;
+    match action {
+        MaskAction::FillHeight => {
+            let (min, max) = (mask_info.min_height, mask_info.max_height);
+            mask.fill(&master_terrain, |world_pos| {
+                let height = master_terrain.get_height(world_pos);
+                (height >= min && height <= max) as u8 as f32
+            });
+        }
+        MaskAction::FillSlope => {
+            let (min, max) = (mask_info.min_slope, mask_info.max_slope);
+            mask.fill(&master_terrain, |world_pos| {
+                let normal = master_terrain.sample_normal(world_pos.as_vec2());
+                let slope = normal.y.clamp(-1.0, 1.0).acos().to_degrees();
+                (slope >= min && slope <= max) as u8 as f32
+            });
+        }
+        MaskAction::Invert => {
+            let old = std::mem::take(&mut *mask);
+            mask.enabled = old.enabled;
+            mask.fill(&master_terrain, |world_pos| {
+                1.0 - old.get(&master_terrain, world_pos)
+            });
+        }
+        MaskAction::Feather => {
+            //box blur, reading through get so it blends across chunk borders
+            let radius = mask_info.feather_radius as i32;
+            let old = std::mem::take(&mut *mask);
+            mask.enabled = old.enabled;
+            mask.fill(&master_terrain, |world_pos| {
+                let mut sum = 0.0;
+                let mut count = 0.0;
+                for x in -radius..=radius {
+                    for y in -radius..=radius {
+                        sum += old.get(&master_terrain, world_pos + IVec2::new(x, y));
+                        count += 1.0;
+                    }
+                }
+                sum / count
+            });
+        }
+        MaskAction::FillShoreline => {
+            //the band along the waterline where both water and dry land are within reach,
+            //for painting beaches and banks
+            let (min, size, cells) = shore_cells(&master_terrain, &water);
+            let radius = mask_info.shore_width as i32;
+            let cells = spread_shore_cells(&cells, size, radius, false);
+            let cells = spread_shore_cells(&cells, size, radius, true);
+            mask.fill(&master_terrain, |world_pos| {
+                let pos = world_pos - min;
+                (cells[(pos.x + pos.y * size.x) as usize] == SHORE_WET | SHORE_DRY) as u8 as f32
+            });
+        }
+        MaskAction::SelectAll => mask.fill(&master_terrain, |_| 1.0),
+        MaskAction::Clear => mask.clear(),
+    }
+}
+
+//Whether each cell is under water or dry, in a grid over the bounds of the existing chunks.
+//Cells of missing chunks are neither. Returns the first cell of the grid and its size.
+fn shore_cells(master_terrain: &MasterTerrain, water: &Water) -> (IVec2, IVec2, Vec<u8>) {
+    let chunk_size = master_terrain.chunk_size as i32;
+    let (min_chunk, max_chunk) = master_terrain
+        .chunks
+        .keys()
+        .fold((IVec2::MAX, IVec2::MIN), |(min, max), chunk_pos| {
+            (min.min(*chunk_pos), max.max(*chunk_pos))
+        });
+    let min = min_chunk * chunk_size;
+    let size = (max_chunk - min_chunk + 1) * chunk_size;
+    let mut cells = vec![0; (size.x * size.y) as usize];
+    //the heights are read under one lock instead of one per cell
+    let heightmaps = master_terrain.heightmap.heightmaps.lock().unwrap();
+    for chunk_pos in master_terrain.chunks.keys() {
+        let Some(heights) = heightmaps.get(chunk_pos) else {
+            continue;
+        };
+        for y in 0..chunk_size {
+            for x in 0..chunk_size {
+                let world_pos = *chunk_pos * chunk_size + IVec2::new(x, y);
+                let height = heights[(x + y * chunk_size) as usize];
+                let underwater = water
+                    .level_at(world_pos)
+                    .map_or(false, |level| level > height);
+                let pos = world_pos - min;
+                cells[(pos.x + pos.y * size.x) as usize] =
+                    if underwater { SHORE_WET } else { SHORE_DRY };
+            }
+        }
+    }
+    (min, size, cells)
+}
+//Gives every cell the flags of the cells within radius along one axis. Running it along
+//both axes covers the square around each cell, with a running count per row or column
+//instead of visiting the whole square.
+fn spread_shore_cells(cells: &[u8], size: IVec2, radius: i32, vertical: bool) -> Vec<u8> {
+    let (length, lines) = if vertical {
+        (size.y, size.x)
+    } else {
+        (size.x, size.y)
+    };
+    let index = |line: i32, i: i32| {
+        if vertical {
+            (line + i * size.x) as usize
+        } else {
+            (i + line * size.x) as usize
+        }
+    };
+    let mut spread = vec![0; cells.len()];
+    for line in 0..lines {
+        //cells of each kind from i - 2 * radius to i
+        let (mut wet, mut dry) = (0, 0);
+        for i in 0..length + radius {
+            if i < length {
+                let cell = cells[index(line, i)];
+                wet += (cell & SHORE_WET != 0) as i32;
+                dry += (cell & SHORE_DRY != 0) as i32;
+            }
+            let leaving = i - 2 * radius - 1;
+            if leaving >= 0 {
+                let cell = cells[index(line, leaving)];
+                wet -= (cell & SHORE_WET != 0) as i32;
+                dry -= (cell & SHORE_DRY != 0) as i32;
+            }
+            let center = i - radius;
+            if center >= 0 {
+                spread[index(line, center)] =
+                    (wet > 0) as u8 * SHORE_WET | (dry > 0) as u8 * SHORE_DRY;
+            }
+        }
+    }
+    spread
+}
+
+//Shows the mask around the cursor as short lines, brighter where more is selected.
+fn draw_mask(
+    edit_info: Res<EditInfo>,
+    mask: Res<TerrainMask>,
+    raycast_source: Query<&RaycastSource<()>>,
+    master_terrain: Res<MasterTerrain>,
+    mut gizmos: Gizmos,
+) {
+    if !master_terrain.loaded {
+        return;
+    }
+    if let EditMode::Mask = &edit_info.edit_mode {
+        let raycast_source = raycast_source.single();
+        let (_, intersection) = if let Some(intersection) = raycast_source.intersections().first() {
+            intersection
+        } else {
+            return;
+        };
+        let pos = intersection.position();
+        let center = master_terrain.vec2_to_world_pos(Vec2::new(pos.x, pos.z));
+        for x in (-MASK_PREVIEW_RADIUS..=MASK_PREVIEW_RADIUS).step_by(MASK_PREVIEW_STEP) {
+            for y in (-MASK_PREVIEW_RADIUS..=MASK_PREVIEW_RADIUS).step_by(MASK_PREVIEW_STEP) {
+                let world_pos = center + IVec2::new(x, y);
+                let value = mask.get(&master_terrain, world_pos);
+                if value <= 0.0 {
+                    continue;
+                }
+                let height = master_terrain.get_height(world_pos);
+                let base = Vec3::new(world_pos.x as f32, height, world_pos.y as f32);
+                gizmos.line(
+                    base,
+                    base + Vec3::Y * MASK_PREVIEW_HEIGHT,
+                    Color::rgba(1.0, 0.5, 0.0, value),
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,256 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+};
+
+use bevy::{
+    asset::LoadedFolder,
+    gltf::Gltf,
+    prelude::*,
+    render::render_resource::{Extent3d, TextureDimension, TextureFormat},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    asset_path,
+    detail_instancing::{is_lod_model, model_parts, point_in_triangle, side_view_triangles},
+    ui::EditInfo,
+};
+
+pub struct ModelLibraryPlugin;
+impl Plugin for ModelLibraryPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(ModelLibrary::default())
+            .add_systems(Update, update_model_library);
+    }
+}
+
+const THUMBNAIL_SIZE: u32 = 64;
+
+//How a model is placed, stored in a manifest next to the model file.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ModelMetadata {
+    //the placement scale range is relative to it
+    pub default_scale: f32,
+    //in model units, for models whose origin isn't at their base
+    pub pivot_offset: Vec3,
+    //how far the model is sunk into the ground, in world units
+    pub embed_depth: f32,
+    //no other detail is placed closer than this
+    pub collision_radius: f32,
+    pub tags: Vec<String>,
+}
+impl Default for ModelMetadata {
+    fn default() -> Self {
+        Self {
+            default_scale: 1.0,
+            pivot_offset: Vec3::ZERO,
+            embed_depth: 0.0,
+            collision_radius: 1.0,
+            tags: Vec::new(),
+        }
+    }
+}
+
+//Models of the models folder with their metadata and a thumbnail once they've loaded.
+#[derive(Resource, Default)]
+pub struct ModelLibrary {
+    //file names of the models without their LODs, sorted
+    pub names: Vec<String>,
+    pub metadata: HashMap<String, ModelMetadata>,
+    pub thumbnails: HashMap<String, Handle<Image>>,
+
+    pub tag_filter: String,
+
+    //models whose metadata changed since the detail batches were built
+    changed: HashSet<String>,
+}
+impl ModelLibrary {
+    pub fn get(&self, name: &str) -> ModelMetadata {
+        self.metadata.get(name).cloned().unwrap_or_default()
+    }
+    //Only changes the metadata in memory, save writes it to the manifest.
+    pub fn set(&mut self, name: &str, metadata: ModelMetadata) {
+        self.metadata.insert(name.to_string(), metadata);
+        self.changed.insert(name.to_string());
+    }
+    pub fn save(&self, name: &str) {
+        if let Some(metadata) = self.metadata.get(name) {
+            save_metadata(name, metadata);
+        }
+    }
+    pub fn take_changed(&mut self) -> HashSet<String> {
+        std::mem::take(&mut self.changed)
+    }
+    //Models to show, filtered by the tag filter.
+    pub fn visible(&self) -> Vec<&String> {
+        let filter = self.tag_filter.trim().to_lowercase();
+        self.names
+            .iter()
+            .filter(|name| {
+                filter.is_empty()
+                    || self.metadata.get(*name).map_or(false, |metadata| {
+                        metadata
+                            .tags
+                            .iter()
+                            .any(|tag| tag.to_lowercase().contains(&filter))
+                    })
+            })
+            .collect()
+    }
+}
+
+//model.glb keeps its metadata in model.ron
+fn manifest_path(name: &str) -> String {
+    let stem = Path::new(name)
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy();
+    format!("models/{}.ron", stem)
+}
+fn load_metadata(name: &str) -> ModelMetadata {
+    let text = match fs::read_to_string(asset_path(&manifest_path(name))) {
+        Ok(text) => text,
+        //models without a manifest use the defaults
+        Err(_) => return ModelMetadata::default(),
+    };
+    match ron::from_str(&text) {
+        Ok(metadata) => metadata,
+        Err(err) => {
+            error!("Failed to read the manifest of {name}: {err}");
+            ModelMetadata::default()
+        }
+    }
+}
+fn save_metadata(name: &str, metadata: &ModelMetadata) {
+    let text = match ron::ser::to_string_pretty(metadata, ron::ser::PrettyConfig::default()) {
+        Ok(text) => text,
+        Err(err) => {
+            error!("Failed to save the manifest of {name}: {err}");
+            return;
+        }
+    };
+    if let Err(err) = fs::write(asset_path(&manifest_path(name)), text) {
+        error!("Failed to save the manifest of {name}: {err}");
+    }
+}
+
+pub fn is_model_file(name: &str) -> bool {
+    matches!(
+        Path::new(name)
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .as_deref(),
+        Some("glb" | "gltf")
+    )
+}
+
+//Side view of the model in its material colours, drawn like its impostor but finer.
+fn thumbnail(triangles: &[([Vec2; 3], Vec4)]) -> Image {
+    let (min, max) = triangles
+        .iter()
+        .flat_map(|(triangle, _)| triangle.iter())
+        .fold((Vec2::MAX, Vec2::MIN), |(min, max), pos| {
+            (min.min(*pos), max.max(*pos))
+        });
+    //square around the model so it isn't stretched
+    let size = (max - min).max_element();
+    let min = (min + max) * 0.5 - Vec2::splat(size * 0.5);
+    let pixel_size = size / THUMBNAIL_SIZE as f32;
+
+    let mut data = vec![0; (THUMBNAIL_SIZE * THUMBNAIL_SIZE * 4) as usize];
+    for (triangle, color) in triangles {
+        let (triangle_min, triangle_max) = triangle.iter().fold(
+            (Vec2::MAX, Vec2::MIN),
+            |(triangle_min, triangle_max), pos| (triangle_min.min(*pos), triangle_max.max(*pos)),
+        );
+        let start = ((triangle_min - min) / pixel_size).floor().max(Vec2::ZERO);
+        let end = ((triangle_max - min) / pixel_size)
+            .ceil()
+            .min(Vec2::splat(THUMBNAIL_SIZE as f32));
+        let rgba = [
+            (color.x * 255.0) as u8,
+            (color.y * 255.0) as u8,
+            (color.z * 255.0) as u8,
+            255,
+        ];
+        for y in start.y as u32..end.y as u32 {
+            for x in start.x as u32..end.x as u32 {
+                let point = min + (Vec2::new(x as f32, y as f32) + 0.5) * pixel_size;
+                if point_in_triangle(point, triangle) {
+                    //image rows go down, the model goes up
+                    let index = (x + (THUMBNAIL_SIZE - 1 - y) * THUMBNAIL_SIZE) as usize * 4;
+                    data[index..index + 4].copy_from_slice(&rgba);
+                }
+            }
+        }
+    }
+    Image::new(
+        Extent3d {
+            width: THUMBNAIL_SIZE,
+            height: THUMBNAIL_SIZE,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data,
+        TextureFormat::Rgba8UnormSrgb,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn update_model_library(
+    mut library: ResMut<ModelLibrary>,
+    edit_info: Res<EditInfo>,
+    loaded_folders: Res<Assets<LoadedFolder>>,
+    asset_server: Res<AssetServer>,
+    gltfs: Res<Assets<Gltf>>,
+    scenes: Res<Assets<Scene>>,
+    materials: Res<Assets<StandardMaterial>>,
+    meshes: Res<Assets<Mesh>>,
+    mut images: ResMut<Assets<Image>>,
+) {
+    let models_folder = if let Some(models_folder) = loaded_folders.get(&edit_info.models) {
+        models_folder
+    } else {
+        return;
+    };
+    for handle in &models_folder.handles {
+        let name = match asset_server.get_path(handle.id()) {
+            Some(path) => match path.path().file_name() {
+                Some(file_name) => file_name.to_string_lossy().to_string(),
+                None => continue,
+            },
+            None => continue,
+        };
+        //LODs are picked up with their model
+        if !is_model_file(&name) || is_lod_model(&name) {
+            continue;
+        }
+        if !library.metadata.contains_key(&name) {
+            let metadata = load_metadata(&name);
+            library.metadata.insert(name.clone(), metadata);
+            //details may have been batched before the manifest was read
+            library.changed.insert(name.clone());
+            library.names.push(name.clone());
+            library.names.sort();
+        }
+        if library.thumbnails.contains_key(&name) {
+            continue;
+        }
+        let gltf = if let Some(gltf) = gltfs.get(&handle.clone().typed::<Gltf>()) {
+            gltf
+        } else {
+            continue;
+        };
+        let triangles = match model_parts(gltf, &scenes, &materials)
+            .and_then(|parts| side_view_triangles(&parts, &meshes))
+        {
+            Some(triangles) => triangles,
+            None => continue,
+        };
+        let thumbnail = images.add(thumbnail(&triangles));
+        library.thumbnails.insert(name, thumbnail);
+    }
+}
@@ -0,0 +1,265 @@
+use std::collections::HashSet;
+
+use bevy::prelude::*;
+use noise::{core::open_simplex::open_simplex_2d, permutationtable::PermutationTable};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    details::{random_transform, spawn_detail, DetailModel},
+    draw::read_pixel,
+    mask::TerrainMask,
+    model_library::ModelLibrary,
+    terrain::MasterTerrain,
+    ui::DetailVariation,
+    water::Water,
+};
+
+pub struct ScatterPlugin;
+impl Plugin for ScatterPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Scatter::default())
+            .add_systems(Update, apply_scatter);
+    }
+}
+
+//area that the density of a rule is given for
+const DENSITY_AREA: f32 = 100.0;
+
+//Where a model may be placed and how many of it.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ScatterRule {
+    pub model: String,
+    //details per 100 square units
+    pub density: f32,
+    //no two details are placed closer than this
+    pub min_spacing: f32,
+
+    pub min_height: f32,
+    pub max_height: f32,
+    //in degrees
+    pub min_slope: f32,
+    pub max_slope: f32,
+
+    //only scatter where the painted colour is close to this one
+    pub texture_color: Option<Vec3>,
+    pub texture_tolerance: f32,
+
+    //how strongly details gather in clumps, 0 spreads them evenly
+    pub clustering: f32,
+    pub cluster_scale: f32,
+
+    pub variation: DetailVariation,
+    //picked when the rule is added, so removing or reordering other rules doesn't change
+    //what this one places
+    pub seed: u64,
+}
+impl ScatterRule {
+    pub fn new(model: String, seed: u64) -> Self {
+        Self {
+            model,
+            density: 1.0,
+            min_spacing: 2.0,
+
+            min_height: -1000.0,
+            max_height: 1000.0,
+            min_slope: 0.0,
+            max_slope: 30.0,
+
+            texture_color: None,
+            texture_tolerance: 0.2,
+
+            clustering: 0.5,
+            cluster_scale: 50.0,
+
+            variation: DetailVariation::default(),
+            seed,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub enum ScatterRegion {
+    Terrain,
+    Mask,
+}
+impl ToString for ScatterRegion {
+    fn to_string(&self) -> String {
+        match self {
+            ScatterRegion::Terrain => "Whole terrain",
+            ScatterRegion::Mask => "Mask",
+        }
+        .to_string()
+    }
+}
+impl Default for ScatterRegion {
+    fn default() -> Self {
+        Self::Terrain
+    }
+}
+
+//Scatter rules are applied on request. The same seed always gives the same details, so
+//applying them again after sculpting only moves what the new terrain changes.
+#[derive(Resource, Default)]
+pub struct Scatter {
+    pub rules: Vec<ScatterRule>,
+    pub selected: Option<usize>,
+    pub seed: u32,
+    pub region: ScatterRegion,
+    apply: bool,
+}
+impl Scatter {
+    pub fn add(&mut self, model: String) {
+        self.rules.push(ScatterRule::new(model, rand::random()));
+        self.selected = Some(self.rules.len() - 1);
+    }
+    pub fn remove(&mut self, index: usize) {
+        self.rules.remove(index);
+        self.selected = None;
+    }
+    pub fn apply(&mut self) {
+        self.apply = true;
+    }
+    pub fn reset(&mut self, rules: Vec<ScatterRule>, seed: u32) {
+        *self = Self {
+            rules,
+            seed,
+            ..Default::default()
+        };
+    }
+}
+
+//Every rule gets its own random sequence per chunk, so chunks and rules don't affect each other.
+fn chunk_seed(seed: u32, rule_seed: u64, chunk_pos: IVec2) -> u64 {
+    let chunk = ((chunk_pos.x as u32 as u64) << 32) | chunk_pos.y as u32 as u64;
+    (seed as u64)
+        .wrapping_mul(0x9E37_79B9_7F4A_7C15)
+        .wrapping_add(rule_seed)
+        .wrapping_mul(0xBF58_476D_1CE4_E5B9)
+        ^ chunk
+}
+
+fn apply_scatter(
+    mut commands: Commands,
+    mut scatter: ResMut<Scatter>,
+    mut master_terrain: ResMut<MasterTerrain>,
+    mask: Res<TerrainMask>,
+    images: Res<Assets<Image>>,
+    model_library: Res<ModelLibrary>,
+    water: Res<Water>,
+    details: Query<(Entity, &DetailModel)>,
+) {
+    if !scatter.apply {
+        return;
+    }
+    scatter.apply = false;
+    if !master_terrain.loaded {
+        return;
+    }
+    let region_weight = |master_terrain: &MasterTerrain, world_pos: IVec2| match scatter.region {
+        //an enabled mask still limits a scatter over the whole terrain
+        ScatterRegion::Terrain => mask.weight(master_terrain, world_pos),
+        ScatterRegion::Mask => mask.get(master_terrain, world_pos),
+    };
+
+    //earlier results of the rules are replaced, everything else is kept and spaced around
+    let models: HashSet<&String> = scatter.rules.iter().map(|rule| &rule.model).collect();
+    for (entity, detail_model) in &details {
+        let world_pos = master_terrain.vec2_to_world_pos(detail_model.pos);
+        if detail_model.scattered
+            && models.contains(&detail_model.name)
+            && region_weight(&master_terrain, world_pos) > 0.0
+        {
+            commands.entity(entity).despawn_recursive();
+            master_terrain.details.remove(entity, detail_model.pos);
+        }
+    }
+
+    let mut chunks: Vec<IVec2> = master_terrain.chunks.keys().copied().collect();
+    chunks.sort_by_key(|chunk_pos| (chunk_pos.x, chunk_pos.y));
+    let chunk_size = master_terrain.chunk_size as f32;
+    let hasher = PermutationTable::new(scatter.seed);
+    for rule in &scatter.rules {
+        let count = (rule.density * chunk_size * chunk_size / DENSITY_AREA).round() as usize;
+        let metadata = model_library.get(&rule.model);
+        let min_spacing = rule.min_spacing.max(metadata.collision_radius);
+        for chunk_pos in &chunks {
+            let mut rng = StdRng::seed_from_u64(chunk_seed(scatter.seed, rule.seed, *chunk_pos));
+            for _ in 0..count {
+                //every candidate uses the same amount of randomness whether it's placed or not
+                let pos = (chunk_pos.as_vec2() + Vec2::new(rng.gen(), rng.gen())) * chunk_size;
+                let chance: f32 = rng.gen();
+                let placement_seed: u64 = rng.gen();
+
+                let world_pos = master_terrain.vec2_to_world_pos(pos);
+                let detail_chunk_pos = master_terrain.chunk_pos_from_vec2(&pos);
+                if !master_terrain.does_chunk_exist(&detail_chunk_pos) {
+                    continue;
+                }
+                let cluster = (open_simplex_2d(
+                    [
+                        pos.x as f64 / rule.cluster_scale as f64,
+                        pos.y as f64 / rule.cluster_scale as f64,
+                    ],
+                    &hasher,
+                ) as f32
+                    + 1.0)
+                    * 0.5;
+                let weight = (1.0 - rule.clustering + rule.clustering * cluster)
+                    * region_weight(&master_terrain, world_pos);
+                if chance >= weight {
+                    continue;
+                }
+                //nothing is scattered under water
+                if water.is_underwater(&master_terrain, world_pos) {
+                    continue;
+                }
+                let height = master_terrain.sample_height(pos);
+                if height < rule.min_height || height > rule.max_height {
+                    continue;
+                }
+                let normal = master_terrain.sample_normal(pos);
+                let slope = normal.y.clamp(-1.0, 1.0).acos().to_degrees();
+                if slope < rule.min_slope || slope > rule.max_slope {
+                    continue;
+                }
+                if let Some(texture_color) = rule.texture_color {
+                    let pixel_pos = master_terrain.vec2_to_pixel_pos(pos);
+                    let color = match read_pixel(&master_terrain, &images, pixel_pos) {
+                        Some(color) => color,
+                        None => continue,
+                    };
+                    let color = Vec3::new(color.r(), color.g(), color.b());
+                    if color.distance(texture_color) > rule.texture_tolerance {
+                        continue;
+                    }
+                }
+                if master_terrain.details.any_in_radius(pos, min_spacing) {
+                    continue;
+                }
+
+                let mut placement_rng = StdRng::seed_from_u64(placement_seed);
+                let (rotation, scale) = random_transform(
+                    &master_terrain,
+                    &rule.variation,
+                    metadata.default_scale,
+                    pos,
+                    &mut placement_rng,
+                );
+                let id = spawn_detail(
+                    &mut commands,
+                    &master_terrain,
+                    DetailModel {
+                        name: rule.model.clone(),
+                        chunk_pos: detail_chunk_pos,
+                        pos,
+                        rotation,
+                        scale,
+                        scattered: true,
+                    },
+                );
+                master_terrain.details.insert(id, pos);
+            }
+        }
+    }
+}
@@ -1,15 +1,23 @@
+use std::f32::consts::SQRT_2;
+
 use bevy::prelude::*;
 use bevy_mod_raycast::deferred::RaycastSource;
+use noise::{core::open_simplex::open_simplex_2d, permutationtable::PermutationTable};
 
 use crate::{
-    resize_vector,
+    brush::{stroke_stamps, Stamp, Symmetry},
+    mask::TerrainMask,
+    resize_vector, sample_vec,
     terrain::MasterTerrain,
-    ui::{EditInfo, EditMode, SculptType, UiHovered},
+    ui::{
+        Brush, EditInfo, EditMode, SculptInfo, SculptNoiseSettings, SculptType, StampBlend,
+        TerraceSettings, UiHovered,
+    },
 };
 pub struct SculptPlugin;
 impl Plugin for SculptPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Update, sculpt);
+        app.add_systems(Update, (sculpt, terrace_filter));
     }
 }
 
@@ -21,10 +29,14 @@ fn sculpt(
     time: Res<Time>,
     keys: Res<Input<KeyCode>>,
     ui_hovered: Res<UiHovered>,
+    mask: Res<TerrainMask>,
 ) {
     if !master_terrain.loaded {
         return;
     }
+    if keys.just_pressed(KeyCode::Escape) {
+        edit_info.sculpt_info.ramp_start = None;
+    }
     if let EditMode::Sculpt = &edit_info.edit_mode {
         let raycast_source = raycast_source.single();
         for (_, intersection) in raycast_source.intersections() {
@@ -33,7 +45,34 @@ fn sculpt(
                 if edit_info.sculpt_info.auto_height && mouse.just_pressed(MouseButton::Left) {
                     edit_info.sculpt_info.set_height = pos.y;
                 }
-                let pos = master_terrain.vec2_to_world_pos(Vec2::new(pos.x, pos.z));
+                if let SculptType::Ramp = edit_info.sculpt_info.sculpt_type {
+                    if mouse.just_pressed(MouseButton::Left) {
+                        match edit_info.sculpt_info.ramp_start.take() {
+                            Some(start) => apply_ramp(
+                                &master_terrain,
+                                &mask,
+                                &edit_info.sculpt_info,
+                                &edit_info.symmetry,
+                                start,
+                                pos,
+                            ),
+                            None => edit_info.sculpt_info.ramp_start = Some(pos),
+                        }
+                    }
+                    return;
+                }
+                if let SculptType::Stamp = edit_info.sculpt_info.sculpt_type {
+                    if mouse.just_pressed(MouseButton::Left) {
+                        imprint_height_stamp(
+                            &master_terrain,
+                            &mask,
+                            &edit_info.sculpt_info,
+                            &edit_info.symmetry,
+                            Vec2::new(pos.x, pos.z),
+                        );
+                    }
+                    return;
+                }
                 if edit_info.sculpt_info.brush_info.selected_brush.is_none() {
                     return;
                 }
@@ -57,68 +96,151 @@ fn sculpt(
                         resize_vector(&brush.map, brush.map_size as usize, size as usize);
                     brush.sample_map_size = size;
                 }
-                let sample_map = &edit_info
+                if let SculptType::FlattenPlane = edit_info.sculpt_info.sculpt_type {
+                    if mouse.just_pressed(MouseButton::Left) {
+                        edit_info.sculpt_info.flatten_plane = Some(TerrainPlane::fit(
+                            &master_terrain,
+                            Vec2::new(pos.x, pos.z),
+                            size as f32 * 0.5,
+                        ));
+                    }
+                }
+                let stamps = stroke_stamps(
+                    &mut edit_info.sculpt_info.brush_info,
+                    Vec2::new(pos.x, pos.z),
+                    mouse.just_pressed(MouseButton::Left),
+                    time.delta_seconds(),
+                );
+                let stamps = edit_info.symmetry.apply(stamps);
+                let brush = edit_info
                     .sculpt_info
                     .brush_info
                     .selected_brush
                     .as_ref()
-                    .unwrap()
-                    .sample_map;
-                let strength = edit_info.sculpt_info.brush_info.strength;
+                    .unwrap();
                 let set_height = edit_info.sculpt_info.set_height;
-                let avg_height = match edit_info.sculpt_info.sculpt_type {
-                    SculptType::Smooth => {
-                        let mut avg = 0.0;
-                        let mut avg_div = f32::EPSILON;
-                        for x in 0..size {
-                            for y in 0..size {
-                                let x_f32 = x as f32 - size as f32 * 0.5;
-                                let y_f32 = y as f32 - size as f32 * 0.5;
-                                let brush_sample = sample_map[(x + y * size) as usize];
-                                let world_pos =
-                                    pos + master_terrain.vec2_to_world_pos(Vec2::new(x_f32, y_f32));
-                                avg += master_terrain.get_height(world_pos) * brush_sample;
-                                avg_div += brush_sample;
-                            }
-                        }
-                        Some(avg / avg_div)
+                let noise_hasher = PermutationTable::new(edit_info.sculpt_info.noise.seed);
+                for stamp in stamps {
+                    let pos = master_terrain.vec2_to_world_pos(stamp.pos);
+                    let size = stamp.size;
+                    let strength = stamp.strength;
+                    if let SculptType::Sharpen | SculptType::Pinch | SculptType::Relax =
+                        edit_info.sculpt_info.sculpt_type
+                    {
+                        filter_stamp(
+                            &master_terrain,
+                            &mask,
+                            &edit_info.sculpt_info,
+                            brush,
+                            &stamp,
+                            keys.pressed(KeyCode::ControlLeft),
+                        );
+                        continue;
                     }
-                    _ => None,
-                };
-                for x in 0..size {
-                    for y in 0..size {
-                        let x_f32 = x as f32 - size as f32 * 0.5;
-                        let y_f32 = y as f32 - size as f32 * 0.5;
-                        let brush_sample = sample_map[(x + y * size) as usize];
-                        let world_pos =
-                            pos + master_terrain.vec2_to_world_pos(Vec2::new(x_f32, y_f32));
-                        let delta = if keys.pressed(KeyCode::ControlLeft) {
-                            -time.delta_seconds()
-                        } else {
-                            time.delta_seconds()
-                        };
-                        match &edit_info.sculpt_info.sculpt_type {
-                            SculptType::RaiseLower => {
-                                master_terrain
-                                    .add_height(world_pos, delta * 500.0 * brush_sample * strength);
-                            }
-                            SculptType::SetHeight => {
-                                let current_height = master_terrain.get_height(world_pos);
-                                let diff = set_height - current_height;
-                                let clamped_diff = diff
-                                    .abs()
-                                    .min(500.0 * brush_sample * strength * time.delta_seconds())
-                                    * diff.signum();
-                                master_terrain.set_height(world_pos, current_height + clamped_diff);
+                    let avg_height = match edit_info.sculpt_info.sculpt_type {
+                        SculptType::Smooth => {
+                            let mut avg = 0.0;
+                            let mut avg_div = f32::EPSILON;
+                            for x in 0..size {
+                                for y in 0..size {
+                                    let x_f32 = x as f32 - size as f32 * 0.5;
+                                    let y_f32 = y as f32 - size as f32 * 0.5;
+                                    let brush_sample = stamp.sample(brush, x, y);
+                                    let world_pos = pos
+                                        + master_terrain.vec2_to_world_pos(Vec2::new(x_f32, y_f32));
+                                    avg += master_terrain.get_height(world_pos) * brush_sample;
+                                    avg_div += brush_sample;
+                                }
                             }
-                            SculptType::Smooth => {
-                                let current_height = master_terrain.get_height(world_pos);
-                                let diff = avg_height.unwrap() - current_height;
-                                let clamped_diff = diff
-                                    .abs()
-                                    .min(500.0 * brush_sample * strength * time.delta_seconds())
-                                    * diff.signum();
-                                master_terrain.set_height(world_pos, current_height + clamped_diff);
+                            Some(avg / avg_div)
+                        }
+                        _ => None,
+                    };
+                    for x in 0..size {
+                        for y in 0..size {
+                            let x_f32 = x as f32 - size as f32 * 0.5;
+                            let y_f32 = y as f32 - size as f32 * 0.5;
+                            let world_pos =
+                                pos + master_terrain.vec2_to_world_pos(Vec2::new(x_f32, y_f32));
+                            let brush_sample =
+                                stamp.sample(brush, x, y) * mask.weight(&master_terrain, world_pos);
+                            let delta = if keys.pressed(KeyCode::ControlLeft) {
+                                -stamp.delta_seconds
+                            } else {
+                                stamp.delta_seconds
+                            };
+                            match &edit_info.sculpt_info.sculpt_type {
+                                SculptType::RaiseLower => {
+                                    master_terrain.add_height(
+                                        world_pos,
+                                        delta * 500.0 * brush_sample * strength,
+                                    );
+                                }
+                                SculptType::SetHeight => {
+                                    let current_height = master_terrain.get_height(world_pos);
+                                    let diff = set_height - current_height;
+                                    let clamped_diff = diff
+                                        .abs()
+                                        .min(500.0 * brush_sample * strength * stamp.delta_seconds)
+                                        * diff.signum();
+                                    master_terrain
+                                        .set_height(world_pos, current_height + clamped_diff);
+                                }
+                                SculptType::Stamp
+                                | SculptType::Ramp
+                                | SculptType::Sharpen
+                                | SculptType::Pinch
+                                | SculptType::Relax => {}
+                                SculptType::Noise => {
+                                    let noise = fractal_noise(
+                                        &edit_info.sculpt_info.noise,
+                                        &noise_hasher,
+                                        world_pos,
+                                    );
+                                    master_terrain.add_height(
+                                        world_pos,
+                                        delta * 500.0 * noise * brush_sample * strength,
+                                    );
+                                }
+                                SculptType::FlattenPlane => {
+                                    let plane = match &edit_info.sculpt_info.flatten_plane {
+                                        Some(plane) => plane,
+                                        None => continue,
+                                    };
+                                    let current_height = master_terrain.get_height(world_pos);
+                                    let diff =
+                                        plane.height_at(world_pos.as_vec2()) - current_height;
+                                    let clamped_diff = diff
+                                        .abs()
+                                        .min(500.0 * brush_sample * strength * stamp.delta_seconds)
+                                        * diff.signum();
+                                    master_terrain
+                                        .set_height(world_pos, current_height + clamped_diff);
+                                }
+                                SculptType::Terrace => {
+                                    let current_height = master_terrain.get_height(world_pos);
+                                    let diff = terrace_height(
+                                        &edit_info.sculpt_info.terrace,
+                                        world_pos,
+                                        current_height,
+                                    ) - current_height;
+                                    let clamped_diff = diff
+                                        .abs()
+                                        .min(500.0 * brush_sample * strength * stamp.delta_seconds)
+                                        * diff.signum();
+                                    master_terrain
+                                        .set_height(world_pos, current_height + clamped_diff);
+                                }
+                                SculptType::Smooth => {
+                                    let current_height = master_terrain.get_height(world_pos);
+                                    let diff = avg_height.unwrap() - current_height;
+                                    let clamped_diff = diff
+                                        .abs()
+                                        .min(500.0 * brush_sample * strength * stamp.delta_seconds)
+                                        * diff.signum();
+                                    master_terrain
+                                        .set_height(world_pos, current_height + clamped_diff);
+                                }
                             }
                         }
                     }
@@ -127,3 +249,348 @@ fn sculpt(
         }
     }
 }
+
+fn imprint_height_stamp(
+    master_terrain: &MasterTerrain,
+    mask: &TerrainMask,
+    sculpt_info: &SculptInfo,
+    symmetry: &Symmetry,
+    center: Vec2,
+) {
+    if sculpt_info.stamp.is_none() {
+        return;
+    }
+    for (center, mirror) in symmetry.copies(center) {
+        let base_height = master_terrain.sample_height(center);
+        //the stamp can be rotated, so cover the whole circle around its square
+        let radius = (sculpt_info.brush_info.size as f32 * 0.5 * SQRT_2).ceil() as i32;
+        let center_pos = master_terrain.vec2_to_world_pos(center);
+        for x in -radius..=radius {
+            for y in -radius..=radius {
+                let world_pos = center_pos + IVec2::new(x, y);
+                if let Some(height) = height_stamp_result(
+                    master_terrain,
+                    sculpt_info,
+                    center,
+                    mirror,
+                    base_height,
+                    world_pos,
+                ) {
+                    let current_height = master_terrain.get_height(world_pos);
+                    let weight = mask.weight(master_terrain, world_pos);
+                    master_terrain.set_height(
+                        world_pos,
+                        current_height + (height - current_height) * weight,
+                    );
+                }
+            }
+        }
+    }
+}
+//Height the terrain would have at world_pos after imprinting the selected stamp at center.
+//Symmetric copies pass the transform that maps the stamp onto them, identity otherwise.
+//Returns None if the position is outside of the stamp.
+pub fn height_stamp_result(
+    master_terrain: &MasterTerrain,
+    sculpt_info: &SculptInfo,
+    center: Vec2,
+    mirror: Mat2,
+    base_height: f32,
+    world_pos: IVec2,
+) -> Option<f32> {
+    let stamp = sculpt_info.stamp.as_ref()?;
+    let size = sculpt_info.brush_info.size as f32;
+    //the transform is a rotation or reflection, so its inverse is its transpose
+    let offset = mirror.transpose() * (world_pos.as_vec2() - center) / size;
+    let uv = Mat2::from_angle(-sculpt_info.stamp_rotation.to_radians()) * offset + Vec2::splat(0.5);
+    if uv.x < 0.0 || uv.y < 0.0 || uv.x >= 1.0 || uv.y >= 1.0 {
+        return None;
+    }
+    let relief = (sample_vec(uv.x, uv.y, &stamp.map, stamp.map_size as usize) - stamp.edge_level)
+        * sculpt_info.stamp_height;
+
+    //the brush only masks the stamp, so it isn't rotated with it
+    let mask_uv = offset + Vec2::splat(0.5);
+    let mask = match &sculpt_info.brush_info.selected_brush {
+        Some(brush) if mask_uv.cmpge(Vec2::ZERO).all() && mask_uv.cmplt(Vec2::ONE).all() => {
+            sample_vec(mask_uv.y, mask_uv.x, &brush.map, brush.map_size as usize)
+        }
+        Some(_) => 0.0,
+        None => 1.0,
+    } * sculpt_info.brush_info.strength;
+
+    let current_height = master_terrain.get_height(world_pos);
+    let target_height = match sculpt_info.stamp_blend {
+        StampBlend::Add => current_height + relief,
+        StampBlend::Max => current_height.max(base_height + relief),
+        StampBlend::Min => current_height.min(base_height + relief),
+        StampBlend::Replace => base_height + relief,
+    };
+    Some(current_height + (target_height - current_height) * mask)
+}
+
+//A sloped plane through origin, used to flatten while keeping the existing slope.
+pub struct TerrainPlane {
+    pub origin: Vec3,
+    //height change per unit along x and z
+    pub slope: Vec2,
+}
+impl TerrainPlane {
+    //Least squares fit to the heights in a square around center.
+    //The grid is symmetric, so the x and z slopes can be fitted independently.
+    pub fn fit(master_terrain: &MasterTerrain, center: Vec2, radius: f32) -> Self {
+        let center_pos = master_terrain.vec2_to_world_pos(center);
+        let radius = (radius.ceil() as i32).max(1);
+        let mut height_sum = 0.0;
+        let mut x_sum = 0.0;
+        let mut y_sum = 0.0;
+        let mut offset_sum = 0.0;
+        let mut count = 0.0;
+        for x in -radius..=radius {
+            for y in -radius..=radius {
+                let height = master_terrain.get_height(center_pos + IVec2::new(x, y));
+                height_sum += height;
+                x_sum += x as f32 * height;
+                y_sum += y as f32 * height;
+                offset_sum += (x * x) as f32;
+                count += 1.0;
+            }
+        }
+        //offset_sum is the same for both axes
+        let offset_sum = offset_sum.max(f32::EPSILON);
+        Self {
+            origin: Vec3::new(center_pos.x as f32, height_sum / count, center_pos.y as f32),
+            slope: Vec2::new(x_sum / offset_sum, y_sum / offset_sum),
+        }
+    }
+    pub fn height_at(&self, pos: Vec2) -> f32 {
+        self.origin.y + (pos - Vec2::new(self.origin.x, self.origin.z)).dot(self.slope)
+    }
+}
+
+//Turns the terrain between start and end into a straight slope. Outside of the
+//ramp width the terrain blends back over ramp_falloff. Symmetric copies get their own ramp.
+fn apply_ramp(
+    master_terrain: &MasterTerrain,
+    terrain_mask: &TerrainMask,
+    sculpt_info: &SculptInfo,
+    symmetry: &Symmetry,
+    start: Vec3,
+    end: Vec3,
+) {
+    let starts = symmetry.positions(Vec2::new(start.x, start.z));
+    let ends = symmetry.positions(Vec2::new(end.x, end.z));
+    for (start_2d, end_2d) in starts.into_iter().zip(ends) {
+        ramp_segment(
+            master_terrain,
+            terrain_mask,
+            sculpt_info,
+            (start_2d, start.y),
+            (end_2d, end.y),
+        );
+    }
+}
+fn ramp_segment(
+    master_terrain: &MasterTerrain,
+    terrain_mask: &TerrainMask,
+    sculpt_info: &SculptInfo,
+    (start_2d, start_height): (Vec2, f32),
+    (end_2d, end_height): (Vec2, f32),
+) {
+    let half_width = sculpt_info.ramp_width * 0.5;
+    let reach = half_width + sculpt_info.ramp_falloff;
+    let min = master_terrain.vec2_to_world_pos(start_2d.min(end_2d) - Vec2::splat(reach));
+    let max = master_terrain.vec2_to_world_pos(start_2d.max(end_2d) + Vec2::splat(reach));
+    let path = end_2d - start_2d;
+    let length_squared = path.length_squared().max(f32::EPSILON);
+    for x in min.x..=max.x {
+        for y in min.y..=max.y {
+            let world_pos = IVec2::new(x, y);
+            let pos = world_pos.as_vec2();
+            let t = ((pos - start_2d).dot(path) / length_squared).clamp(0.0, 1.0);
+            let distance = pos.distance(start_2d + path * t);
+            let mask = if distance <= half_width {
+                1.0
+            } else if distance < reach {
+                let fraction = 1.0 - (distance - half_width) / sculpt_info.ramp_falloff;
+                fraction * fraction * (3.0 - 2.0 * fraction)
+            } else {
+                continue;
+            };
+            let mask = mask * terrain_mask.weight(master_terrain, world_pos);
+            let target_height = start_height + (end_height - start_height) * t;
+            let current_height = master_terrain.get_height(world_pos);
+            master_terrain.set_height(
+                world_pos,
+                current_height + (target_height - current_height) * mask,
+            );
+        }
+    }
+}
+
+//Sculpt types that need the heights around each cell. All new heights are
+//computed from the unchanged terrain first, then written back.
+fn filter_stamp(
+    master_terrain: &MasterTerrain,
+    mask: &TerrainMask,
+    sculpt_info: &SculptInfo,
+    brush: &Brush,
+    stamp: &Stamp,
+    inverted: bool,
+) {
+    let pos = master_terrain.vec2_to_world_pos(stamp.pos);
+    let size = stamp.size;
+    let mut new_heights = Vec::new();
+    for x in 0..size {
+        for y in 0..size {
+            let offset = Vec2::new(x as f32 - size as f32 * 0.5, y as f32 - size as f32 * 0.5);
+            let world_pos = pos + master_terrain.vec2_to_world_pos(offset);
+            let amount = (stamp.sample(brush, x, y)
+                * mask.weight(master_terrain, world_pos)
+                * stamp.strength
+                * stamp.delta_seconds
+                * 10.0)
+                .min(1.0);
+            if amount <= 0.0 {
+                continue;
+            }
+            let height = master_terrain.get_height(world_pos);
+            let new_height = match sculpt_info.sculpt_type {
+                SculptType::Sharpen => {
+                    //unsharp mask, push the height away from the local average
+                    let radius = sculpt_info.sharpen_radius as i32;
+                    let blurred = neighbour_average(master_terrain, world_pos, radius, true);
+                    height + (height - blurred) * amount
+                }
+                SculptType::Pinch => {
+                    //a heightfield can't move vertices sideways, so the heights further out
+                    //are sampled inward instead
+                    let pull = if inverted { -amount } else { amount } * 0.5;
+                    let cell = world_pos.as_vec2();
+                    master_terrain.sample_height(cell + (cell - stamp.pos) * pull)
+                }
+                SculptType::Relax => {
+                    let average = neighbour_average(master_terrain, world_pos, 1, false);
+                    height + (average - height) * amount
+                }
+                _ => return,
+            };
+            new_heights.push((world_pos, height, new_height, amount));
+        }
+    }
+    //relax moves the heights toward their neighbours, then removes the overall change
+    //so the area keeps its average height
+    let correction = if let SculptType::Relax = sculpt_info.sculpt_type {
+        let (change, weight) = new_heights.iter().fold(
+            (0.0, 0.0),
+            |(change, weight), (_, height, new_height, amount)| {
+                (change + new_height - height, weight + amount)
+            },
+        );
+        change / f32::max(weight, f32::EPSILON)
+    } else {
+        0.0
+    };
+    for (world_pos, _, new_height, amount) in new_heights {
+        master_terrain.set_height(world_pos, new_height - correction * amount);
+    }
+}
+//Average height of the neighbours at the given distance, optionally including the diagonals.
+fn neighbour_average(
+    master_terrain: &MasterTerrain,
+    world_pos: IVec2,
+    radius: i32,
+    diagonals: bool,
+) -> f32 {
+    let mut offsets = vec![
+        IVec2::X * radius,
+        IVec2::NEG_X * radius,
+        IVec2::Y * radius,
+        IVec2::NEG_Y * radius,
+    ];
+    if diagonals {
+        offsets.extend([
+            IVec2::ONE * radius,
+            IVec2::NEG_ONE * radius,
+            IVec2::new(radius, -radius),
+            IVec2::new(-radius, radius),
+        ]);
+    }
+    offsets
+        .iter()
+        .map(|offset| master_terrain.get_height(world_pos + *offset))
+        .sum::<f32>()
+        / offsets.len() as f32
+}
+
+//Fractal noise in -1..1 sampled in world space, so overlapping strokes line up.
+fn fractal_noise(noise: &SculptNoiseSettings, hasher: &PermutationTable, world_pos: IVec2) -> f32 {
+    let mut sum = 0.0;
+    let mut amplitude = 1.0;
+    let mut amplitude_sum = 0.0;
+    let mut frequency = 1.0 / noise.scale as f64;
+    for _ in 0..noise.octaves {
+        sum += open_simplex_2d(
+            [
+                world_pos.x as f64 * frequency,
+                world_pos.y as f64 * frequency,
+            ],
+            hasher,
+        ) as f32
+            * amplitude;
+        amplitude_sum += amplitude;
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+    sum / amplitude_sum.max(f32::EPSILON)
+}
+
+//Quantizes a height into steps. The noise offsets the steps in world space,
+//so neighbouring strokes line up.
+pub fn terrace_height(terrace: &TerraceSettings, world_pos: IVec2, height: f32) -> f32 {
+    let offset = if terrace.noise > 0.0 {
+        let hasher = PermutationTable::new(0);
+        open_simplex_2d(
+            [
+                world_pos.x as f64 * terrace.noise_scale as f64,
+                world_pos.y as f64 * terrace.noise_scale as f64,
+            ],
+            &hasher,
+        ) as f32
+            * terrace.noise
+            * terrace.step_height
+    } else {
+        0.0
+    };
+    let steps = (height + offset) / terrace.step_height;
+    let step = steps.floor();
+    let edge_width = (1.0 - terrace.sharpness).max(0.001);
+    let fraction = ((steps - step - (1.0 - edge_width)) / edge_width).clamp(0.0, 1.0);
+    let fraction = fraction * fraction * (3.0 - 2.0 * fraction);
+    (step + fraction) * terrace.step_height - offset
+}
+fn terrace_filter(
+    mut edit_info: ResMut<EditInfo>,
+    master_terrain: Res<MasterTerrain>,
+    mask: Res<TerrainMask>,
+) {
+    if !edit_info.sculpt_info.terrace_whole_terrain {
+        return;
+    }
+    edit_info.sculpt_info.terrace_whole_terrain = false;
+    if !master_terrain.loaded {
+        return;
+    }
+    let chunk_size = master_terrain.chunk_size as i32;
+    for chunk_pos in master_terrain.chunks.keys() {
+        for y in 0..chunk_size {
+            for x in 0..chunk_size {
+                let world_pos = *chunk_pos * chunk_size + IVec2::new(x, y);
+                let height = master_terrain.get_height(world_pos);
+                let terraced = terrace_height(&edit_info.sculpt_info.terrace, world_pos, height);
+                let weight = mask.weight(&master_terrain, world_pos);
+                master_terrain.set_height(world_pos, height + (terraced - height) * weight);
+            }
+        }
+    }
+}
@@ -1,9 +1,4 @@
-use std::{
-    collections::HashMap,
-    fs::File,
-    io::{Read, Write},
-    path::PathBuf,
-};
+use std::{collections::HashMap, fs, path::PathBuf};
 
 use bevy::{
     prelude::*,
@@ -15,8 +10,14 @@ use bevy::{
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    details::DetailModel,
+    detail_instancing::{ModelLod, ModelLods},
+    details::{spawn_detail, DetailModel},
+    ground_cover::{GroundCover, GroundCoverSettings},
+    mask::TerrainMask,
+    scatter::{Scatter, ScatterRule},
+    splines::{Splines, TerrainSpline},
     terrain::{LODLevel, MasterTerrain, LOD},
+    water::{Lake, Water},
 };
 
 pub struct SerializePlugin;
@@ -46,6 +47,11 @@ impl Serializer {
         self.deserialize_path = Some(path);
     }
 }
+//Saved files start with these bytes and the version of the layout of TerrainData. Files
+//without them are from before the layout had a version.
+const FILE_MAGIC: &[u8; 4] = b"MFTR";
+const FORMAT_VERSION: u32 = 1;
+
 #[derive(Serialize, Deserialize)]
 pub struct TerrainData {
     pub chunk_size: usize,
@@ -55,6 +61,22 @@ pub struct TerrainData {
     pub chunks: Vec<ChunkData>,
 
     pub details: Vec<DetailData>,
+    //details of removed chunks, restored when the chunk is added again
+    pub removed_details: Vec<DetailData>,
+
+    pub splines: Vec<TerrainSpline>,
+
+    pub model_lods: HashMap<String, ModelLod>,
+
+    pub scatter_rules: Vec<ScatterRule>,
+    pub scatter_seed: u32,
+
+    pub ground_cover: HashMap<IVec2, Vec<f32>>,
+    pub ground_cover_settings: GroundCoverSettings,
+
+    pub sea_enabled: bool,
+    pub sea_level: f32,
+    pub lakes: Vec<Lake>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -66,8 +88,119 @@ pub struct ChunkData {
 #[derive(Serialize, Deserialize)]
 pub struct DetailData {
     pub name: String,
-    pub chunk_pos: IVec2,
-    pub local_pos: UVec2,
+    pub pos: Vec2,
+    pub rotation: Quat,
+    pub scale: Vec3,
+    pub scattered: bool,
+}
+impl DetailData {
+    fn new(detail_model: &DetailModel) -> Self {
+        Self {
+            name: detail_model.name.clone(),
+            pos: detail_model.pos,
+            rotation: detail_model.rotation,
+            scale: detail_model.scale,
+            scattered: detail_model.scattered,
+        }
+    }
+    fn into_model(self, master_terrain: &MasterTerrain) -> DetailModel {
+        DetailModel {
+            name: self.name,
+            chunk_pos: master_terrain.chunk_pos_from_vec2(&self.pos),
+            pos: self.pos,
+            rotation: self.rotation,
+            scale: self.scale,
+            scattered: self.scattered,
+        }
+    }
+}
+
+//The layout of files saved before details could be rotated and scaled. Details were
+//stored by chunk with a whole unit position inside it.
+#[derive(Deserialize)]
+struct LegacyTerrainData {
+    chunk_size: usize,
+    texture_size: usize,
+    lod: Vec<LODLevel>,
+    chunks: Vec<ChunkData>,
+    details: Vec<LegacyDetailData>,
+}
+#[derive(Deserialize)]
+struct LegacyDetailData {
+    name: String,
+    chunk_pos: IVec2,
+    local_pos: UVec2,
+}
+//every detail was drawn at this scale
+const LEGACY_DETAIL_SCALE: f32 = 0.05;
+impl From<LegacyTerrainData> for TerrainData {
+    fn from(legacy: LegacyTerrainData) -> Self {
+        let chunk_size = legacy.chunk_size as i32;
+        let details = legacy
+            .details
+            .into_iter()
+            .map(|detail| DetailData {
+                name: detail.name,
+                pos: (detail.chunk_pos * chunk_size + detail.local_pos.as_ivec2()).as_vec2(),
+                rotation: Quat::IDENTITY,
+                scale: Vec3::splat(LEGACY_DETAIL_SCALE),
+                scattered: false,
+            })
+            .collect();
+        Self {
+            chunk_size: legacy.chunk_size,
+            texture_size: legacy.texture_size,
+            lod: legacy.lod,
+            chunks: legacy.chunks,
+            details,
+            removed_details: Vec::new(),
+            splines: Vec::new(),
+            model_lods: HashMap::new(),
+            scatter_rules: Vec::new(),
+            scatter_seed: 0,
+            ground_cover: HashMap::new(),
+            ground_cover_settings: GroundCoverSettings::default(),
+            sea_enabled: false,
+            sea_level: 0.0,
+            lakes: Vec::new(),
+        }
+    }
+}
+
+fn encode(data: &TerrainData) -> Result<Vec<u8>, String> {
+    let mut bytes = FILE_MAGIC.to_vec();
+    bytes.extend(FORMAT_VERSION.to_le_bytes());
+    bytes.extend(bincode::serialize(data).map_err(|err| err.to_string())?);
+    Ok(bytes)
+}
+fn decode(bytes: &[u8]) -> Result<TerrainData, String> {
+    let data = match bytes.strip_prefix(FILE_MAGIC) {
+        Some(versioned) if versioned.len() >= 4 => {
+            let (version, rest) = versioned.split_at(4);
+            match u32::from_le_bytes([version[0], version[1], version[2], version[3]]) {
+                FORMAT_VERSION => bincode::deserialize(rest).map_err(|err| err.to_string())?,
+                version => return Err(format!("unknown format version {version}")),
+            }
+        }
+        Some(_) => return Err("the file is truncated".to_string()),
+        None => bincode::deserialize::<LegacyTerrainData>(bytes)
+            .map_err(|err| err.to_string())?
+            .into(),
+    };
+    check_chunks(&data)?;
+    Ok(data)
+}
+//The chunk textures are turned into images as they are, so their size has to fit.
+fn check_chunks(data: &TerrainData) -> Result<(), String> {
+    let texture_len = data.texture_size * data.texture_size * 4;
+    match data
+        .chunks
+        .iter()
+        .find(|chunk| chunk.texture.len() != texture_len)
+    {
+        Some(chunk) => Err(format!("the texture of chunk {} is damaged", chunk.pos)),
+        None => Ok(()),
+    }
 }
 
 fn serialize(
@@ -75,6 +208,11 @@ fn serialize(
     master_terrain: Res<MasterTerrain>,
     images: Res<Assets<Image>>,
     detail_models: Query<&DetailModel>,
+    splines: Res<Splines>,
+    model_lods: Res<ModelLods>,
+    scatter: Res<Scatter>,
+    ground_cover: Res<GroundCover>,
+    water: Res<Water>,
 ) {
     if let Some(path) = &serializer.serialize_path {
         let mut chunks = HashMap::new();
@@ -101,29 +239,39 @@ fn serialize(
             }
         }
         let chunks = chunks.values().cloned().collect();
-        let mut details = Vec::new();
-        for detail_model in &detail_models {
-            details.push(DetailData {
-                name: detail_model.name.clone(),
-                chunk_pos: detail_model.chunk_pos,
-                local_pos: detail_model.local_pos,
-            })
-        }
+        let details = detail_models.iter().map(DetailData::new).collect();
+        let removed_details = master_terrain
+            .removed_details
+            .values()
+            .flatten()
+            .map(DetailData::new)
+            .collect();
         let data = TerrainData {
             chunk_size: master_terrain.chunk_size,
             texture_size: master_terrain.texture_size,
             lod: master_terrain.lod.levels.clone(),
             chunks,
             details,
+            removed_details,
+            splines: splines.splines.clone(),
+            model_lods: model_lods.models.clone(),
+            scatter_rules: scatter.rules.clone(),
+            scatter_seed: scatter.seed,
+            ground_cover: ground_cover.chunks.clone(),
+            ground_cover_settings: ground_cover.settings.clone(),
+            sea_enabled: water.sea_enabled,
+            sea_level: water.sea_level,
+            lakes: water.lakes.clone(),
         };
-        // Serialize the data to binary using bincode
-        let serialized = bincode::serialize(&data).expect("Serialization failed");
-
-        // Write the serialized data to a file
         let final_path = path.with_extension("mf");
-        let mut file = File::create(final_path).expect("Failed to create file");
-        file.write_all(&serialized)
-            .expect("Failed to write to file");
+        if let Err(err) = encode(&data)
+            .and_then(|bytes| fs::write(&final_path, bytes).map_err(|err| err.to_string()))
+        {
+            error!(
+                "Failed to save the terrain to {}: {err}",
+                final_path.display()
+            );
+        }
     }
     serializer.serialize_path = None;
 }
@@ -132,16 +280,25 @@ fn deserialize(
     mut master_terrain: ResMut<MasterTerrain>,
     mut images: ResMut<Assets<Image>>,
     mut commands: Commands,
-    asset_server: Res<AssetServer>,
+    mut splines: ResMut<Splines>,
+    mut mask: ResMut<TerrainMask>,
+    mut model_lods: ResMut<ModelLods>,
+    mut scatter: ResMut<Scatter>,
+    mut ground_cover: ResMut<GroundCover>,
+    mut water: ResMut<Water>,
 ) {
-    if let Some(path) = &serializer.deserialize_path {
-        let mut file = File::open(path).expect("Failed to open file");
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer)
-            .expect("Failed to read from file");
-
-        // Deserialize the data back into your data structure
-        let data: TerrainData = bincode::deserialize(&buffer).expect("Deserialization failed");
+    if let Some(path) = serializer.deserialize_path.take() {
+        let data = match fs::read(&path)
+            .map_err(|err| err.to_string())
+            .and_then(|bytes| decode(&bytes))
+        {
+            Ok(data) => data,
+            //the current terrain stays as it is
+            Err(err) => {
+                error!("Failed to load the terrain from {}: {err}", path.display());
+                return;
+            }
+        };
 
         master_terrain.reset();
         master_terrain.chunk_size = data.chunk_size;
@@ -174,26 +331,25 @@ fn deserialize(
                 .insert(chunk_data.pos, handle.clone());
         }
         for detail in data.details {
-            let world_pos = detail.local_pos.as_ivec2() + detail.chunk_pos * data.chunk_size as i32;
-            let translation = Vec3::new(world_pos.x as f32, 0.0, world_pos.y as f32);
-            let id = commands
-                .spawn((
-                    SceneBundle {
-                        scene: asset_server.load(format!("models/{}#Scene0", detail.name)),
-                        transform: Transform::from_translation(translation)
-                            .with_scale(Vec3::ONE * 0.05),
-                        ..Default::default()
-                    },
-                    DetailModel {
-                        name: detail.name,
-                        chunk_pos: detail.chunk_pos,
-                        local_pos: detail.local_pos,
-                    },
-                ))
-                .id();
-            master_terrain.details.insert(world_pos, id);
+            let pos = detail.pos;
+            let detail_model = detail.into_model(&master_terrain);
+            let id = spawn_detail(&mut commands, &master_terrain, detail_model);
+            master_terrain.details.insert(id, pos);
+        }
+        for detail in data.removed_details {
+            let detail_model = detail.into_model(&master_terrain);
+            master_terrain
+                .removed_details
+                .entry(detail_model.chunk_pos)
+                .or_default()
+                .push(detail_model);
         }
+        splines.reset(data.splines);
+        mask.clear();
+        model_lods.models = data.model_lods;
+        scatter.reset(data.scatter_rules, data.scatter_seed);
+        ground_cover.reset(data.ground_cover, data.ground_cover_settings);
+        water.reset(data.sea_enabled, data.sea_level, data.lakes);
         master_terrain.loaded = true;
     }
-    serializer.deserialize_path = None;
 }
@@ -0,0 +1,456 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use bevy_mod_raycast::deferred::RaycastSource;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    draw::{lerp_color, sample_repeating},
+    mask::TerrainMask,
+    resize_vector,
+    terrain::MasterTerrain,
+    ui::{EditInfo, EditMode, UiHovered},
+};
+
+pub struct SplinesPlugin;
+impl Plugin for SplinesPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Splines::default())
+            .add_systems(Update, (edit_splines, apply_splines, draw_splines));
+    }
+}
+
+//how close the cursor has to be to a control point to grab it
+const POINT_PICK_RADIUS: f32 = 3.0;
+const POINT_RADIUS: f32 = 0.8;
+//the curve is sampled roughly once per world unit
+const SAMPLES_PER_UNIT: f32 = 1.0;
+const LINE_OFFSET: f32 = 0.3;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub enum SplineKind {
+    Road,
+    River,
+}
+impl ToString for SplineKind {
+    fn to_string(&self) -> String {
+        match self {
+            SplineKind::Road => "Road",
+            SplineKind::River => "River",
+        }
+        .to_string()
+    }
+}
+
+//A Catmull-Rom spline through control points that sit on the terrain.
+//It only changes the terrain when it's applied, so it can be edited and applied again.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TerrainSpline {
+    pub kind: SplineKind,
+    pub points: Vec<Vec3>,
+    pub width: f32,
+    //distance over which the terrain blends back outside of the width
+    pub falloff: f32,
+    //how deep a river channel is carved below the spline
+    pub depth: f32,
+    //heights the last apply replaced, restored before applying again so an edited
+    //spline replaces its old shape instead of stacking on top of it
+    base_heights: HashMap<IVec2, f32>,
+    //texture pixels the last apply painted over, by chunk and byte index
+    base_pixels: HashMap<IVec2, HashMap<usize, [u8; 4]>>,
+}
+impl TerrainSpline {
+    pub fn new(kind: SplineKind) -> Self {
+        let (width, falloff) = match kind {
+            SplineKind::Road => (6.0, 4.0),
+            SplineKind::River => (8.0, 6.0),
+        };
+        Self {
+            kind,
+            points: Vec::new(),
+            width,
+            falloff,
+            depth: 4.0,
+            base_heights: HashMap::new(),
+            base_pixels: HashMap::new(),
+        }
+    }
+    //Points along the curve. The end points are repeated so the curve passes through them.
+    pub fn sample(&self) -> Vec<Vec3> {
+        if self.points.len() < 2 {
+            return self.points.clone();
+        }
+        let last = self.points.len() - 1;
+        let mut samples = Vec::new();
+        for i in 0..last {
+            let p0 = self.points[i.saturating_sub(1)];
+            let p1 = self.points[i];
+            let p2 = self.points[i + 1];
+            let p3 = self.points[(i + 2).min(last)];
+            let steps = ((p2 - p1).length() * SAMPLES_PER_UNIT).ceil().max(1.0) as usize;
+            for step in 0..steps {
+                samples.push(catmull_rom(p0, p1, p2, p3, step as f32 / steps as f32));
+            }
+        }
+        samples.push(self.points[last]);
+        samples
+    }
+    //Terrain height at pos without this spline's last apply, so moving points onto
+    //the channel it carved doesn't make the next apply carve deeper.
+    fn ground_height(&self, master_terrain: &MasterTerrain, pos: Vec2) -> f32 {
+        match self
+            .base_heights
+            .get(&master_terrain.vec2_to_world_pos(pos))
+        {
+            Some(height) => *height,
+            None => master_terrain.sample_height(pos),
+        }
+    }
+    //Puts back the terrain the last apply changed.
+    fn restore(&mut self, master_terrain: &MasterTerrain, images: &mut Assets<Image>) {
+        for (world_pos, height) in self.base_heights.drain() {
+            master_terrain.set_height(world_pos, height);
+        }
+        for (chunk_pos, pixels) in self.base_pixels.drain() {
+            let image = match master_terrain
+                .texture_map
+                .textures
+                .get(&chunk_pos)
+                .and_then(|handle| images.get_mut(handle))
+            {
+                Some(image) => image,
+                None => continue,
+            };
+            for (pixel_index, color) in pixels {
+                image.data[pixel_index..pixel_index + 4].copy_from_slice(&color);
+            }
+        }
+    }
+    fn reach(&self) -> f32 {
+        self.width * 0.5 + self.falloff
+    }
+    //Height the terrain should have at pos, and how strongly it's pulled toward it.
+    fn target_height(
+        &self,
+        distance: f32,
+        curve_height: f32,
+        current_height: f32,
+    ) -> Option<(f32, f32)> {
+        let half_width = self.width * 0.5;
+        if distance >= self.reach() {
+            return None;
+        }
+        let mask = if distance <= half_width {
+            1.0
+        } else {
+            let fraction = 1.0 - (distance - half_width) / self.falloff.max(f32::EPSILON);
+            fraction * fraction * (3.0 - 2.0 * fraction)
+        };
+        let target = match self.kind {
+            SplineKind::Road => curve_height,
+            SplineKind::River => {
+                //parabolic channel profile, rivers only carve
+                let across = (distance / half_width.max(f32::EPSILON)).min(1.0);
+                (curve_height - self.depth * (1.0 - across * across)).min(current_height)
+            }
+        };
+        Some((target, mask))
+    }
+}
+fn catmull_rom(p0: Vec3, p1: Vec3, p2: Vec3, p3: Vec3, t: f32) -> Vec3 {
+    let t2 = t * t;
+    let t3 = t2 * t;
+    0.5 * (2.0 * p1
+        + (p2 - p0) * t
+        + (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * t2
+        + (3.0 * p1 - p0 - 3.0 * p2 + p3) * t3)
+}
+//Horizontal distance to the closest point of the segment and the height there.
+fn closest_on_segment(a: Vec3, b: Vec3, pos: Vec2) -> (f32, f32) {
+    let a_2d = Vec2::new(a.x, a.z);
+    let segment = Vec2::new(b.x, b.z) - a_2d;
+    let t =
+        ((pos - a_2d).dot(segment) / segment.length_squared().max(f32::EPSILON)).clamp(0.0, 1.0);
+    (pos.distance(a_2d + segment * t), a.y + (b.y - a.y) * t)
+}
+//Distance to the closest point of the polyline and the height there, for every cell of a
+//grid that is within reach of it. Only the corridor around each segment is walked, not
+//the bounds of the whole polyline.
+fn cells_in_reach(samples: &[Vec3], reach: f32, cell_size: f32) -> HashMap<IVec2, (f32, f32)> {
+    let mut cells: HashMap<IVec2, (f32, f32)> = HashMap::new();
+    for index in 0..samples.len().saturating_sub(1).max(1) {
+        let (a, b) = (samples[index], samples[(index + 1).min(samples.len() - 1)]);
+        let (a_2d, b_2d) = (Vec2::new(a.x, a.z), Vec2::new(b.x, b.z));
+        let min = ((a_2d.min(b_2d) - reach) / cell_size).floor().as_ivec2();
+        let max = ((a_2d.max(b_2d) + reach) / cell_size).ceil().as_ivec2();
+        for x in min.x..=max.x {
+            for y in min.y..=max.y {
+                let cell = IVec2::new(x, y);
+                let (distance, height) = closest_on_segment(a, b, cell.as_vec2() * cell_size);
+                if distance >= reach {
+                    continue;
+                }
+                let closest = cells.entry(cell).or_insert((distance, height));
+                if distance < closest.0 {
+                    *closest = (distance, height);
+                }
+            }
+        }
+    }
+    cells
+}
+
+#[derive(Resource, Default)]
+pub struct Splines {
+    pub splines: Vec<TerrainSpline>,
+    pub selected: Option<usize>,
+    //spline and point index of the control point being dragged
+    dragging: Option<(usize, usize)>,
+    apply: Option<usize>,
+    //deleted splines whose change to the terrain still has to be undone
+    deleted: Vec<TerrainSpline>,
+}
+impl Splines {
+    pub fn add(&mut self, kind: SplineKind) {
+        self.splines.push(TerrainSpline::new(kind));
+        self.selected = Some(self.splines.len() - 1);
+    }
+    pub fn remove(&mut self, index: usize) {
+        self.deleted.push(self.splines.remove(index));
+        self.selected = None;
+        self.dragging = None;
+    }
+    pub fn apply(&mut self, index: usize) {
+        self.apply = Some(index);
+    }
+    pub fn reset(&mut self, splines: Vec<TerrainSpline>) {
+        *self = Self {
+            splines,
+            ..Default::default()
+        };
+    }
+    fn point_at(&self, pos: Vec2) -> Option<(usize, usize)> {
+        let mut closest = None;
+        let mut closest_distance = POINT_PICK_RADIUS;
+        for (spline_index, spline) in self.splines.iter().enumerate() {
+            for (point_index, point) in spline.points.iter().enumerate() {
+                let distance = pos.distance(Vec2::new(point.x, point.z));
+                if distance < closest_distance {
+                    closest = Some((spline_index, point_index));
+                    closest_distance = distance;
+                }
+            }
+        }
+        closest
+    }
+}
+
+//Click to add a point to the selected spline, drag points to move them, Ctrl click removes them.
+fn edit_splines(
+    mut splines: ResMut<Splines>,
+    edit_info: Res<EditInfo>,
+    raycast_source: Query<&RaycastSource<()>>,
+    master_terrain: Res<MasterTerrain>,
+    mouse: Res<Input<MouseButton>>,
+    keys: Res<Input<KeyCode>>,
+    ui_hovered: Res<UiHovered>,
+) {
+    if !master_terrain.loaded {
+        return;
+    }
+    if mouse.just_released(MouseButton::Left) {
+        splines.dragging = None;
+    }
+    if let EditMode::Splines = &edit_info.edit_mode {
+        let raycast_source = raycast_source.single();
+        let (_, intersection) = if let Some(intersection) = raycast_source.intersections().first() {
+            intersection
+        } else {
+            return;
+        };
+        let pos = intersection.position();
+        let pos_2d = Vec2::new(pos.x, pos.z);
+        let snapped = |spline: &TerrainSpline| {
+            Vec3::new(pos.x, spline.ground_height(&master_terrain, pos_2d), pos.z)
+        };
+
+        if mouse.just_pressed(MouseButton::Left) && !ui_hovered.0 {
+            match splines.point_at(pos_2d) {
+                Some((spline_index, point_index)) => {
+                    if keys.pressed(KeyCode::ControlLeft) {
+                        splines.splines[spline_index].points.remove(point_index);
+                    } else {
+                        splines.dragging = Some((spline_index, point_index));
+                    }
+                    splines.selected = Some(spline_index);
+                }
+                None => {
+                    if let Some(selected) = splines.selected {
+                        let point = snapped(&splines.splines[selected]);
+                        splines.splines[selected].points.push(point);
+                    }
+                }
+            }
+        }
+        if let Some((spline_index, point_index)) = splines.dragging {
+            if mouse.pressed(MouseButton::Left) {
+                let point = snapped(&splines.splines[spline_index]);
+                splines.splines[spline_index].points[point_index] = point;
+            }
+        }
+    }
+}
+
+fn apply_splines(
+    mut splines: ResMut<Splines>,
+    mut edit_info: ResMut<EditInfo>,
+    master_terrain: Res<MasterTerrain>,
+    mut images: ResMut<Assets<Image>>,
+    terrain_mask: Res<TerrainMask>,
+) {
+    for mut spline in splines.deleted.drain(..) {
+        spline.restore(&master_terrain, &mut images);
+    }
+    let index = if let Some(index) = splines.apply.take() {
+        index
+    } else {
+        return;
+    };
+    let spline = if let Some(spline) = splines.splines.get_mut(index) {
+        spline
+    } else {
+        return;
+    };
+    spline.restore(&master_terrain, &mut images);
+    let samples = spline.sample();
+    if samples.is_empty() {
+        return;
+    }
+    for (world_pos, (distance, curve_height)) in cells_in_reach(&samples, spline.reach(), 1.0) {
+        let current_height = master_terrain.get_height(world_pos);
+        if let Some((target, mask)) = spline.target_height(distance, curve_height, current_height) {
+            let mask = mask * terrain_mask.weight(&master_terrain, world_pos);
+            spline.base_heights.insert(world_pos, current_height);
+            master_terrain.set_height(world_pos, current_height + (target - current_height) * mask);
+        }
+    }
+
+    //roads are painted with the selected draw texture
+    if spline.kind != SplineKind::Road {
+        return;
+    }
+    let scale = edit_info.draw_info.draw_texture_info.scale;
+    let texture = if let Some(texture) = edit_info
+        .draw_info
+        .draw_texture_info
+        .selected_texture
+        .as_mut()
+    {
+        texture
+    } else {
+        return;
+    };
+    if texture.sample_map_size != scale {
+        texture.sample_map = resize_vector(&texture.map, texture.map_size as usize, scale as usize);
+        texture.sample_map_size = scale;
+    }
+    let p_per_tile = master_terrain.pixels_per_tile() as f32;
+    //the texture fades out over the outer part of the road only, not the banks
+    let half_width = spline.width * 0.5;
+    let mut pixels: HashMap<IVec2, Vec<(Color, usize)>> = HashMap::new();
+    for (pixel_pos, (distance, _)) in cells_in_reach(&samples, half_width, 1.0 / p_per_tile) {
+        let pos = pixel_pos.as_vec2() / p_per_tile;
+        let fade = (half_width * 0.2).max(f32::EPSILON);
+        let alpha = ((half_width - distance) / fade).clamp(0.0, 1.0);
+        let alpha = alpha * terrain_mask.weight(&master_terrain, (pos.floor()).as_ivec2());
+        if alpha <= 0.0 {
+            continue;
+        }
+        let color = sample_repeating(
+            pixel_pos.x,
+            pixel_pos.y,
+            &texture.sample_map,
+            scale as usize,
+        );
+        let chunk_pos = master_terrain.pixel_to_chunk_pos(pixel_pos);
+        let local_pixel_pos =
+            master_terrain.pixel_to_local_pixel_pos_with_chunk(pixel_pos, chunk_pos);
+        let pixel_index = (local_pixel_pos.x * 4
+            + local_pixel_pos.y * master_terrain.texture_size as u32 * 4)
+            as usize;
+        pixels
+            .entry(chunk_pos)
+            .or_default()
+            .push((color.with_a(alpha), pixel_index));
+    }
+    for (chunk_pos, pixels) in pixels {
+        let image = match master_terrain
+            .texture_map
+            .textures
+            .get(&chunk_pos)
+            .and_then(|handle| images.get_mut(handle))
+        {
+            Some(image) => image,
+            None => continue,
+        };
+        let base_pixels = spline.base_pixels.entry(chunk_pos).or_default();
+        for (wanted_color, pixel_index) in pixels {
+            let mut base_color = [0; 4];
+            base_color.copy_from_slice(&image.data[pixel_index..pixel_index + 4]);
+            base_pixels.entry(pixel_index).or_insert(base_color);
+            let current_color = Color::rgb_u8(
+                image.data[pixel_index + 0],
+                image.data[pixel_index + 1],
+                image.data[pixel_index + 2],
+            );
+            let calculated_color =
+                lerp_color(&current_color, &wanted_color, wanted_color.a()).with_a(1.0);
+            for (i, b) in calculated_color.as_rgba_u8().into_iter().enumerate() {
+                image.data[pixel_index + i] = b;
+            }
+        }
+    }
+}
+
+fn draw_splines(
+    splines: Res<Splines>,
+    edit_info: Res<EditInfo>,
+    master_terrain: Res<MasterTerrain>,
+    mut gizmos: Gizmos,
+) {
+    if !master_terrain.loaded {
+        return;
+    }
+    if let EditMode::Splines = &edit_info.edit_mode {
+        for (index, spline) in splines.splines.iter().enumerate() {
+            let selected = splines.selected == Some(index);
+            let color = match spline.kind {
+                SplineKind::Road => Color::ORANGE,
+                SplineKind::River => Color::BLUE,
+            };
+            let offset = Vec3::Y * LINE_OFFSET;
+            let samples = spline.sample();
+            gizmos.linestrip(samples.iter().map(|point| *point + offset), color);
+            if selected {
+                //show the corridor the spline will change
+                for side in [-1.0, 1.0] {
+                    let edge = samples.windows(2).map(|pair| {
+                        let direction = Vec2::new(pair[1].x - pair[0].x, pair[1].z - pair[0].z)
+                            .normalize_or_zero();
+                        let normal = Vec3::new(-direction.y, 0.0, direction.x);
+                        pair[0] + normal * spline.width * 0.5 * side + offset
+                    });
+                    gizmos.linestrip(edge, color.with_a(0.5));
+                }
+            }
+            for point in &spline.points {
+                gizmos.sphere(
+                    *point + offset,
+                    Quat::IDENTITY,
+                    POINT_RADIUS,
+                    if selected { Color::YELLOW } else { color },
+                );
+            }
+        }
+    }
+}
@@ -19,6 +19,8 @@ use noise::{
 };
 use serde::{Deserialize, Serialize};
 
+use crate::details::{spawn_detail, DetailIndex, DetailModel};
+
 pub struct TerrainPlugin;
 impl Plugin for TerrainPlugin {
     fn build(&self, app: &mut App) {
@@ -81,7 +83,9 @@ pub struct MasterTerrain {
     chunk_destroy_queue: Vec<IVec2>,
     update_positions: Mutex<HashMap<IVec2, UpdateChunk>>,
 
-    pub details: HashMap<IVec2, Entity>,
+    pub details: DetailIndex,
+    //details of removed chunks, they come back when the chunk is added again
+    pub removed_details: HashMap<IVec2, Vec<DetailModel>>,
 
     delete_entities: Vec<Entity>,
 }
@@ -101,7 +105,8 @@ impl MasterTerrain {
             chunk_destroy_queue: Vec::new(),
             update_positions: Mutex::new(HashMap::new()),
 
-            details: HashMap::new(),
+            details: DetailIndex::default(),
+            removed_details: HashMap::new(),
 
             delete_entities: Vec::new(),
         }
@@ -111,8 +116,8 @@ impl MasterTerrain {
         for (ent, _) in self.chunks.values() {
             delete_entities.push(*ent);
         }
-        for ent in self.details.values() {
-            delete_entities.push(*ent);
+        for ent in self.details.entities() {
+            delete_entities.push(ent);
         }
         *self = Self::unloaded();
         self.delete_entities = delete_entities;
@@ -443,6 +448,26 @@ impl MasterTerrain {
     pub fn add_height(&self, world_pos: IVec2, value: f32) {
         self.set_height(world_pos, self.get_height(world_pos) + value);
     }
+    //bilinearly interpolated height at an arbitrary (non-integer) position
+    pub fn sample_height(&self, pos: Vec2) -> f32 {
+        let floor = pos.floor();
+        let fraction = pos - floor;
+        let floor = floor.as_ivec2();
+
+        let top_left = self.get_height(floor);
+        let top_right = self.get_height(floor + IVec2::X);
+        let bottom_left = self.get_height(floor + IVec2::Y);
+        let bottom_right = self.get_height(floor + IVec2::ONE);
+
+        let top = top_left + (top_right - top_left) * fraction.x;
+        let bottom = bottom_left + (bottom_right - bottom_left) * fraction.x;
+        top + (bottom - top) * fraction.y
+    }
+    pub fn sample_normal(&self, pos: Vec2) -> Vec3 {
+        let dx = self.sample_height(pos + Vec2::X) - self.sample_height(pos - Vec2::X);
+        let dy = self.sample_height(pos + Vec2::Y) - self.sample_height(pos - Vec2::Y);
+        Vec3::new(-dx, 2.0, -dy).normalize()
+    }
     fn mesh_size(&self, lod: usize) -> usize {
         self.chunk_size / (2_u32.pow(lod as u32)) as usize
     }
@@ -540,12 +565,24 @@ fn gen_terrain_chunks(
         master_terrain
             .chunks
             .insert(chunk_pos, (entity, mesh_entities));
+        if let Some(removed_details) = master_terrain.removed_details.remove(&chunk_pos) {
+            for detail_model in removed_details {
+                let pos = detail_model.pos;
+                let id = spawn_detail(&mut commands, &master_terrain, detail_model);
+                master_terrain.details.insert(id, pos);
+            }
+        }
         //let terrain_chunk = TerrainChunk { entity, heightmap };
     }
     master_terrain.chunk_spawn_queue.clear();
 }
 
-fn destroy_terrain_chunks(mut master_terrain: ResMut<MasterTerrain>, mut commands: Commands) {
+fn destroy_terrain_chunks(
+    mut master_terrain: ResMut<MasterTerrain>,
+    mut commands: Commands,
+    details: Query<&DetailModel>,
+) {
+    let chunk_size = master_terrain.chunk_size as f32;
     for chunk_pos in master_terrain.chunk_destroy_queue.clone() {
         if master_terrain.chunk_count() == 1 {
             //Don't delete the single remaining chunk,
@@ -555,6 +592,25 @@ fn destroy_terrain_chunks(mut master_terrain: ResMut<MasterTerrain>, mut command
         if let Some(chunk) = master_terrain.chunks.get(&chunk_pos) {
             commands.entity(chunk.0).despawn_recursive();
             master_terrain.chunks.remove(&chunk_pos);
+
+            //keep the details of the chunk aside instead of leaving them over empty space
+            let min = chunk_pos.as_vec2() * chunk_size;
+            let mut removed_details = Vec::new();
+            for (entity, pos) in master_terrain.details.in_rect(min, min + chunk_size) {
+                if master_terrain.chunk_pos_from_vec2(&pos) != chunk_pos {
+                    continue;
+                }
+                if let Ok(detail_model) = details.get(entity) {
+                    removed_details.push(detail_model.clone());
+                }
+                master_terrain.details.remove(entity, pos);
+                commands.entity(entity).despawn_recursive();
+            }
+            if !removed_details.is_empty() {
+                master_terrain
+                    .removed_details
+                    .insert(chunk_pos, removed_details);
+            }
         }
     }
     master_terrain.chunk_destroy_queue.clear();
@@ -1,7 +1,6 @@
-use std::path::Path;
-
 use bevy::{
     asset::LoadedFolder,
+    ecs::system::SystemParam,
     prelude::*,
 };
 use bevy_egui::{
@@ -11,10 +10,25 @@ use bevy_egui::{
     EguiContexts, EguiPlugin,
 };
 use bevy_inspector_egui::egui;
+use serde::{Deserialize, Serialize};
 
 use crate::{
+    brush::{
+        BrushShape, FalloffCurve, ProceduralBrush, RotationMode, StrokeSettings, Symmetry,
+        PROCEDURAL_BRUSH_ID,
+    },
+    brush_library::{brush_map_from_image, is_displayable, BrushLibrary},
+    detail_instancing::ModelLods,
+    detail_selection::{DetailSelection, GizmoMode, SelectionAction},
+    ground_cover::GroundCover,
+    mask::{MaskAction, TerrainMask},
+    model_library::ModelLibrary,
+    scatter::{Scatter, ScatterRegion},
+    sculpt::TerrainPlane,
     serialize::Serializer,
+    splines::{SplineKind, Splines},
     terrain::{LODLevel, MasterTerrain},
+    water::Water,
     VERSION,
 };
 
@@ -123,11 +137,17 @@ pub struct EditInfo {
     pub brushes: Handle<LoadedFolder>,
     pub textures: Handle<LoadedFolder>,
     pub models: Handle<LoadedFolder>,
+    pub stamps: Handle<LoadedFolder>,
 
     pub sculpt_info: SculptInfo,
     pub edit_chunks_info: EditChunksInfo,
     pub draw_info: DrawInfo,
     pub details_info: DetailsInfo,
+    pub mask_info: MaskInfo,
+    pub ground_cover_info: GroundCoverInfo,
+
+    //shared by the sculpt, draw and detail brushes
+    pub symmetry: Symmetry,
 }
 impl Default for EditInfo {
     fn default() -> Self {
@@ -140,11 +160,16 @@ impl Default for EditInfo {
             brushes: Handle::default(),
             textures: Handle::default(),
             models: Handle::default(),
+            stamps: Handle::default(),
 
             sculpt_info: SculptInfo::default(),
             edit_chunks_info: EditChunksInfo::default(),
             draw_info: DrawInfo::default(),
             details_info: DetailsInfo::default(),
+            mask_info: MaskInfo::default(),
+            ground_cover_info: GroundCoverInfo::default(),
+
+            symmetry: Symmetry::default(),
         }
     }
 }
@@ -153,6 +178,14 @@ pub enum SculptType {
     RaiseLower,
     SetHeight,
     Smooth,
+    Stamp,
+    Terrace,
+    FlattenPlane,
+    Ramp,
+    Noise,
+    Sharpen,
+    Pinch,
+    Relax,
 }
 impl ToString for SculptType {
     fn to_string(&self) -> String {
@@ -160,6 +193,14 @@ impl ToString for SculptType {
             SculptType::RaiseLower => "Raise/lower terrain",
             SculptType::SetHeight => "Set terrain height",
             SculptType::Smooth => "Smooth terrain",
+            SculptType::Stamp => "Stamp heightmap",
+            SculptType::Terrace => "Terrace terrain",
+            SculptType::FlattenPlane => "Flatten to plane",
+            SculptType::Ramp => "Ramp",
+            SculptType::Noise => "Noise",
+            SculptType::Sharpen => "Sharpen",
+            SculptType::Pinch => "Pinch",
+            SculptType::Relax => "Relax",
         }
         .to_string()
     }
@@ -172,6 +213,7 @@ impl Default for SculptType {
 pub struct DetailsInfo {
     pub selected_detail_name: String,
     pub selected_detail: Handle<Scene>,
+    pub placement: DetailPlacement,
     pub brush_info: BrushInfo,
 }
 impl Default for DetailsInfo {
@@ -179,19 +221,155 @@ impl Default for DetailsInfo {
         Self {
             selected_detail_name: String::new(),
             selected_detail: Handle::default(),
+            placement: DetailPlacement::default(),
             brush_info: BrushInfo::default(),
         }
     }
 }
+//How each placed detail is randomized.
+pub struct DetailPlacement {
+    pub variation: DetailVariation,
+
+    //random offset inside the grid cell, 1 allows the whole cell
+    pub position_jitter: f32,
+    //painted details keep at least this distance to all others
+    pub min_spacing: f32,
+}
+impl Default for DetailPlacement {
+    fn default() -> Self {
+        Self {
+            variation: DetailVariation::default(),
+
+            position_jitter: 1.0,
+            min_spacing: 1.0,
+        }
+    }
+}
+//Random rotation and scale of a detail. Scatter rules keep their own, so applying them
+//doesn't depend on the brush settings.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DetailVariation {
+    pub random_yaw: bool,
+    //times the default scale of the model
+    pub min_scale: f32,
+    pub max_scale: f32,
+
+    pub align_to_normal: bool,
+    //in degrees
+    pub max_tilt: f32,
+}
+impl Default for DetailVariation {
+    fn default() -> Self {
+        Self {
+            random_yaw: true,
+            min_scale: 0.8,
+            max_scale: 1.2,
+
+            align_to_normal: false,
+            max_tilt: 20.0,
+        }
+    }
+}
+pub struct MaskInfo {
+    pub brush_info: BrushInfo,
+
+    pub min_height: f32,
+    pub max_height: f32,
+    //in degrees
+    pub min_slope: f32,
+    pub max_slope: f32,
+    pub feather_radius: u32,
+    //how far the shoreline reaches to both sides of the waterline
+    pub shore_width: u32,
+
+    pub action: Option<MaskAction>,
+}
+impl Default for MaskInfo {
+    fn default() -> Self {
+        Self {
+            brush_info: BrushInfo::default(),
+
+            min_height: 0.0,
+            max_height: 100.0,
+            min_slope: 0.0,
+            max_slope: 30.0,
+            feather_radius: 2,
+            shore_width: 4,
+
+            action: None,
+        }
+    }
+}
+pub struct GroundCoverInfo {
+    pub brush_info: BrushInfo,
+}
+impl Default for GroundCoverInfo {
+    fn default() -> Self {
+        Self {
+            brush_info: BrushInfo::default(),
+        }
+    }
+}
+#[derive(PartialEq, Clone)]
+pub enum DrawType {
+    Paint,
+    SolidColor,
+    Erase,
+    Blur,
+    Smudge,
+    ColorAdjust,
+}
+impl ToString for DrawType {
+    fn to_string(&self) -> String {
+        match self {
+            DrawType::Paint => "Paint texture",
+            DrawType::SolidColor => "Paint solid colour",
+            DrawType::Erase => "Erase",
+            DrawType::Blur => "Blur",
+            DrawType::Smudge => "Smudge",
+            DrawType::ColorAdjust => "Adjust colour",
+        }
+        .to_string()
+    }
+}
+impl Default for DrawType {
+    fn default() -> Self {
+        Self::Paint
+    }
+}
 pub struct DrawInfo {
+    pub draw_type: DrawType,
     pub draw_texture_info: DrawTextureInfo,
     pub brush_info: BrushInfo,
+
+    pub paint_color: Color,
+    pub tint_texture: bool,
+    pub eyedropper: bool,
+    pub erase_color: Color,
+    pub blur_radius: u32,
+    pub hue_shift: f32,
+    pub saturation: f32,
+    pub brightness: f32,
+
+    pub last_pixel_pos: Option<IVec2>,
 }
 impl Default for DrawInfo {
     fn default() -> Self {
         Self {
+            draw_type: DrawType::default(),
             draw_texture_info: DrawTextureInfo::default(),
             brush_info: BrushInfo::default(),
+
+            paint_color: Color::WHITE,
+            tint_texture: false,
+            eyedropper: false,
+            erase_color: Color::WHITE,
+            blur_radius: 2,
+            hue_shift: 0.0,
+            saturation: 1.0,
+            brightness: 1.0,
+
+            last_pixel_pos: None,
         }
     }
 }
@@ -201,6 +379,26 @@ pub struct SculptInfo {
     pub set_height: f32,
     pub auto_height: bool,
 
+    pub stamp: Option<HeightStamp>,
+    pub stamp_height: f32,
+    pub stamp_rotation: f32,
+    pub stamp_blend: StampBlend,
+
+    pub terrace: TerraceSettings,
+    pub terrace_whole_terrain: bool,
+
+    pub noise: SculptNoiseSettings,
+
+    //distance of the neighbours that sharpen compares against
+    pub sharpen_radius: u32,
+
+    //fitted at the start of a flatten stroke
+    pub flatten_plane: Option<TerrainPlane>,
+
+    pub ramp_start: Option<Vec3>,
+    pub ramp_width: f32,
+    pub ramp_falloff: f32,
+
     pub brush_info: BrushInfo,
 }
 impl Default for SculptInfo {
@@ -209,10 +407,104 @@ impl Default for SculptInfo {
             set_height: 100.0,
             auto_height: false,
             sculpt_type: SculptType::default(),
+
+            stamp: None,
+            stamp_height: 30.0,
+            stamp_rotation: 0.0,
+            stamp_blend: StampBlend::Add,
+
+            terrace: TerraceSettings::default(),
+            terrace_whole_terrain: false,
+
+            noise: SculptNoiseSettings::default(),
+
+            sharpen_radius: 2,
+
+            flatten_plane: None,
+
+            ramp_start: None,
+            ramp_width: 10.0,
+            ramp_falloff: 5.0,
+
             brush_info: BrushInfo::default(),
         }
     }
 }
+pub struct TerraceSettings {
+    pub step_height: f32,
+    //0 gives smooth slopes between the steps, 1 gives vertical cliffs
+    pub sharpness: f32,
+    pub noise: f32,
+    pub noise_scale: f32,
+}
+impl Default for TerraceSettings {
+    fn default() -> Self {
+        Self {
+            step_height: 10.0,
+            sharpness: 0.8,
+            noise: 0.0,
+            noise_scale: 0.05,
+        }
+    }
+}
+pub struct SculptNoiseSettings {
+    //size of the largest noise features in world units
+    pub scale: f32,
+    pub octaves: u32,
+    pub seed: u32,
+}
+impl Default for SculptNoiseSettings {
+    fn default() -> Self {
+        Self {
+            scale: 20.0,
+            octaves: 4,
+            seed: 0,
+        }
+    }
+}
+#[derive(PartialEq, Clone)]
+pub enum StampBlend {
+    Add,
+    Max,
+    Min,
+    Replace,
+}
+impl ToString for StampBlend {
+    fn to_string(&self) -> String {
+        match self {
+            StampBlend::Add => "Add",
+            StampBlend::Max => "Max",
+            StampBlend::Min => "Min",
+            StampBlend::Replace => "Replace",
+        }
+        .to_string()
+    }
+}
+//A heightmap image that defines the relief imprinted by the stamp tool.
+pub struct HeightStamp {
+    //path of the stamp image, it stays the same when other stamps are added
+    pub name: String,
+    pub map: Vec<f32>,
+    pub map_size: u32,
+    //average value along the border, this is treated as ground level
+    pub edge_level: f32,
+}
+impl HeightStamp {
+    pub fn new(name: String, map: Vec<f32>, map_size: u32) -> Self {
+        let size = map_size as usize;
+        let mut edge_sum = 0.0;
+        for i in 0..size {
+            edge_sum +=
+                map[i] + map[i + (size - 1) * size] + map[i * size] + map[size - 1 + i * size];
+        }
+        Self {
+            name,
+            map,
+            map_size,
+            edge_level: edge_sum / (size * 4) as f32,
+        }
+    }
+}
 #[derive(PartialEq, Clone)]
 pub enum EditChunksAction {
     Remove,
@@ -238,13 +530,23 @@ pub enum EditMode {
     Sculpt,
     Draw,
     EditDetails,
+    Splines,
+    Mask,
+    SelectDetails,
+    GroundCover,
+    Water,
 }
-pub const EDIT_MODES: [EditMode; 5] = [
+pub const EDIT_MODES: [EditMode; 10] = [
     EditMode::View,
     EditMode::EditChunks,
     EditMode::Sculpt,
     EditMode::Draw,
     EditMode::EditDetails,
+    EditMode::Splines,
+    EditMode::Mask,
+    EditMode::SelectDetails,
+    EditMode::GroundCover,
+    EditMode::Water,
 ];
 impl ToString for EditMode {
     fn to_string(&self) -> String {
@@ -254,6 +556,11 @@ impl ToString for EditMode {
             EditMode::Sculpt => "Sculpt terrain",
             EditMode::Draw => "Draw textures",
             EditMode::EditDetails => "Edit details",
+            EditMode::Splines => "Roads and rivers",
+            EditMode::Mask => "Edit mask",
+            EditMode::SelectDetails => "Select details",
+            EditMode::GroundCover => "Paint grass",
+            EditMode::Water => "Water",
         }
         .to_string()
     }
@@ -262,10 +569,25 @@ fn load_assets(asset_server: Res<AssetServer>, mut edit_info: ResMut<EditInfo>)
     edit_info.brushes = asset_server.load_folder("brushes");
     edit_info.textures = asset_server.load_folder("textures");
     edit_info.models = asset_server.load_folder("models");
+    edit_info.stamps = asset_server.load_folder("stamps");
 }
 #[derive(Resource)]
 pub struct UiHovered(pub bool);
 
+//The painted layers, grouped to stay within the system parameter limit.
+#[derive(SystemParam)]
+pub struct TerrainLayers<'w> {
+    mask: ResMut<'w, TerrainMask>,
+    ground_cover: ResMut<'w, GroundCover>,
+}
+#[derive(SystemParam)]
+pub struct DetailTools<'w> {
+    model_lods: ResMut<'w, ModelLods>,
+    scatter: ResMut<'w, Scatter>,
+    detail_selection: ResMut<'w, DetailSelection>,
+    model_library: ResMut<'w, ModelLibrary>,
+}
+
 pub fn update_egui(
     mut contexts: EguiContexts,
     time: Res<Time>,
@@ -277,7 +599,18 @@ pub fn update_egui(
     q_windows: Query<&Window>,
     mut serializer: ResMut<Serializer>,
     mut master_terrain: ResMut<MasterTerrain>,
+    mut brush_library: ResMut<BrushLibrary>,
+    mut splines: ResMut<Splines>,
+    mut layers: TerrainLayers,
+    detail_tools: DetailTools,
+    mut water: ResMut<Water>,
 ) {
+    let DetailTools {
+        mut model_lods,
+        mut scatter,
+        mut detail_selection,
+        mut model_library,
+    } = detail_tools;
     let mouse = q_windows.single().cursor_position().unwrap_or(Vec2::ZERO);
     let mouse = Pos2::new(mouse.x, mouse.y);
     ui_hovered.0 = false;
@@ -380,6 +713,13 @@ pub fn update_egui(
                         edit_info.new_terrain.texture_size,
                         edit_info.new_terrain.quality.to_lod(),
                     );
+                    //nothing of the previous terrain carries over into the new one
+                    layers.mask.clear();
+                    layers.ground_cover.clear();
+                    splines.reset(Vec::new());
+                    model_lods.models.clear();
+                    scatter.reset(Vec::new(), 0);
+                    water.reset(false, 0.0, Vec::new());
                     edit_info.new_terrain.active = false;
                 }
             })
@@ -405,15 +745,12 @@ pub fn update_egui(
         .response;
     ui_hovered.0 = response.rect.contains(mouse) || ui_hovered.0;
     let mut brush_texture_ids = Vec::new();
-    if let Some(brushes_folder) = loaded_folders.get(&edit_info.brushes) {
-        for handle in &brushes_folder.handles {
-            if images.get(handle).is_some() {
-                if let Some(id) = contexts.image_id(&handle.clone().typed()) {
-                    brush_texture_ids.push((id, handle.clone().typed::<Image>()));
-                } else {
-                    contexts.add_image(handle.clone().typed());
-                }
-            }
+    for entry in brush_library.visible() {
+        let preview = brush_library.previews[&entry.id].clone();
+        if let Some(id) = contexts.image_id(&preview) {
+            brush_texture_ids.push((id, entry.clone()));
+        } else {
+            contexts.add_image(preview);
         }
     }
     let mut draw_texture_ids = Vec::new();
@@ -428,45 +765,213 @@ pub fn update_egui(
             }
         }
     }
-    let mut models: Vec<String> = Vec::new();
-    if let Some(models_folder) = loaded_folders.get(&edit_info.models) {
-        for handle in &models_folder.handles {
-            if let Some(path) = asset_server.get_path(handle.id()) {
-                let path = path.to_string();
-                let path = Path::new(&path);
-                models.push(path.file_name().unwrap().to_str().unwrap().to_string());
+    let mut stamp_ids = Vec::new();
+    if let Some(stamps_folder) = loaded_folders.get(&edit_info.stamps) {
+        for handle in &stamps_folder.handles {
+            let handle = handle.clone().typed::<Image>();
+            let name = match asset_server.get_path(handle.id()) {
+                Some(path) => path.to_string(),
+                None => continue,
+            };
+            if let Some(image) = images.get(&handle) {
+                //16-bit heightmaps can't be shown by egui, they are listed by name instead
+                if !is_displayable(image) {
+                    stamp_ids.push((None, name, handle));
+                } else if let Some(id) = contexts.image_id(&handle) {
+                    stamp_ids.push((Some(id), name, handle));
+                } else {
+                    contexts.add_image(handle);
+                }
             }
         }
     }
+    let mut model_texture_ids = Vec::new();
+    for name in model_library.visible() {
+        //models are listed by name until their thumbnail has been generated
+        let texture_id = match model_library.thumbnails.get(name) {
+            Some(thumbnail) => {
+                let texture_id = contexts.image_id(thumbnail);
+                if texture_id.is_none() {
+                    contexts.add_image(thumbnail.clone());
+                }
+                texture_id
+            }
+            None => None,
+        };
+        model_texture_ids.push((texture_id, name.clone()));
+    }
     let brushes = |ui: &mut Ui, brush_info: &mut BrushInfo| {
-        ui.label("Brushes");
+        ui.horizontal(|ui| {
+            ui.label("Brushes");
+            if ui.small_button("Import").clicked() {
+                let path = std::env::current_dir().unwrap();
+                let res = rfd::FileDialog::new()
+                    .set_directory(path)
+                    .add_filter("png", &["png"])
+                    .pick_file();
+                if let Some(path) = res {
+                    brush_library.import(path, &asset_server);
+                }
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("Filter by tag:");
+            ui.text_edit_singleline(&mut brush_library.tag_filter);
+        });
         ui.horizontal_wrapped(|ui| {
-            for (i, (texture_id, handle)) in brush_texture_ids.into_iter().enumerate() {
-                if ui
+            for (texture_id, entry) in brush_texture_ids.into_iter() {
+                let response = ui
                     .add(
                         ImageButton::new(egui::load::SizedTexture::new(
                             texture_id,
                             egui::vec2(40., 40.),
                         ))
                         .selected(match &brush_info.selected_brush {
-                            Some(brush) => i == brush.id,
+                            Some(brush) => entry.id == brush.id,
                             None => false,
                         }),
                     )
+                    .on_hover_text(if entry.tags.is_empty() {
+                        entry.name.clone()
+                    } else {
+                        format!("{} ({})", entry.name, entry.tags.join(", "))
+                    });
+                if response.clicked() {
+                    let should_update = match &brush_info.selected_brush {
+                        Some(brush) => entry.id != brush.id,
+                        None => true,
+                    };
+                    let image = images.get(&brush_library.images[&entry.id]).unwrap();
+                    if should_update {
+                        //unreadable brushes aren't listed, so this only fails if the file changed
+                        if let Some((map, map_size)) = brush_map_from_image(image) {
+                            brush_info.selected_brush = Some(Brush {
+                                id: entry.id,
+                                map,
+                                map_size,
+                                sample_map: Vec::new(),
+                                sample_map_size: 0,
+                            });
+                        }
+                    }
+                }
+                response.context_menu(|ui| brush_context_menu(ui, &mut brush_library, entry.id));
+            }
+        });
+        ui.collapsing("Procedural brush", |ui| {
+            let procedural = &mut brush_info.procedural;
+            let mut changed = false;
+            egui::ComboBox::from_label("Shape")
+                .selected_text(procedural.shape.to_string())
+                .show_ui(ui, |ui| {
+                    for shape in [BrushShape::Circle, BrushShape::Square] {
+                        changed |= ui
+                            .selectable_value(
+                                &mut procedural.shape,
+                                shape.clone(),
+                                shape.to_string(),
+                            )
+                            .changed();
+                    }
+                });
+            ui.label("Hardness:");
+            changed |= ui
+                .add(Slider::new(&mut procedural.hardness, 0.0..=1.0))
+                .changed();
+            ui.label("Falloff:");
+            changed |= falloff_curve_editor(ui, &mut procedural.falloff);
+            ui.label("Edge noise:");
+            changed |= ui
+                .add(Slider::new(&mut procedural.noise_amount, 0.0..=1.0))
+                .changed();
+            if procedural.noise_amount > 0.0 {
+                ui.horizontal(|ui| {
+                    ui.label("Scale:");
+                    changed |= ui
+                        .add(DragValue::new(&mut procedural.noise_scale).speed(0.1))
+                        .changed();
+                    ui.label("Seed:");
+                    changed |= ui.add(DragValue::new(&mut procedural.noise_seed)).changed();
+                });
+            }
+            let selected = match &brush_info.selected_brush {
+                Some(brush) => brush.id == PROCEDURAL_BRUSH_ID,
+                None => false,
+            };
+            if ui
+                .selectable_label(selected, "Use procedural brush")
+                .clicked()
+                || (selected && changed)
+            {
+                brush_info.selected_brush = Some(brush_info.procedural.to_brush());
+            }
+        });
+        ui.label("Strength:");
+        ui.add(Slider::new(&mut brush_info.strength, 0.0..=1.0));
+        ui.label("Size:");
+        ui.add(Slider::new(&mut brush_info.size, 1..=200));
+        ui.collapsing("Stroke", |ui| {
+            let stroke = &mut brush_info.stroke;
+            ui.label("Spacing (% of size, 0 applies every frame):");
+            ui.add(Slider::new(&mut stroke.spacing, 0.0..=200.0));
+            egui::ComboBox::from_label("Rotation")
+                .selected_text(stroke.rotation_mode.to_string())
+                .show_ui(ui, |ui| {
+                    for rotation_mode in [
+                        RotationMode::Fixed,
+                        RotationMode::FollowStroke,
+                        RotationMode::Random,
+                    ] {
+                        ui.selectable_value(
+                            &mut stroke.rotation_mode,
+                            rotation_mode.clone(),
+                            rotation_mode.to_string(),
+                        );
+                    }
+                });
+            if stroke.rotation_mode != RotationMode::Random {
+                ui.label("Angle:");
+                ui.add(Slider::new(&mut stroke.rotation, -180.0..=180.0));
+            }
+            ui.label("Position jitter:");
+            ui.add(Slider::new(&mut stroke.position_jitter, 0.0..=1.0));
+            ui.label("Size jitter:");
+            ui.add(Slider::new(&mut stroke.size_jitter, 0.0..=1.0));
+            ui.label("Strength jitter:");
+            ui.add(Slider::new(&mut stroke.strength_jitter, 0.0..=1.0));
+        });
+    };
+    let draw_textures = |ui: &mut Ui, draw_texture_info: &mut DrawTextureInfo| {
+        ui.label("Textures");
+        ui.horizontal_wrapped(|ui| {
+            for (i, (texture_id, handle)) in draw_texture_ids.into_iter().enumerate() {
+                if ui
+                    .add(
+                        ImageButton::new(egui::load::SizedTexture::new(
+                            texture_id,
+                            egui::vec2(40., 40.),
+                        ))
+                        .selected(
+                            match &draw_texture_info.selected_texture {
+                                Some(texture) => i == texture.id,
+                                None => false,
+                            },
+                        ),
+                    )
                     .clicked()
                 {
-                    let should_update = match &brush_info.selected_brush {
-                        Some(brush) => i != brush.id,
+                    let should_update = match &draw_texture_info.selected_texture {
+                        Some(texture) => i != texture.id,
                         None => true,
                     };
                     if should_update {
                         let image = images.get(handle).unwrap();
                         let mut map = Vec::new();
                         for b in image.data.chunks(4) {
-                            map.push(b[0] as f32 / 255.0);
+                            map.push(Color::rgba_u8(b[0], b[1], b[2], b[3]));
                         }
 
-                        brush_info.selected_brush = Some(Brush {
+                        draw_texture_info.selected_texture = Some(DrawTexture {
                             id: i,
                             map,
                             map_size: image.width(),
@@ -477,10 +982,63 @@ pub fn update_egui(
                 }
             }
         });
-        ui.label("Strength:");
-        ui.add(Slider::new(&mut brush_info.strength, 0.0..=1.0));
-        ui.label("Size:");
-        ui.add(Slider::new(&mut brush_info.size, 1..=200));
+        ui.label("Scale:");
+        ui.horizontal(|ui| {
+            ui.add(DragValue::new(&mut draw_texture_info.scale).clamp_range(1..=usize::MAX));
+        });
+        ui.checkbox(&mut draw_texture_info.triplanar, "Triplanar on slopes");
+        if draw_texture_info.triplanar {
+            ui.label("Slope threshold (degrees):");
+            ui.add(Slider::new(
+                &mut draw_texture_info.triplanar_angle,
+                0.0..=90.0,
+            ));
+        }
+    };
+    let paint_color = |ui: &mut Ui, draw_info: &mut DrawInfo| {
+        ui.horizontal(|ui| {
+            ui.label("Colour:");
+            let mut color = draw_info.paint_color.as_rgba_f32();
+            ui.color_edit_button_rgba_unmultiplied(&mut color);
+            draw_info.paint_color = Color::rgba(color[0], color[1], color[2], color[3]);
+            ui.toggle_value(&mut draw_info.eyedropper, "Eyedropper");
+        });
+        ui.label("Hold Alt and click to pick a colour from the terrain.");
+    };
+    let stamps = |ui: &mut Ui, sculpt_info: &mut SculptInfo| {
+        ui.label("Stamps");
+        ui.horizontal_wrapped(|ui| {
+            for (texture_id, name, handle) in stamp_ids {
+                let selected = match &sculpt_info.stamp {
+                    Some(stamp) => name == stamp.name,
+                    None => false,
+                };
+                let response = match texture_id {
+                    Some(texture_id) => ui
+                        .add(
+                            ImageButton::new(egui::load::SizedTexture::new(
+                                texture_id,
+                                egui::vec2(40., 40.),
+                            ))
+                            .selected(selected),
+                        )
+                        .on_hover_text(name.clone()),
+                    None => ui.selectable_label(selected, name.clone()),
+                };
+                if response.clicked() && !selected {
+                    let image = images.get(handle).unwrap();
+                    match brush_map_from_image(image) {
+                        Some((map, map_size)) => {
+                            sculpt_info.stamp = Some(HeightStamp::new(name, map, map_size))
+                        }
+                        None => error!(
+                            "Stamp {name} has an unsupported format {:?}",
+                            image.texture_descriptor.format
+                        ),
+                    }
+                }
+            }
+        });
     };
     let response = egui::Window::new(edit_info.edit_mode.to_string())
         .anchor(Align2::RIGHT_TOP, bevy_egui::egui::Vec2::new(-10.0, 165.0))
@@ -519,8 +1077,49 @@ pub fn update_egui(
                             SculptType::Smooth,
                             SculptType::Smooth.to_string(),
                         );
+                        ui.selectable_value(
+                            &mut edit_info.sculpt_info.sculpt_type,
+                            SculptType::Stamp,
+                            SculptType::Stamp.to_string(),
+                        );
+                        ui.selectable_value(
+                            &mut edit_info.sculpt_info.sculpt_type,
+                            SculptType::Terrace,
+                            SculptType::Terrace.to_string(),
+                        );
+                        ui.selectable_value(
+                            &mut edit_info.sculpt_info.sculpt_type,
+                            SculptType::FlattenPlane,
+                            SculptType::FlattenPlane.to_string(),
+                        );
+                        ui.selectable_value(
+                            &mut edit_info.sculpt_info.sculpt_type,
+                            SculptType::Ramp,
+                            SculptType::Ramp.to_string(),
+                        );
+                        ui.selectable_value(
+                            &mut edit_info.sculpt_info.sculpt_type,
+                            SculptType::Noise,
+                            SculptType::Noise.to_string(),
+                        );
+                        ui.selectable_value(
+                            &mut edit_info.sculpt_info.sculpt_type,
+                            SculptType::Sharpen,
+                            SculptType::Sharpen.to_string(),
+                        );
+                        ui.selectable_value(
+                            &mut edit_info.sculpt_info.sculpt_type,
+                            SculptType::Pinch,
+                            SculptType::Pinch.to_string(),
+                        );
+                        ui.selectable_value(
+                            &mut edit_info.sculpt_info.sculpt_type,
+                            SculptType::Relax,
+                            SculptType::Relax.to_string(),
+                        );
                     });
                 brushes(ui, &mut edit_info.sculpt_info.brush_info);
+                symmetry_settings(ui, &mut edit_info.symmetry);
                 match edit_info.sculpt_info.sculpt_type {
                     SculptType::RaiseLower => {}
                     SculptType::SetHeight => {
@@ -534,79 +1133,776 @@ pub fn update_egui(
                         });
                     }
                     SculptType::Smooth => {}
+                    SculptType::Stamp => {
+                        stamps(ui, &mut edit_info.sculpt_info);
+                        ui.label("Height:");
+                        ui.add(DragValue::new(&mut edit_info.sculpt_info.stamp_height));
+                        ui.label("Rotation:");
+                        ui.add(Slider::new(
+                            &mut edit_info.sculpt_info.stamp_rotation,
+                            -180.0..=180.0,
+                        ));
+                        egui::ComboBox::from_label("Blend mode")
+                            .selected_text(edit_info.sculpt_info.stamp_blend.to_string())
+                            .show_ui(ui, |ui| {
+                                for blend in [
+                                    StampBlend::Add,
+                                    StampBlend::Max,
+                                    StampBlend::Min,
+                                    StampBlend::Replace,
+                                ] {
+                                    ui.selectable_value(
+                                        &mut edit_info.sculpt_info.stamp_blend,
+                                        blend.clone(),
+                                        blend.to_string(),
+                                    );
+                                }
+                            });
+                        ui.label("The selected brush masks the stamp edges. Click to imprint.");
+                    }
+                    SculptType::Terrace => {
+                        let terrace = &mut edit_info.sculpt_info.terrace;
+                        ui.label("Step height:");
+                        ui.add(
+                            DragValue::new(&mut terrace.step_height).clamp_range(0.1..=f32::MAX),
+                        );
+                        ui.label("Edge sharpness:");
+                        ui.add(Slider::new(&mut terrace.sharpness, 0.0..=1.0));
+                        ui.label("Noise:");
+                        ui.add(Slider::new(&mut terrace.noise, 0.0..=1.0));
+                        if terrace.noise > 0.0 {
+                            ui.label("Noise scale:");
+                            ui.add(DragValue::new(&mut terrace.noise_scale).speed(0.001));
+                        }
+                        if ui.button("Apply to whole terrain").clicked() {
+                            edit_info.sculpt_info.terrace_whole_terrain = true;
+                        }
+                    }
+                    SculptType::FlattenPlane => {
+                        ui.label(
+                            "Flattens toward the slope under the brush where the stroke starts.",
+                        );
+                    }
+                    SculptType::Noise => {
+                        let noise = &mut edit_info.sculpt_info.noise;
+                        ui.label("Scale:");
+                        ui.add(DragValue::new(&mut noise.scale).clamp_range(1.0..=f32::MAX));
+                        ui.label("Octaves:");
+                        ui.add(Slider::new(&mut noise.octaves, 1..=8));
+                        ui.label("Seed:");
+                        ui.add(DragValue::new(&mut noise.seed));
+                        ui.label("Hold Ctrl to invert the noise.");
+                    }
+                    SculptType::Sharpen => {
+                        ui.label("Radius:");
+                        ui.add(Slider::new(
+                            &mut edit_info.sculpt_info.sharpen_radius,
+                            1..=8,
+                        ));
+                    }
+                    SculptType::Pinch => {
+                        ui.label("Pulls the terrain toward the brush centre.");
+                        ui.label("Hold Ctrl to push it outward.");
+                    }
+                    SculptType::Relax => {
+                        ui.label("Evens out bumps without lowering or raising the area.");
+                    }
+                    SculptType::Ramp => {
+                        ui.label("Width:");
+                        ui.add(
+                            DragValue::new(&mut edit_info.sculpt_info.ramp_width)
+                                .clamp_range(1.0..=f32::MAX),
+                        );
+                        ui.label("Falloff:");
+                        ui.add(
+                            DragValue::new(&mut edit_info.sculpt_info.ramp_falloff)
+                                .clamp_range(0.0..=f32::MAX),
+                        );
+                        if edit_info.sculpt_info.ramp_start.is_some() {
+                            ui.label("Click the end of the ramp, Escape cancels.");
+                        } else {
+                            ui.label("Click the start of the ramp.");
+                        }
+                    }
                 }
             }
             EditMode::Draw => {
-                ui.label("Textures");
+                egui::ComboBox::from_label("Select draw type")
+                    .selected_text(format!("{}", edit_info.draw_info.draw_type.to_string()))
+                    .show_ui(ui, |ui| {
+                        for draw_type in [
+                            DrawType::Paint,
+                            DrawType::SolidColor,
+                            DrawType::Erase,
+                            DrawType::Blur,
+                            DrawType::Smudge,
+                            DrawType::ColorAdjust,
+                        ] {
+                            ui.selectable_value(
+                                &mut edit_info.draw_info.draw_type,
+                                draw_type.clone(),
+                                draw_type.to_string(),
+                            );
+                        }
+                    });
+                match edit_info.draw_info.draw_type {
+                    DrawType::Paint => {
+                        draw_textures(ui, &mut edit_info.draw_info.draw_texture_info);
+                        ui.checkbox(&mut edit_info.draw_info.tint_texture, "Tint texture");
+                        if edit_info.draw_info.tint_texture {
+                            paint_color(ui, &mut edit_info.draw_info);
+                        }
+                    }
+                    DrawType::SolidColor => paint_color(ui, &mut edit_info.draw_info),
+                    DrawType::Erase => {
+                        ui.horizontal(|ui| {
+                            ui.label("Base colour:");
+                            let mut color = edit_info.draw_info.erase_color.as_rgba_f32();
+                            ui.color_edit_button_rgba_unmultiplied(&mut color);
+                            edit_info.draw_info.erase_color =
+                                Color::rgba(color[0], color[1], color[2], color[3]);
+                        });
+                    }
+                    DrawType::Blur => {
+                        ui.label("Blur radius (pixels):");
+                        ui.add(Slider::new(&mut edit_info.draw_info.blur_radius, 1..=16));
+                    }
+                    DrawType::Smudge => {}
+                    DrawType::ColorAdjust => {
+                        ui.label("Hue shift:");
+                        ui.add(Slider::new(
+                            &mut edit_info.draw_info.hue_shift,
+                            -180.0..=180.0,
+                        ));
+                        ui.label("Saturation:");
+                        ui.add(Slider::new(&mut edit_info.draw_info.saturation, 0.0..=2.0));
+                        ui.label("Brightness:");
+                        ui.add(Slider::new(&mut edit_info.draw_info.brightness, 0.0..=2.0));
+                    }
+                }
+                brushes(ui, &mut edit_info.draw_info.brush_info);
+                symmetry_settings(ui, &mut edit_info.symmetry);
+            }
+            EditMode::EditDetails => {
+                ui.label("Models");
+                ui.horizontal(|ui| {
+                    ui.label("Filter by tag:");
+                    ui.text_edit_singleline(&mut model_library.tag_filter);
+                });
                 ui.horizontal_wrapped(|ui| {
-                    for (i, (texture_id, handle)) in draw_texture_ids.into_iter().enumerate() {
-                        if ui
-                            .add(
+                    for (texture_id, name) in model_texture_ids {
+                        let selected = edit_info.details_info.selected_detail_name == name;
+                        let response = match texture_id {
+                            Some(texture_id) => ui.add(
                                 ImageButton::new(egui::load::SizedTexture::new(
                                     texture_id,
-                                    egui::vec2(40., 40.),
+                                    egui::vec2(48., 48.),
                                 ))
-                                .selected(
-                                    match &edit_info.draw_info.draw_texture_info.selected_texture {
-                                        Some(texture) => i == texture.id,
-                                        None => false,
-                                    },
-                                ),
+                                .selected(selected),
+                            ),
+                            None => ui.selectable_label(selected, &name),
+                        };
+                        let tags = model_library.get(&name).tags;
+                        let response = response.on_hover_text(if tags.is_empty() {
+                            name.clone()
+                        } else {
+                            format!("{} ({})", name, tags.join(", "))
+                        });
+                        if response.clicked() {
+                            edit_info.details_info.selected_detail_name = name;
+                        }
+                    }
+                });
+                ui.collapsing("Placement", |ui| {
+                    let placement = &mut edit_info.details_info.placement;
+                    detail_variation_settings(ui, &mut placement.variation);
+                    ui.label("Position jitter:");
+                    ui.add(Slider::new(&mut placement.position_jitter, 0.0..=1.0));
+                    ui.label("Minimum spacing:");
+                    ui.add(
+                        DragValue::new(&mut placement.min_spacing)
+                            .speed(0.1)
+                            .clamp_range(0.0..=f32::MAX),
+                    );
+                });
+                let model_name = &edit_info.details_info.selected_detail_name;
+                if !model_name.is_empty() {
+                    ui.collapsing("Model settings", |ui| {
+                        model_settings(ui, &mut model_library, model_name);
+                    });
+                    ui.collapsing("Level of detail", |ui| {
+                        let mut model_lod = model_lods.get(model_name);
+                        ui.label("Distance per LOD:");
+                        let mut changed = ui
+                            .add(
+                                DragValue::new(&mut model_lod.lod_step)
+                                    .speed(1.0)
+                                    .clamp_range(1.0..=f32::MAX),
+                            )
+                            .changed();
+                        ui.label("Impostor distance:");
+                        changed |= ui
+                            .add(
+                                DragValue::new(&mut model_lod.impostor_distance)
+                                    .speed(1.0)
+                                    .clamp_range(0.0..=f32::MAX),
+                            )
+                            .changed();
+                        ui.label("Cull distance:");
+                        changed |= ui
+                            .add(
+                                DragValue::new(&mut model_lod.cull_distance)
+                                    .speed(1.0)
+                                    .clamp_range(0.0..=f32::MAX),
                             )
+                            .changed();
+                        if changed {
+                            model_lods.models.insert(model_name.clone(), model_lod);
+                        }
+                    });
+                }
+                ui.collapsing("Scatter rules", |ui| {
+                    if ui.button("Add rule for the selected model").clicked()
+                        && !model_name.is_empty()
+                    {
+                        scatter.add(model_name.clone());
+                    }
+                    for index in 0..scatter.rules.len() {
+                        let name = format!("{}. {}", index + 1, scatter.rules[index].model);
+                        if ui
+                            .selectable_label(scatter.selected == Some(index), name)
                             .clicked()
                         {
-                            let should_update =
-                                match &edit_info.draw_info.draw_texture_info.selected_texture {
-                                    Some(texture) => i != texture.id,
-                                    None => true,
-                                };
-                            if should_update {
-                                let image = images.get(handle).unwrap();
-                                let mut map = Vec::new();
-                                for b in image.data.chunks(4) {
-                                    map.push(Color::rgba_u8(b[0], b[1], b[2], b[3]));
-                                }
-
-                                edit_info.draw_info.draw_texture_info.selected_texture =
-                                    Some(DrawTexture {
-                                        id: i,
-                                        map,
-                                        map_size: image.width(),
-                                        sample_map: Vec::new(),
-                                        sample_map_size: 0,
-                                    });
-                            }
+                            scatter.selected = Some(index);
+                        }
+                    }
+                    if let Some(selected) = scatter.selected {
+                        ui.separator();
+                        let rule = &mut scatter.rules[selected];
+                        ui.label("Density (per 100 square units):");
+                        ui.add(
+                            DragValue::new(&mut rule.density)
+                                .speed(0.01)
+                                .clamp_range(0.0..=f32::MAX),
+                        );
+                        ui.label("Minimum spacing:");
+                        ui.add(
+                            DragValue::new(&mut rule.min_spacing)
+                                .speed(0.1)
+                                .clamp_range(0.0..=f32::MAX),
+                        );
+                        ui.label("Height range:");
+                        ui.horizontal(|ui| {
+                            ui.add(DragValue::new(&mut rule.min_height));
+                            ui.add(DragValue::new(&mut rule.max_height));
+                        });
+                        ui.label("Slope range:");
+                        ui.add(Slider::new(&mut rule.min_slope, 0.0..=90.0));
+                        ui.add(Slider::new(&mut rule.max_slope, 0.0..=90.0));
+                        let mut limit_color = rule.texture_color.is_some();
+                        if ui
+                            .checkbox(&mut limit_color, "Only on a painted colour")
+                            .changed()
+                        {
+                            rule.texture_color = limit_color.then_some(Vec3::ONE);
+                        }
+                        if let Some(texture_color) = &mut rule.texture_color {
+                            ui.horizontal(|ui| {
+                                ui.color_edit_button_rgb(texture_color.as_mut());
+                                ui.label("Tolerance:");
+                                ui.add(Slider::new(&mut rule.texture_tolerance, 0.0..=1.0));
+                            });
                         }
+                        ui.label("Clustering:");
+                        ui.add(Slider::new(&mut rule.clustering, 0.0..=1.0));
+                        ui.label("Cluster size:");
+                        ui.add(DragValue::new(&mut rule.cluster_scale).clamp_range(1.0..=f32::MAX));
+                        detail_variation_settings(ui, &mut rule.variation);
+                        if ui.button("Delete rule").clicked() {
+                            scatter.remove(selected);
+                        }
+                    }
+                    ui.separator();
+                    ui.horizontal(|ui| {
+                        ui.label("Seed:");
+                        ui.add(DragValue::new(&mut scatter.seed));
+                    });
+                    egui::ComboBox::from_label("Region")
+                        .selected_text(scatter.region.to_string())
+                        .show_ui(ui, |ui| {
+                            for region in [ScatterRegion::Terrain, ScatterRegion::Mask] {
+                                ui.selectable_value(
+                                    &mut scatter.region,
+                                    region.clone(),
+                                    region.to_string(),
+                                );
+                            }
+                        });
+                    if ui.button("Apply rules").clicked() {
+                        scatter.apply();
                     }
+                    ui.label("Applying again replaces the details placed by the rules.");
                 });
-                ui.label("Scale:");
+                brushes(ui, &mut edit_info.details_info.brush_info);
+                symmetry_settings(ui, &mut edit_info.symmetry);
+            }
+            EditMode::Mask => {
+                ui.checkbox(&mut layers.mask.enabled, "Limit edits to the mask");
+                brushes(ui, &mut edit_info.mask_info.brush_info);
+                symmetry_settings(ui, &mut edit_info.symmetry);
+                ui.label("Hold Ctrl to remove from the mask.");
+                ui.separator();
+                let mask_info = &mut edit_info.mask_info;
+                ui.label("Height range:");
                 ui.horizontal(|ui| {
-                    ui.add(
-                        DragValue::new(&mut edit_info.draw_info.draw_texture_info.scale)
-                            .clamp_range(1..=usize::MAX),
-                    );
+                    ui.add(DragValue::new(&mut mask_info.min_height));
+                    ui.add(DragValue::new(&mut mask_info.max_height));
+                });
+                if ui.button("Fill from height").clicked() {
+                    mask_info.action = Some(MaskAction::FillHeight);
+                }
+                ui.label("Slope range (degrees):");
+                ui.horizontal(|ui| {
+                    ui.add(DragValue::new(&mut mask_info.min_slope).clamp_range(0.0..=90.0));
+                    ui.add(DragValue::new(&mut mask_info.max_slope).clamp_range(0.0..=90.0));
+                });
+                if ui.button("Fill from slope").clicked() {
+                    mask_info.action = Some(MaskAction::FillSlope);
+                }
+                ui.separator();
+                ui.horizontal(|ui| {
+                    ui.label("Feather radius:");
+                    ui.add(Slider::new(&mut mask_info.feather_radius, 1..=16));
+                });
+                ui.horizontal(|ui| {
+                    if ui.button("Feather").clicked() {
+                        mask_info.action = Some(MaskAction::Feather);
+                    }
+                    if ui.button("Invert").clicked() {
+                        mask_info.action = Some(MaskAction::Invert);
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Shore width:");
+                    ui.add(Slider::new(&mut mask_info.shore_width, 1..=32));
+                });
+                if ui.button("Fill from shoreline").clicked() {
+                    mask_info.action = Some(MaskAction::FillShoreline);
+                }
+                ui.horizontal(|ui| {
+                    if ui.button("Select all").clicked() {
+                        mask_info.action = Some(MaskAction::SelectAll);
+                    }
+                    if ui.button("Clear").clicked() {
+                        mask_info.action = Some(MaskAction::Clear);
+                    }
                 });
-                brushes(ui, &mut edit_info.draw_info.brush_info);
             }
-            EditMode::EditDetails => {
-                egui::ComboBox::from_label("Models")
-                    .selected_text(format!("{}", edit_info.details_info.selected_detail_name))
-                    .show_ui(ui, |ui| {
-                        for name in models {
-                            ui.selectable_value(
-                                &mut edit_info.details_info.selected_detail_name,
-                                name.to_string(),
-                                name.to_string(),
-                            );
+            EditMode::Splines => {
+                ui.horizontal(|ui| {
+                    if ui.button("New road").clicked() {
+                        splines.add(SplineKind::Road);
+                    }
+                    if ui.button("New river").clicked() {
+                        splines.add(SplineKind::River);
+                    }
+                });
+                for index in 0..splines.splines.len() {
+                    let name = format!("{} {}", splines.splines[index].kind.to_string(), index + 1);
+                    if ui
+                        .selectable_label(splines.selected == Some(index), name)
+                        .clicked()
+                    {
+                        splines.selected = Some(index);
+                    }
+                }
+                if let Some(selected) = splines.selected {
+                    ui.separator();
+                    let spline = &mut splines.splines[selected];
+                    ui.label("Width:");
+                    ui.add(DragValue::new(&mut spline.width).clamp_range(1.0..=f32::MAX));
+                    ui.label("Falloff:");
+                    ui.add(DragValue::new(&mut spline.falloff).clamp_range(0.0..=f32::MAX));
+                    if spline.kind == SplineKind::River {
+                        ui.label("Depth:");
+                        ui.add(DragValue::new(&mut spline.depth).clamp_range(0.0..=f32::MAX));
+                    } else {
+                        ui.label("Road texture:");
+                        draw_textures(ui, &mut edit_info.draw_info.draw_texture_info);
+                    }
+                    ui.horizontal(|ui| {
+                        if ui.button("Apply to terrain").clicked() {
+                            splines.apply(selected);
+                        }
+                        if ui.button("Delete").clicked() {
+                            splines.remove(selected);
                         }
                     });
-                brushes(ui, &mut edit_info.details_info.brush_info);
+                    ui.label("Applying again replaces the spline's previous change.");
+                    ui.label("Click to add points, drag to move them, Ctrl click removes them.");
+                }
+            }
+            EditMode::SelectDetails => {
+                ui.horizontal(|ui| {
+                    for gizmo_mode in [GizmoMode::Move, GizmoMode::Rotate, GizmoMode::Scale] {
+                        ui.selectable_value(
+                            &mut detail_selection.gizmo_mode,
+                            gizmo_mode,
+                            gizmo_mode.to_string(),
+                        );
+                    }
+                });
+                ui.label(format!("{} selected", detail_selection.selected.len()));
+                ui.horizontal(|ui| {
+                    if ui.button("Duplicate").clicked() {
+                        detail_selection.action = Some(SelectionAction::Duplicate);
+                    }
+                    if ui.button("Delete").clicked() {
+                        detail_selection.action = Some(SelectionAction::Delete);
+                    }
+                });
+                let mut changed = false;
+                if let Some(properties) = &mut detail_selection.properties {
+                    ui.separator();
+                    ui.label(format!("Model: {}", properties.name));
+                    ui.label("Position:");
+                    ui.horizontal(|ui| {
+                        ui.label("X");
+                        changed |= ui
+                            .add(DragValue::new(&mut properties.pos.x).speed(0.1))
+                            .changed();
+                        ui.label("Z");
+                        changed |= ui
+                            .add(DragValue::new(&mut properties.pos.y).speed(0.1))
+                            .changed();
+                    });
+                    ui.label("Rotation:");
+                    changed |= ui
+                        .add(Slider::new(&mut properties.yaw, -180.0..=180.0))
+                        .changed();
+                    ui.label("Scale:");
+                    changed |= ui
+                        .add(
+                            DragValue::new(&mut properties.scale)
+                                .speed(0.001)
+                                .clamp_range(0.001..=f32::MAX),
+                        )
+                        .changed();
+                }
+                if changed {
+                    detail_selection.properties_changed = true;
+                }
+                ui.label(
+                    "Click to select, drag to box select, hold Shift to add to the selection.",
+                );
+                ui.label("Drag the gizmo to transform the selection, details stay on the terrain.");
+            }
+            EditMode::GroundCover => {
+                brushes(ui, &mut edit_info.ground_cover_info.brush_info);
+                symmetry_settings(ui, &mut edit_info.symmetry);
+                ui.label("Hold Ctrl to remove grass.");
+                ui.separator();
+                let ground_cover = &mut *layers.ground_cover;
+                let settings = &mut ground_cover.settings;
+                let mut changed = false;
+                ui.label("Blades per square unit:");
+                changed |= ui
+                    .add(Slider::new(&mut settings.blades_per_unit, 0.5..=32.0))
+                    .changed();
+                ui.label("Blade height:");
+                changed |= ui
+                    .add(
+                        DragValue::new(&mut settings.blade_height)
+                            .speed(0.01)
+                            .clamp_range(0.05..=f32::MAX),
+                    )
+                    .changed();
+                ui.label("Blade width:");
+                changed |= ui
+                    .add(
+                        DragValue::new(&mut settings.blade_width)
+                            .speed(0.005)
+                            .clamp_range(0.01..=f32::MAX),
+                    )
+                    .changed();
+                ui.label("Height variation:");
+                changed |= ui
+                    .add(Slider::new(&mut settings.height_variation, 0.0..=0.9))
+                    .changed();
+                ui.label("Colour variation:");
+                changed |= ui
+                    .add(Slider::new(&mut settings.color_variation, 0.0..=0.5))
+                    .changed();
+                //tiles follow the view distance by themselves
+                ui.label("View distance:");
+                ui.add(Slider::new(&mut settings.view_distance, 10.0..=200.0));
+                if changed {
+                    ground_cover.refresh();
+                }
+                if ui.button("Clear grass").clicked() {
+                    ground_cover.clear();
+                }
+                ui.label("Grass takes its colour from the painted texture.");
+            }
+            EditMode::Water => {
+                let mut changed = ui.checkbox(&mut water.sea_enabled, "Sea").changed();
+                if water.sea_enabled {
+                    ui.label("Sea level:");
+                    changed |= ui
+                        .add(DragValue::new(&mut water.sea_level).speed(0.1))
+                        .changed();
+                }
+                if changed {
+                    water.refresh();
+                }
+                ui.separator();
+                ui.label("New lakes fill this far above the clicked point:");
+                ui.add(
+                    DragValue::new(&mut water.lake_depth)
+                        .speed(0.1)
+                        .clamp_range(0.1..=f32::MAX),
+                );
+                for index in 0..water.lakes.len() {
+                    let name = format!("Lake {}", index + 1);
+                    if ui
+                        .selectable_label(water.selected == Some(index), name)
+                        .clicked()
+                    {
+                        water.selected = Some(index);
+                    }
+                }
+                if let Some(selected) = water.selected {
+                    ui.separator();
+                    ui.label("Water level:");
+                    //filling is a flood fill, so it only runs once the new level is set
+                    let response =
+                        ui.add(DragValue::new(&mut water.lakes[selected].level).speed(0.1));
+                    if response.drag_released() || response.lost_focus() {
+                        water.refill(selected);
+                    }
+                    if ui.button("Delete lake").clicked() {
+                        water.remove(selected);
+                    }
+                }
+                if ui.button("Refill lakes").clicked() {
+                    water.refill_all();
+                }
+                ui.label("Lakes keep their shape until they're refilled after sculpting.");
+                ui.label("Click to fill a lake, Ctrl click removes it.");
             }
         })
         .unwrap()
         .response;
     ui_hovered.0 = response.rect.contains(mouse) || ui_hovered.0;
 }
+fn symmetry_settings(ui: &mut Ui, symmetry: &mut Symmetry) {
+    ui.collapsing("Symmetry", |ui| {
+        ui.checkbox(&mut symmetry.mirror_x, "Mirror X");
+        ui.checkbox(&mut symmetry.mirror_z, "Mirror Z");
+        ui.label("Radial copies:");
+        ui.add(Slider::new(&mut symmetry.radial_count, 1..=16));
+        ui.label("Centre:");
+        ui.horizontal(|ui| {
+            ui.label("X");
+            ui.add(DragValue::new(&mut symmetry.center.x));
+            ui.label("Z");
+            ui.add(DragValue::new(&mut symmetry.center.y));
+        });
+    });
+}
+//Random rotation, scale and tilt of new details.
+fn detail_variation_settings(ui: &mut Ui, variation: &mut DetailVariation) {
+    ui.checkbox(&mut variation.random_yaw, "Random rotation");
+    ui.label("Scale range (times the model scale):");
+    ui.horizontal(|ui| {
+        ui.add(
+            DragValue::new(&mut variation.min_scale)
+                .speed(0.001)
+                .clamp_range(0.001..=f32::MAX),
+        );
+        ui.add(
+            DragValue::new(&mut variation.max_scale)
+                .speed(0.001)
+                .clamp_range(0.001..=f32::MAX),
+        );
+    });
+    ui.checkbox(&mut variation.align_to_normal, "Align to terrain");
+    if variation.align_to_normal {
+        ui.label("Max tilt:");
+        ui.add(Slider::new(&mut variation.max_tilt, 0.0..=90.0));
+    }
+}
+//Edits the metadata of a model, it's saved to the manifest next to the model once an
+//edit is finished.
+fn model_settings(ui: &mut Ui, model_library: &mut ModelLibrary, name: &str) {
+    let mut metadata = model_library.get(name);
+    let mut changed = false;
+    //the details follow every change, the manifest is only written once an edit is done
+    let mut finished = false;
+    let mut track = |response: egui::Response| {
+        changed |= response.changed();
+        finished |= response.drag_released() || response.lost_focus();
+    };
+    ui.label("Default scale:");
+    track(
+        ui.add(
+            DragValue::new(&mut metadata.default_scale)
+                .speed(0.001)
+                .clamp_range(0.001..=f32::MAX),
+        ),
+    );
+    ui.label("Pivot offset:");
+    ui.horizontal(|ui| {
+        for (label, value) in [
+            ("X", &mut metadata.pivot_offset.x),
+            ("Y", &mut metadata.pivot_offset.y),
+            ("Z", &mut metadata.pivot_offset.z),
+        ] {
+            ui.label(label);
+            track(ui.add(DragValue::new(value).speed(0.01)));
+        }
+    });
+    ui.label("Embed depth:");
+    track(ui.add(DragValue::new(&mut metadata.embed_depth).speed(0.01)));
+    ui.label("Collision radius:");
+    track(
+        ui.add(
+            DragValue::new(&mut metadata.collision_radius)
+                .speed(0.1)
+                .clamp_range(0.0..=f32::MAX),
+        ),
+    );
+    ui.label("Tags (comma separated):");
+    let tags_id = ui.id().with(("model tags", name));
+    let mut tags = ui.data_mut(|data| {
+        data.get_temp_mut_or_insert_with(tags_id, || metadata.tags.join(", "))
+            .clone()
+    });
+    let response = ui.text_edit_singleline(&mut tags);
+    if response.changed() {
+        metadata.tags = tags
+            .split(',')
+            .map(|tag| tag.trim().to_string())
+            .filter(|tag| !tag.is_empty())
+            .collect();
+    }
+    track(response);
+    ui.data_mut(|data| data.insert_temp(tags_id, tags));
+    if changed {
+        model_library.set(name, metadata);
+    }
+    if finished {
+        model_library.save(name);
+    }
+}
+fn brush_context_menu(ui: &mut Ui, brush_library: &mut BrushLibrary, id: usize) {
+    let entry = if let Some(entry) = brush_library.get_mut(id) {
+        entry
+    } else {
+        return;
+    };
+    let mut changed = false;
+    ui.label("Name:");
+    changed |= ui.text_edit_singleline(&mut entry.name).changed();
+    ui.label("Tags (comma separated):");
+    let tags_id = ui.id().with(("brush tags", id));
+    let mut tags = ui.data_mut(|data| {
+        data.get_temp_mut_or_insert_with(tags_id, || entry.tags.join(", "))
+            .clone()
+    });
+    if ui.text_edit_singleline(&mut tags).changed() {
+        entry.tags = tags
+            .split(',')
+            .map(|tag| tag.trim().to_string())
+            .filter(|tag| !tag.is_empty())
+            .collect();
+        changed = true;
+    }
+    ui.data_mut(|data| data.insert_temp(tags_id, tags));
+    changed |= ui.checkbox(&mut entry.favourite, "Favourite").changed();
+    if changed {
+        brush_library.save();
+    }
+    ui.separator();
+    if ui.button("Delete brush").clicked() {
+        brush_library.delete(id);
+        ui.close_menu();
+    }
+}
+//Small graph editor for a brush falloff curve, returns true if the curve was changed.
+fn falloff_curve_editor(ui: &mut Ui, curve: &mut FalloffCurve) -> bool {
+    let mut changed = false;
+    let (response, painter) = ui.allocate_painter(egui::vec2(200.0, 100.0), egui::Sense::click());
+    let rect = response.rect;
+    painter.rect_stroke(rect, 0.0, ui.visuals().widgets.noninteractive.bg_stroke);
+    let to_screen = |point: Vec2| {
+        egui::pos2(
+            rect.left() + point.x * rect.width(),
+            rect.bottom() - point.y * rect.height(),
+        )
+    };
+    let from_screen = |pos: Pos2| {
+        Vec2::new(
+            ((pos.x - rect.left()) / rect.width()).clamp(0.0, 1.0),
+            ((rect.bottom() - pos.y) / rect.height()).clamp(0.0, 1.0),
+        )
+    };
+    let line = (0..=50)
+        .map(|i| {
+            let t = i as f32 / 50.0;
+            to_screen(Vec2::new(t, curve.sample(t)))
+        })
+        .collect();
+    painter.add(egui::Shape::line(
+        line,
+        egui::Stroke::new(2.0, Color32::LIGHT_BLUE),
+    ));
+
+    let last = curve.points.len() - 1;
+    let mut remove = None;
+    for i in 0..curve.points.len() {
+        let point_rect =
+            egui::Rect::from_center_size(to_screen(curve.points[i]), egui::vec2(10.0, 10.0));
+        let point_response = ui.interact(point_rect, response.id.with(i), egui::Sense::drag());
+        if point_response.dragged() {
+            if let Some(pointer) = point_response.interact_pointer_pos() {
+                let new_point = from_screen(pointer);
+                curve.points[i].y = new_point.y;
+                //the end points stay at the edges, the rest can't pass their neighbours
+                if i != 0 && i != last {
+                    curve.points[i].x = new_point
+                        .x
+                        .clamp(curve.points[i - 1].x, curve.points[i + 1].x);
+                }
+                changed = true;
+            }
+        }
+        if point_response.secondary_clicked() && i != 0 && i != last {
+            remove = Some(i);
+        }
+        let color = if point_response.hovered() {
+            Color32::WHITE
+        } else {
+            Color32::LIGHT_BLUE
+        };
+        painter.circle_filled(point_rect.center(), 4.0, color);
+    }
+    if let Some(i) = remove {
+        curve.points.remove(i);
+        changed = true;
+    }
+    if response.double_clicked() {
+        if let Some(pos) = response.interact_pointer_pos() {
+            curve.points.push(from_screen(pos));
+            curve.sort();
+            changed = true;
+        }
+    }
+    ui.label(
+        "Drag points to shape the falloff. Double-click adds a point, right-click removes one.",
+    );
+    changed
+}
 pub struct Brush {
     pub id: usize,
     pub map: Vec<f32>,
@@ -617,17 +1913,27 @@ pub struct Brush {
 }
 pub struct BrushInfo {
     pub selected_brush: Option<Brush>,
+    pub procedural: ProceduralBrush,
 
     pub strength: f32,
     pub size: u32,
+
+    pub stroke: StrokeSettings,
+    pub last_stamp: Option<Vec2>,
+    pub stroke_direction: Vec2,
 }
 impl Default for BrushInfo {
     fn default() -> Self {
         Self {
             selected_brush: None,
+            procedural: ProceduralBrush::default(),
 
             strength: 1.0,
             size: 100,
+
+            stroke: StrokeSettings::default(),
+            last_stamp: None,
+            stroke_direction: Vec2::X,
         }
     }
 }
@@ -635,12 +1941,18 @@ impl Default for BrushInfo {
 pub struct DrawTextureInfo {
     pub selected_texture: Option<DrawTexture>,
     pub scale: u32,
+
+    pub triplanar: bool,
+    pub triplanar_angle: f32,
 }
 impl Default for DrawTextureInfo {
     fn default() -> Self {
         Self {
             selected_texture: None,
             scale: 100,
+
+            triplanar: true,
+            triplanar_angle: 35.0,
         }
     }
 }
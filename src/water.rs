@@ -0,0 +1,364 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use bevy::{
+    pbr::{wireframe::NoWireframe, NotShadowCaster},
+    prelude::*,
+    render::{
+        mesh::Indices,
+        render_resource::PrimitiveTopology,
+        texture::{ImageAddressMode, ImageSampler, ImageSamplerDescriptor},
+    },
+};
+use bevy_mod_raycast::deferred::RaycastSource;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    terrain::MasterTerrain,
+    ui::{EditInfo, EditMode, UiHovered},
+};
+
+pub struct WaterPlugin;
+impl Plugin for WaterPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Water::default()).add_systems(
+            Update,
+            (
+                edit_lakes,
+                refill_lakes,
+                update_water_texture,
+                update_water_meshes,
+            )
+                .chain(),
+        );
+    }
+}
+
+//a lake that would flood more cells than this is flooding the terrain, that's what the sea is for
+const MAX_LAKE_CELLS: usize = 1 << 20;
+//world units covered by the water texture once
+const WATER_TEXTURE_SCALE: f32 = 16.0;
+//how far the sea reaches past the outermost chunks
+const SEA_MARGIN: f32 = 1000.0;
+
+//Water filled from seed up to level, the cells are the height samples it covers.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Lake {
+    pub seed: IVec2,
+    pub level: f32,
+    pub cells: Vec<IVec2>,
+}
+
+#[derive(Resource)]
+pub struct Water {
+    pub sea_enabled: bool,
+    pub sea_level: f32,
+    pub lakes: Vec<Lake>,
+    pub selected: Option<usize>,
+    //new lakes are filled this far above the clicked point
+    pub lake_depth: f32,
+
+    //level of the lake covering each cell
+    lake_levels: HashMap<IVec2, f32>,
+    refill: Vec<usize>,
+    dirty: bool,
+
+    texture: Handle<Image>,
+    material: Option<Handle<StandardMaterial>>,
+    entities: Vec<Entity>,
+}
+impl Default for Water {
+    fn default() -> Self {
+        Self {
+            sea_enabled: false,
+            sea_level: 0.0,
+            lakes: Vec::new(),
+            selected: None,
+            lake_depth: 2.0,
+
+            lake_levels: HashMap::new(),
+            refill: Vec::new(),
+            dirty: true,
+
+            texture: Handle::default(),
+            material: None,
+            entities: Vec::new(),
+        }
+    }
+}
+impl Water {
+    pub fn remove(&mut self, index: usize) {
+        self.lakes.remove(index);
+        self.selected = None;
+        self.refresh();
+    }
+    //Fills the lake again, needed after its level or the terrain around it changed.
+    pub fn refill(&mut self, index: usize) {
+        self.refill.push(index);
+    }
+    pub fn refill_all(&mut self) {
+        self.refill.extend(0..self.lakes.len());
+    }
+    //Rebuilds the water meshes, needed after the sea settings changed.
+    pub fn refresh(&mut self) {
+        self.dirty = true;
+    }
+    pub fn reset(&mut self, sea_enabled: bool, sea_level: f32, lakes: Vec<Lake>) {
+        self.sea_enabled = sea_enabled;
+        self.sea_level = sea_level;
+        self.lakes = lakes;
+        self.selected = None;
+        self.refill.clear();
+        self.refresh();
+    }
+    //Height of the water surface at world_pos, if there's any water.
+    pub fn level_at(&self, world_pos: IVec2) -> Option<f32> {
+        let sea_level = self.sea_enabled.then_some(self.sea_level);
+        match (sea_level, self.lake_levels.get(&world_pos)) {
+            (Some(sea_level), Some(lake_level)) => Some(sea_level.max(*lake_level)),
+            (sea_level, lake_level) => sea_level.or(lake_level.copied()),
+        }
+    }
+    pub fn is_underwater(&self, master_terrain: &MasterTerrain, world_pos: IVec2) -> bool {
+        self.level_at(world_pos)
+            .map_or(false, |level| level > master_terrain.get_height(world_pos))
+    }
+    fn lake_at(&self, world_pos: IVec2) -> Option<usize> {
+        self.lakes
+            .iter()
+            .position(|lake| lake.cells.contains(&world_pos))
+    }
+}
+
+//Cells below level that are connected to seed. The lake ends where the terrain rises above
+//level or at missing chunks, None if it would flood more than MAX_LAKE_CELLS.
+fn flood_fill(master_terrain: &MasterTerrain, seed: IVec2, level: f32) -> Option<Vec<IVec2>> {
+    let mut cells = Vec::new();
+    let mut visited = HashSet::from([seed]);
+    let mut queue = VecDeque::from([seed]);
+    while let Some(cell) = queue.pop_front() {
+        if !master_terrain.does_chunk_exist(&master_terrain.world_to_chunk_pos(cell))
+            || master_terrain.get_height(cell) >= level
+        {
+            continue;
+        }
+        cells.push(cell);
+        if cells.len() > MAX_LAKE_CELLS {
+            return None;
+        }
+        for offset in [IVec2::X, IVec2::NEG_X, IVec2::Y, IVec2::NEG_Y] {
+            if visited.insert(cell + offset) {
+                queue.push_back(cell + offset);
+            }
+        }
+    }
+    Some(cells)
+}
+
+//Click to fill a lake from the clicked point, Ctrl click removes the lake there.
+fn edit_lakes(
+    mut water: ResMut<Water>,
+    edit_info: Res<EditInfo>,
+    raycast_source: Query<&RaycastSource<()>>,
+    master_terrain: Res<MasterTerrain>,
+    mouse: Res<Input<MouseButton>>,
+    keys: Res<Input<KeyCode>>,
+    ui_hovered: Res<UiHovered>,
+) {
+    if !master_terrain.loaded {
+        return;
+    }
+    if let EditMode::Water = &edit_info.edit_mode {
+        if !mouse.just_pressed(MouseButton::Left) || ui_hovered.0 {
+            return;
+        }
+        let raycast_source = raycast_source.single();
+        let (_, intersection) = if let Some(intersection) = raycast_source.intersections().first() {
+            intersection
+        } else {
+            return;
+        };
+        let pos = intersection.position();
+        let world_pos = master_terrain.vec2_to_world_pos(Vec2::new(pos.x, pos.z));
+        if keys.pressed(KeyCode::ControlLeft) {
+            if let Some(index) = water.lake_at(world_pos) {
+                water.remove(index);
+            }
+            return;
+        }
+        let level = master_terrain.get_height(world_pos) + water.lake_depth;
+        water.lakes.push(Lake {
+            seed: world_pos,
+            level,
+            cells: Vec::new(),
+        });
+        let index = water.lakes.len() - 1;
+        water.selected = Some(index);
+        water.refill(index);
+    }
+}
+
+fn refill_lakes(mut water: ResMut<Water>, master_terrain: Res<MasterTerrain>) {
+    if water.refill.is_empty() || !master_terrain.loaded {
+        return;
+    }
+    let mut refill = std::mem::take(&mut water.refill);
+    refill.sort();
+    refill.dedup();
+    for index in refill {
+        let lake = if let Some(lake) = water.lakes.get_mut(index) {
+            lake
+        } else {
+            continue;
+        };
+        match flood_fill(&master_terrain, lake.seed, lake.level) {
+            Some(cells) => lake.cells = cells,
+            None => {
+                warn!("The lake at {} floods too much of the terrain", lake.seed);
+                lake.cells.clear();
+            }
+        }
+    }
+    water.refresh();
+}
+
+//The water texture is tiled, so it gets a copy with a repeating sampler once it has loaded.
+fn update_water_texture(
+    mut water: ResMut<Water>,
+    asset_server: Res<AssetServer>,
+    mut images: ResMut<Assets<Image>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    if water.material.is_some() {
+        return;
+    }
+    if water.texture == Handle::default() {
+        water.texture = asset_server.load("textures/water.png");
+    }
+    let mut texture = if let Some(image) = images.get(&water.texture) {
+        image.clone()
+    } else {
+        return;
+    };
+    texture.sampler = ImageSampler::Descriptor(ImageSamplerDescriptor {
+        address_mode_u: ImageAddressMode::Repeat,
+        address_mode_v: ImageAddressMode::Repeat,
+        ..ImageSamplerDescriptor::linear()
+    });
+    water.material = Some(materials.add(StandardMaterial {
+        base_color: Color::rgba(0.7, 0.85, 1.0, 0.75),
+        base_color_texture: Some(images.add(texture)),
+        perceptual_roughness: 0.1,
+        reflectance: 0.5,
+        alpha_mode: AlphaMode::Blend,
+        ..Default::default()
+    }));
+    water.refresh();
+}
+
+fn update_water_meshes(
+    mut commands: Commands,
+    mut water: ResMut<Water>,
+    master_terrain: Res<MasterTerrain>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut chunk_count: Local<usize>,
+) {
+    //the sea covers all chunks
+    if master_terrain.chunk_count() != *chunk_count {
+        *chunk_count = master_terrain.chunk_count();
+        water.refresh();
+    }
+    if !water.dirty {
+        return;
+    }
+    let water = &mut *water;
+    water.dirty = false;
+    water.lake_levels.clear();
+    for lake in &water.lakes {
+        for cell in &lake.cells {
+            let level = water.lake_levels.entry(*cell).or_insert(lake.level);
+            *level = level.max(lake.level);
+        }
+    }
+    for entity in water.entities.drain(..) {
+        commands.entity(entity).despawn();
+    }
+    //refreshed again once the texture has loaded
+    let material = match &water.material {
+        Some(material) => material.clone(),
+        None => return,
+    };
+    if !master_terrain.loaded {
+        return;
+    }
+
+    let mut spawn_water = |mesh: Mesh| {
+        commands
+            .spawn((
+                PbrBundle {
+                    mesh: meshes.add(mesh),
+                    material: material.clone(),
+                    ..default()
+                },
+                NotShadowCaster,
+                NoWireframe,
+                Name::new("Water"),
+            ))
+            .id()
+    };
+    let mut entities = Vec::new();
+    if water.sea_enabled && !master_terrain.chunks.is_empty() {
+        let chunk_size = master_terrain.chunk_size as f32;
+        let (min, max) =
+            master_terrain
+                .chunks
+                .keys()
+                .fold((Vec2::MAX, Vec2::MIN), |(min, max), chunk_pos| {
+                    let chunk_min = chunk_pos.as_vec2() * chunk_size;
+                    (min.min(chunk_min), max.max(chunk_min + chunk_size))
+                });
+        let sea = [(min - SEA_MARGIN, max + SEA_MARGIN)];
+        entities.push(spawn_water(water_mesh(&sea, water.sea_level)));
+    }
+    for lake in &water.lakes {
+        if lake.cells.is_empty() {
+            continue;
+        }
+        //every cell is centred on its height sample
+        let quads: Vec<(Vec2, Vec2)> = lake
+            .cells
+            .iter()
+            .map(|cell| (cell.as_vec2() - 0.5, cell.as_vec2() + 0.5))
+            .collect();
+        entities.push(spawn_water(water_mesh(&quads, lake.level)));
+    }
+    water.entities = entities;
+}
+
+//Flat quads at level, given by their corners on the ground plane.
+fn water_mesh(quads: &[(Vec2, Vec2)], level: f32) -> Mesh {
+    let mut positions = Vec::with_capacity(quads.len() * 4);
+    let mut uvs = Vec::with_capacity(quads.len() * 4);
+    let mut indices = Vec::with_capacity(quads.len() * 6);
+    for (min, max) in quads {
+        let index = positions.len() as u32;
+        for corner in [
+            Vec2::new(min.x, min.y),
+            Vec2::new(min.x, max.y),
+            Vec2::new(max.x, max.y),
+            Vec2::new(max.x, min.y),
+        ] {
+            positions.push([corner.x, level, corner.y]);
+            uvs.push((corner / WATER_TEXTURE_SCALE).to_array());
+        }
+        indices.extend([index, index + 1, index + 2, index, index + 2, index + 3]);
+    }
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    mesh.insert_attribute(
+        Mesh::ATTRIBUTE_NORMAL,
+        vec![[0.0, 1.0, 0.0]; positions.len()],
+    );
+    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.set_indices(Some(Indices::U32(indices)));
+    mesh
+}